@@ -0,0 +1,509 @@
+use crate::config::Config;
+use crate::db::db::Database;
+use crate::db::{ApiKey, User};
+use actix_web::body::EitherBody;
+use actix_web::cookie::{Cookie, SameSite, time::Duration};
+use actix_web::dev::{Payload, Service, ServiceRequest, ServiceResponse, Transform, forward_ready};
+use actix_web::http::header;
+use actix_web::{FromRequest, HttpMessage as _, HttpRequest, HttpResponse, web};
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher as _, PasswordVerifier as _, SaltString};
+use futures_util::future::{LocalBoxFuture, Ready, ready};
+use log::error;
+use rand::RngCore as _;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::LazyLock;
+
+/// Every generated key starts with this, so leaked keys are easy to recognise.
+pub const KEY_PREFIX: &str = "rih_";
+/// Header carrying an API key, as an alternative to `Authorization: Bearer`.
+pub const API_KEY_HEADER: &str = "x-api-key";
+/// Cookie holding the session token of a logged-in browser.
+pub const SESSION_COOKIE: &str = "rih_session";
+
+/// What an API key is allowed to do. `Admin` allows everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Upload,
+    List,
+    Delete,
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 4] = [Scope::Upload, Scope::List, Scope::Delete, Scope::Admin];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Upload => "upload",
+            Scope::List => "list",
+            Scope::Delete => "delete",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| format!("Unknown scope {:?}", s))
+    }
+}
+
+/// Parse a comma-separated scope list, as stored in the database.
+pub fn parse_scopes(s: &str) -> Result<Vec<Scope>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|scope| !scope.is_empty())
+        .map(Scope::from_str)
+        .collect()
+}
+
+pub fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(|scope| scope.as_str())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 32 random bytes, hex encoded.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// A new random key. Only its hash is stored, so it must be shown to the user right away.
+pub fn generate_key() -> String {
+    format!("{}{}", KEY_PREFIX, generate_token())
+}
+
+/// Hex SHA-256 of a key or session token, the form it is stored and looked up in. Both are
+/// random, so a fast hash is enough; there is nothing to brute-force.
+pub fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+/// Argon2id hash of a password, in PHC string format.
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut rand::rngs::OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("Failed to hash password: {}", e))
+}
+
+/// Check `password` against a stored hash. Without one, for an unknown user, a dummy hash is
+/// checked anyway so a failed login takes as long whether or not the user exists.
+pub fn verify_password(password: &str, hash: Option<&str>) -> bool {
+    static DUMMY_HASH: LazyLock<String> =
+        LazyLock::new(|| hash_password("dummy password").expect("hashing a fixed password"));
+    let matches = PasswordHash::new(hash.unwrap_or(&DUMMY_HASH)).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    });
+    matches && hash.is_some()
+}
+
+/// Cookie starting a session with `token`, sent only over HTTPS when the request came that way.
+pub fn session_cookie(req: &HttpRequest, token: String, ttl_secs: u64) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .secure(req.connection_info().scheme() == "https")
+        .max_age(Duration::seconds(ttl_secs.try_into().unwrap_or(i64::MAX)))
+        .finish()
+}
+
+/// Cookie telling the browser to forget its session.
+pub fn removal_cookie() -> Cookie<'static> {
+    let mut cookie = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    cookie.make_removal();
+    cookie
+}
+
+/// Who a request acts for and what it may do, put in the request extensions by
+/// [`RequireScope`] and available to handlers as an extractor.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    /// User name, or the API key's name for keys without a user
+    pub name: String,
+    /// User whose files the request sees and who owns what it uploads. `None` for API keys not
+    /// tied to a user, which manage the files nobody owns.
+    pub user_id: Option<i64>,
+    pub scopes: Vec<Scope>,
+    /// Downloads other people's images without the configured watermark
+    pub skip_watermark: bool,
+}
+
+impl Identity {
+    /// Everything allowed, for when authentication is disabled.
+    fn unrestricted() -> Self {
+        Identity {
+            name: "anonymous".to_string(),
+            user_id: None,
+            scopes: vec![Scope::Admin],
+            skip_watermark: false,
+        }
+    }
+
+    /// A logged-in user can do anything with their own files; admins with everyone's.
+    fn for_user(user: &User) -> Self {
+        Identity {
+            name: user.username.clone(),
+            user_id: Some(user.id),
+            scopes: if user.is_admin {
+                vec![Scope::Admin]
+            } else {
+                vec![Scope::Upload, Scope::List, Scope::Delete]
+            },
+            skip_watermark: false,
+        }
+    }
+
+    fn for_key(key: &ApiKey) -> Self {
+        Identity {
+            name: key.name.clone(),
+            user_id: key.user_id,
+            scopes: key.scopes.clone(),
+            skip_watermark: key.skip_watermark,
+        }
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope) || self.is_admin()
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.scopes.contains(&Scope::Admin)
+    }
+
+    /// Whether this identity may download or delete a file owned by `owner_id`.
+    pub fn can_access(&self, owner_id: Option<i64>) -> bool {
+        self.is_admin() || self.user_id == owner_id
+    }
+
+    /// Whether this identity downloads a file owned by `owner_id` without the watermark: its
+    /// owner, admins and API keys allowed to.
+    pub fn skips_watermark(&self, owner_id: Option<i64>) -> bool {
+        self.skip_watermark || self.can_access(owner_id)
+    }
+
+    /// Owner to restrict listings to, as in [`crate::db::FileQuery::owned_by`].
+    pub fn owned_by(&self) -> Option<Option<i64>> {
+        (!self.is_admin()).then_some(self.user_id)
+    }
+}
+
+impl FromRequest for Identity {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(
+            req.extensions()
+                .get::<Identity>()
+                .cloned()
+                .ok_or_else(|| actix_web::error::ErrorUnauthorized("Not authenticated")),
+        )
+    }
+}
+
+/// The key sent with a request, from `Authorization: Bearer` or `X-API-Key`.
+fn request_key(req: &HttpRequest) -> Option<&str> {
+    let headers = req.headers();
+    if let Some(value) = headers.get(header::AUTHORIZATION) {
+        let value = value.to_str().ok()?;
+        let (scheme, key) = value.split_once(' ')?;
+        return scheme.eq_ignore_ascii_case("bearer").then(|| key.trim());
+    }
+    headers.get(API_KEY_HEADER)?.to_str().ok().map(str::trim)
+}
+
+fn unauthorized(message: &str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+        .json(serde_json::json!({ "message": message }))
+}
+
+fn internal_error() -> HttpResponse {
+    HttpResponse::InternalServerError().json(serde_json::json!({ "message": "Internal error" }))
+}
+
+/// Who sent `req`: the owner of its API key, else of its session cookie. `None` when it has
+/// neither or the session expired; an unknown or revoked API key is an error response.
+pub async fn identify(req: &HttpRequest) -> Result<Option<Identity>, HttpResponse> {
+    let enabled = req
+        .app_data::<web::Data<Config>>()
+        .is_none_or(|config| config.auth.enabled);
+    if !enabled {
+        return Ok(Some(Identity::unrestricted()));
+    }
+    let Some(db) = req.app_data::<web::Data<Database>>() else {
+        error!("Authentication without a database");
+        return Err(internal_error());
+    };
+
+    if let Some(key) = request_key(req) {
+        let hash = hash_key(key);
+        return match db.run(move |db| db.use_api_key(&hash)).await {
+            Ok(Some(api_key)) => Ok(Some(Identity::for_key(&api_key))),
+            Ok(None) => Err(unauthorized("Invalid API key")),
+            Err(e) => {
+                error!("Failed to look up API key: {}", e);
+                Err(internal_error())
+            }
+        };
+    }
+    let Some(cookie) = req.cookie(SESSION_COOKIE) else {
+        return Ok(None);
+    };
+    let hash = hash_key(cookie.value());
+    match db.run(move |db| db.get_session_user(&hash)).await {
+        Ok(user) => Ok(user.as_ref().map(Identity::for_user)),
+        Err(e) => {
+            error!("Failed to look up session: {}", e);
+            Err(internal_error())
+        }
+    }
+}
+
+/// Identify the request and check it is allowed `scope`, returning the response to send instead
+/// when it isn't.
+async fn authorize(req: &HttpRequest, scope: Scope) -> Result<Identity, HttpResponse> {
+    let Some(identity) = identify(req).await? else {
+        return Err(unauthorized("Log in or send an API key"));
+    };
+    if !identity.allows(scope) {
+        return Err(HttpResponse::Forbidden().json(serde_json::json!({
+            "message": format!("Not allowed to {}", scope),
+        })));
+    }
+    Ok(identity)
+}
+
+/// Middleware rejecting requests that are not logged in or carry no API key granted `scope`,
+/// unless authentication is disabled in the config. Handlers take the [`Identity`] it found.
+///
+/// ```ignore
+/// #[post("/upload", wrap = "RequireScope(Scope::Upload)")]
+/// ```
+pub struct RequireScope(pub Scope);
+
+impl<S, B> Transform<S, ServiceRequest> for RequireScope
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Transform = RequireScopeMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequireScopeMiddleware {
+            service: Rc::new(service),
+            scope: self.0,
+        }))
+    }
+}
+
+pub struct RequireScopeMiddleware<S> {
+    service: Rc<S>,
+    scope: Scope,
+}
+
+impl<S, B> Service<ServiceRequest> for RequireScopeMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let scope = self.scope;
+        Box::pin(async move {
+            match authorize(req.request(), scope).await {
+                Ok(identity) => {
+                    req.extensions_mut().insert(identity);
+                }
+                Err(response) => return Ok(req.into_response(response).map_into_right_body()),
+            }
+            service
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{TestRequest, call_service, init_service, read_body};
+    use actix_web::{App, Responder, get};
+    use std::path::PathBuf;
+
+    #[get("/guarded", wrap = "RequireScope(Scope::Upload)")]
+    async fn guarded(identity: Identity) -> impl Responder {
+        HttpResponse::Ok().body(identity.name)
+    }
+
+    fn temp_db() -> (PathBuf, Database) {
+        let dir = std::env::temp_dir().join(format!("rih-auth-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::open(&dir.join("auth.db").to_string_lossy()).unwrap();
+        (dir, db)
+    }
+
+    fn create_key(db: &Database, name: &str, scopes: &[Scope]) -> (i64, String) {
+        let key = generate_key();
+        let id = db
+            .insert_api_key(name, &key[..12], &hash_key(&key), scopes, None, false)
+            .unwrap();
+        (id, key)
+    }
+
+    #[test]
+    fn test_scopes() {
+        assert_eq!(
+            parse_scopes("upload, list").unwrap(),
+            [Scope::Upload, Scope::List]
+        );
+        assert!(parse_scopes("upload,everything").is_err());
+        assert_eq!(format_scopes(&Scope::ALL), "upload,list,delete,admin");
+
+        let key = generate_key();
+        assert!(key.starts_with(KEY_PREFIX));
+        assert_eq!(key.len(), KEY_PREFIX.len() + 64);
+        assert_ne!(key, generate_key());
+        assert_eq!(hash_key(&key), hash_key(&key));
+    }
+
+    #[test]
+    fn test_passwords() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert_ne!(hash, hash_password("correct horse").unwrap());
+        assert!(verify_password("correct horse", Some(&hash)));
+        assert!(!verify_password("battery staple", Some(&hash)));
+        assert!(!verify_password("correct horse", None));
+        assert!(!verify_password("correct horse", Some("not a hash")));
+    }
+
+    #[test]
+    fn test_identity_access() {
+        let user = |id, is_admin| User {
+            id,
+            username: "u".to_string(),
+            is_admin,
+            created_at: String::new(),
+            limits: Default::default(),
+        };
+        let alice = Identity::for_user(&user(1, false));
+        assert!(alice.allows(Scope::Delete));
+        assert!(!alice.allows(Scope::Admin));
+        assert!(alice.can_access(Some(1)));
+        assert!(!alice.can_access(Some(2)));
+        assert!(!alice.can_access(None));
+        assert_eq!(alice.owned_by(), Some(Some(1)));
+        // Others' downloads and thumbnails carry the watermark
+        assert!(alice.skips_watermark(Some(1)));
+        assert!(!alice.skips_watermark(Some(2)));
+        let unmarked = Identity {
+            skip_watermark: true,
+            ..alice.clone()
+        };
+        assert!(unmarked.skips_watermark(Some(2)));
+
+        let admin = Identity::for_user(&user(2, true));
+        assert!(admin.can_access(Some(1)));
+        assert!(admin.skips_watermark(Some(1)));
+        assert_eq!(admin.owned_by(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_require_scope() {
+        let (dir, db) = temp_db();
+        let (_, uploader) = create_key(&db, "uploader", &[Scope::Upload]);
+        let (_, lister) = create_key(&db, "lister", &[Scope::List]);
+        let (_, admin) = create_key(&db, "admin", &[Scope::Admin]);
+        let (revoked_id, revoked) = create_key(&db, "revoked", &[Scope::Upload]);
+        db.revoke_api_key(revoked_id).unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(Config::default()))
+                .app_data(web::Data::new(db.clone()))
+                .service(guarded),
+        )
+        .await;
+        let call = |header: Option<(&'static str, String)>| {
+            let mut req = TestRequest::get().uri("/guarded");
+            if let Some(header) = header {
+                req = req.insert_header(header);
+            }
+            call_service(&app, req.to_request())
+        };
+
+        let res = call(None).await;
+        assert_eq!(res.status(), 401);
+        assert_eq!(
+            res.headers().get(header::WWW_AUTHENTICATE).unwrap(),
+            "Bearer"
+        );
+        assert_eq!(
+            call(Some(("x-api-key", "rih_nope".into()))).await.status(),
+            401
+        );
+        assert_eq!(call(Some(("x-api-key", revoked))).await.status(), 401);
+        assert_eq!(call(Some(("x-api-key", lister))).await.status(), 403);
+
+        let res = call(Some(("authorization", format!("Bearer {}", uploader)))).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(read_body(res).await, "uploader");
+        let res = call(Some(("x-api-key", admin))).await;
+        assert_eq!(res.status(), 200);
+
+        let user_id = db.insert_user("alice", "unused", false).unwrap();
+        db.create_session(&hash_key("token"), user_id, 60).unwrap();
+        let res = call(Some(("cookie", format!("{}=token", SESSION_COOKIE)))).await;
+        assert_eq!(res.status(), 200);
+        assert_eq!(read_body(res).await, "alice");
+        let res = call(Some(("cookie", format!("{}=stale", SESSION_COOKIE)))).await;
+        assert_eq!(res.status(), 401);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_auth_disabled() {
+        let mut config = Config::default();
+        config.auth.enabled = false;
+        let app = init_service(App::new().app_data(web::Data::new(config)).service(guarded)).await;
+        let res = call_service(&app, TestRequest::get().uri("/guarded").to_request()).await;
+        assert_eq!(res.status(), 200);
+    }
+}
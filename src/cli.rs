@@ -0,0 +1,457 @@
+use crate::auth::{self, Scope};
+use crate::config::Config;
+use crate::db::FileRecord;
+use crate::db::db::Database;
+use crate::imaging;
+use crate::probe::{self, MediaInfo};
+use crate::quota::Limits;
+use crate::storage::{Spool, Storage, StorageBackend as _};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use log::warn;
+use std::collections::HashMap;
+use std::io::{BufRead as _, Write as _};
+
+/// `migrate status|up`: inspect or apply schema migrations without starting the server.
+pub fn migrate_command() -> Command {
+    Command::new("migrate")
+        .about("Show or apply database schema migrations")
+        .subcommand_required(true)
+        .subcommand(Command::new("status").about("List migrations and whether each is applied"))
+        .subcommand(Command::new("up").about("Apply all pending migrations"))
+}
+
+pub fn migrate(matches: &ArgMatches, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    // Connect without migrating, so `status` shows the database as it is.
+    let db = Database::connect(&config.database.path).map_err(|e| e.to_string())?;
+    match matches.subcommand() {
+        Some(("status", _)) => {
+            for migration in db.migration_status().map_err(|e| e.to_string())? {
+                println!(
+                    "{:>4}  {:<28}  {}",
+                    migration.version,
+                    migration.name,
+                    migration.applied_at.as_deref().unwrap_or("pending")
+                );
+            }
+        }
+        Some(("up", _)) => {
+            let applied = db.migrate().map_err(|e| e.to_string())?;
+            if applied.is_empty() {
+                println!("Database schema is up to date");
+            } else {
+                println!("Applied migrations: {:?}", applied);
+            }
+        }
+        _ => unreachable!("subcommand_required"),
+    }
+    Ok(())
+}
+
+/// `keys create|list|revoke`: manage the API keys that guard uploads, listings and deletes.
+pub fn keys_command() -> Command {
+    Command::new("keys")
+        .about("Create, list or revoke API keys")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("create")
+                .about("Create a key and print it; it is not shown again")
+                .arg(
+                    Arg::new("name")
+                        .long("name")
+                        .required(true)
+                        .help("What the key is for, shown in listings"),
+                )
+                .arg(
+                    Arg::new("scopes")
+                        .long("scopes")
+                        .required(true)
+                        .value_delimiter(',')
+                        .action(ArgAction::Append)
+                        .value_parser(Scope::ALL.map(Scope::as_str))
+                        .help("Comma-separated scopes: upload, list, delete, admin"),
+                )
+                .arg(
+                    Arg::new("user")
+                        .long("user")
+                        .help("User the key acts for; it sees and uploads that user's files"),
+                )
+                .arg(
+                    Arg::new("skip_watermark")
+                        .long("skip-watermark")
+                        .action(ArgAction::SetTrue)
+                        .help("Download images with the key without a watermark"),
+                ),
+        )
+        .subcommand(Command::new("list").about("List keys, including revoked ones"))
+        .subcommand(
+            Command::new("revoke").about("Revoke a key by id").arg(
+                Arg::new("id")
+                    .required(true)
+                    .value_parser(clap::value_parser!(i64)),
+            ),
+        )
+}
+
+pub fn keys(matches: &ArgMatches, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(&config.database.path).map_err(|e| e.to_string())?;
+    match matches.subcommand() {
+        Some(("create", matches)) => {
+            let name = matches.get_one::<String>("name").expect("required");
+            let scopes = matches
+                .get_many::<String>("scopes")
+                .expect("required")
+                .map(|scope| scope.parse())
+                .collect::<Result<Vec<Scope>, _>>()?;
+            let user = match matches.get_one::<String>("user") {
+                Some(username) => Some(
+                    db.get_user_by_username(username)
+                        .map_err(|e| e.to_string())?
+                        .ok_or_else(|| format!("No user named {}", username))?,
+                ),
+                None => None,
+            };
+            if scopes.contains(&Scope::Admin) && user.as_ref().is_some_and(|user| !user.is_admin) {
+                return Err("Only keys for admin users can have the admin scope".into());
+            }
+            let key = auth::generate_key();
+            let prefix = &key[..auth::KEY_PREFIX.len() + 8];
+            let id = db
+                .insert_api_key(
+                    name,
+                    prefix,
+                    &auth::hash_key(&key),
+                    &scopes,
+                    user.map(|user| user.id),
+                    matches.get_flag("skip_watermark"),
+                )
+                .map_err(|e| e.to_string())?;
+            println!("Created key {} ({}):", id, auth::format_scopes(&scopes));
+            println!("{}", key);
+        }
+        Some(("list", _)) => {
+            let users: HashMap<i64, String> = db
+                .list_users()
+                .map_err(|e| e.to_string())?
+                .into_iter()
+                .map(|user| (user.id, user.username))
+                .collect();
+            for key in db.list_api_keys().map_err(|e| e.to_string())? {
+                let status = match (&key.revoked_at, &key.last_used_at) {
+                    (Some(revoked_at), _) => format!("revoked {}", revoked_at),
+                    (None, Some(last_used_at)) => format!("last used {}", last_used_at),
+                    (None, None) => "never used".to_string(),
+                };
+                let user = key
+                    .user_id
+                    .map_or("-", |id| users.get(&id).map_or("?", String::as_str));
+                println!(
+                    "{:>4}  {:<12}  {:<20}  {:<16}  {:<24}  created {}  {}{}",
+                    key.id,
+                    key.prefix,
+                    key.name,
+                    user,
+                    auth::format_scopes(&key.scopes),
+                    key.created_at,
+                    status,
+                    if key.skip_watermark {
+                        "  no watermark"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        Some(("revoke", matches)) => {
+            let id = *matches.get_one::<i64>("id").expect("required");
+            if !db.revoke_api_key(id).map_err(|e| e.to_string())? {
+                return Err(format!("No active key with id {}", id).into());
+            }
+            println!("Revoked key {}", id);
+        }
+        _ => unreachable!("subcommand_required"),
+    }
+    Ok(())
+}
+
+/// `users create|list|passwd`: manage accounts that log in to the web page.
+pub fn users_command() -> Command {
+    let username = Arg::new("username").required(true);
+    Command::new("users")
+        .about("Create or list users, or change a password")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("create")
+                .about("Create a user, reading the password from standard input")
+                .arg(username.clone())
+                .arg(
+                    Arg::new("admin")
+                        .long("admin")
+                        .action(ArgAction::SetTrue)
+                        .help("Let the user see and delete everyone's files"),
+                ),
+        )
+        .subcommand(Command::new("list").about("List users"))
+        .subcommand(
+            Command::new("passwd")
+                .about("Set a user's password from standard input and log them out")
+                .arg(username.clone()),
+        )
+        .subcommand(
+            Command::new("limits")
+                .about("Set a user's own upload limits, overriding [limits] in the config")
+                .arg(username)
+                .arg(
+                    Arg::new("max_upload_size")
+                        .long("max-upload-size")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .help("Largest single upload in bytes"),
+                )
+                .arg(
+                    Arg::new("max_total_bytes")
+                        .long("max-total-bytes")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .help("Most bytes the user may store"),
+                )
+                .arg(
+                    Arg::new("max_files_per_day")
+                        .long("max-files-per-day")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .help("Most files the user may upload per day"),
+                )
+                .arg(
+                    Arg::new("reset")
+                        .long("reset")
+                        .action(ArgAction::SetTrue)
+                        .help("Drop the user's own limits first, so the config applies"),
+                ),
+        )
+}
+
+/// Limits that are set, as `name=value` pairs, or `-` when none are.
+fn format_limits(limits: &Limits) -> String {
+    let set: Vec<String> = [
+        ("max_upload_size", limits.max_upload_size),
+        ("max_total_bytes", limits.max_total_bytes),
+        ("max_files_per_day", limits.max_files_per_day.map(u64::from)),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| value.map(|value| format!("{}={}", name, value)))
+    .collect();
+    if set.is_empty() {
+        "-".to_string()
+    } else {
+        set.join(" ")
+    }
+}
+
+/// Shortest password `users create` and `users passwd` accept.
+const MIN_PASSWORD_LEN: usize = 8;
+
+/// Read a password from the first line of standard input, prompting on standard error.
+fn read_password() -> Result<String, Box<dyn std::error::Error>> {
+    eprint!("Password: ");
+    std::io::stderr().flush()?;
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(['\r', '\n']).to_string();
+    if password.chars().count() < MIN_PASSWORD_LEN {
+        return Err(format!("Passwords must be at least {} characters", MIN_PASSWORD_LEN).into());
+    }
+    Ok(password)
+}
+
+pub fn users(matches: &ArgMatches, config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(&config.database.path).map_err(|e| e.to_string())?;
+    match matches.subcommand() {
+        Some(("create", matches)) => {
+            let username = matches.get_one::<String>("username").expect("required");
+            let is_admin = matches.get_flag("admin");
+            if db
+                .get_user_by_username(username)
+                .map_err(|e| e.to_string())?
+                .is_some()
+            {
+                return Err(format!("User {} already exists", username).into());
+            }
+            let hash = auth::hash_password(&read_password()?)?;
+            let id = db
+                .insert_user(username, &hash, is_admin)
+                .map_err(|e| e.to_string())?;
+            println!(
+                "Created {}user {} ({})",
+                if is_admin { "admin " } else { "" },
+                username,
+                id
+            );
+        }
+        Some(("list", _)) => {
+            for user in db.list_users().map_err(|e| e.to_string())? {
+                println!(
+                    "{:>4}  {:<20}  {:<5}  created {}  limits {}",
+                    user.id,
+                    user.username,
+                    if user.is_admin { "admin" } else { "" },
+                    user.created_at,
+                    format_limits(&user.limits)
+                );
+            }
+        }
+        Some(("passwd", matches)) => {
+            let username = matches.get_one::<String>("username").expect("required");
+            let hash = auth::hash_password(&read_password()?)?;
+            if !db
+                .set_password(username, &hash)
+                .map_err(|e| e.to_string())?
+            {
+                return Err(format!("No user named {}", username).into());
+            }
+            println!("Password changed; {} was logged out everywhere", username);
+        }
+        Some(("limits", matches)) => {
+            let username = matches.get_one::<String>("username").expect("required");
+            let user = db
+                .get_user_by_username(username)
+                .map_err(|e| e.to_string())?
+                .ok_or_else(|| format!("No user named {}", username))?;
+            let mut limits = if matches.get_flag("reset") {
+                Limits::default()
+            } else {
+                user.limits
+            };
+            if let Some(max) = matches.get_one::<u64>("max_upload_size") {
+                limits.max_upload_size = Some(*max);
+            }
+            if let Some(max) = matches.get_one::<u64>("max_total_bytes") {
+                limits.max_total_bytes = Some(*max);
+            }
+            if let Some(max) = matches.get_one::<u32>("max_files_per_day") {
+                limits.max_files_per_day = Some(*max);
+            }
+            db.set_user_limits(&user.username, &limits)
+                .map_err(|e| e.to_string())?;
+            println!("Limits of {}: {}", user.username, format_limits(&limits));
+        }
+        _ => unreachable!("subcommand_required"),
+    }
+    Ok(())
+}
+
+/// `backfill`: record placeholders, perceptual hashes and media details of files uploaded
+/// before they were kept.
+pub fn backfill_command() -> Command {
+    Command::new("backfill").about(
+        "Compute placeholders, perceptual hashes and missing media details of older image uploads",
+    )
+}
+
+pub async fn backfill(config: &Config) -> Result<(), Box<dyn std::error::Error>> {
+    let db = Database::open(&config.database.path).map_err(|e| e.to_string())?;
+    let storage = Storage::from_config(&config.storage)?;
+    let files = db.list_files_to_backfill().map_err(|e| e.to_string())?;
+    // Deduplicated uploads share their content, so each object is fetched and probed once
+    let mut probed: HashMap<(String, String), MediaInfo> = HashMap::new();
+    let max_size = config.metadata.max_size;
+    let (mut updated, mut failed) = (0, 0);
+    for record in files {
+        let Some(id) = record.id else { continue };
+        let mime_type = record.mime_type.clone().unwrap_or_default();
+        if !imaging::is_raster(&mime_type) {
+            continue;
+        }
+        // Files from before hashes were recorded can't be told apart, so each is probed
+        let key = (record.sha256.clone()).map(|sha256| (record.backend.clone(), sha256));
+        let media = match key.as_ref().and_then(|key| probed.get(key)) {
+            Some(media) => media.clone(),
+            None => match probe_stored(&db, &storage, &record, mime_type, max_size).await {
+                Ok(media) => {
+                    if let Some(key) = key {
+                        probed.insert(key, media.clone());
+                    }
+                    media
+                }
+                Err(e) => {
+                    warn!("Failed to read {} (id {}): {}", record.filename, id, e);
+                    failed += 1;
+                    continue;
+                }
+            },
+        };
+        if media.blurhash.is_none() {
+            warn!("Could not decode {} (id {})", record.filename, id);
+            failed += 1;
+            continue;
+        }
+        db.update_media(id, &media).map_err(|e| e.to_string())?;
+        updated += 1;
+    }
+    println!("Backfilled {} files, {} failed", updated, failed);
+    Ok(())
+}
+
+async fn probe_stored(
+    db: &Database,
+    storage: &Storage,
+    record: &FileRecord,
+    mime_type: String,
+    max_size: u64,
+) -> Result<MediaInfo, Box<dyn std::error::Error>> {
+    let backend = storage
+        .backend(&record.backend)
+        .ok_or_else(|| format!("no storage backend named {}", record.backend))?;
+    let object = db.get_object_ref(record).map_err(|e| e.to_string())?;
+    let spool = Spool::write(backend.get(&object).await?).await?;
+    let path = spool.path().to_path_buf();
+    Ok(tokio::task::spawn_blocking(move || probe::probe(&path, &mime_type, max_size)).await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::LocalConfig;
+    use crate::storage::stream_once;
+    use image::{DynamicImage, RgbImage};
+
+    #[tokio::test]
+    async fn test_backfill_unhashed() {
+        let dir = std::env::temp_dir().join(format!("rih-backfill-{}", uuid::Uuid::new_v4()));
+        let mut config = Config::default();
+        config.database.path = dir.join("backfill.db").to_string_lossy().into_owned();
+        config.storage.local = Some(LocalConfig {
+            root: dir.join("data").to_string_lossy().into_owned(),
+        });
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = Database::open(&config.database.path).unwrap();
+        let storage = Storage::from_config(&config.storage).unwrap();
+        let backend = storage.backend("local").unwrap();
+        // Two different images, both from before hashes were recorded
+        let mut ids = Vec::new();
+        for (i, (width, height)) in [(8, 4), (3, 6)].into_iter().enumerate() {
+            let image = DynamicImage::ImageRgb8(RgbImage::new(width, height));
+            let data = imaging::encode(&image, imaging::Format::Png, 80).unwrap();
+            let uuid = format!("unhashed-{}", i);
+            let key = format!("2023/10/1/{}", uuid);
+            let object = backend.put(&key, "a.png", stream_once(data)).await.unwrap();
+            let mut record = FileRecord::new(
+                "a.png".to_string(),
+                FileRecord::public_url(2023, 10, 1, &uuid),
+                2023,
+                10,
+                1,
+                uuid,
+                object.key,
+                object.handle,
+            );
+            record.backend = "local".to_string();
+            record.mime_type = Some("image/png".to_string());
+            ids.push(db.insert_file(record, &[]).unwrap());
+        }
+
+        backfill(&config).await.unwrap();
+        let sizes: Vec<_> = (ids.iter())
+            .map(|&id| db.get_file_record_by_id(id).unwrap().unwrap().media)
+            .map(|media| (media.width, media.height))
+            .collect();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(sizes, [(Some(8), Some(4)), (Some(3), Some(6))]);
+    }
+}
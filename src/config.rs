@@ -0,0 +1,982 @@
+use crate::imaging::Format;
+use crate::imaging::transform::Transform;
+use crate::imaging::watermark::{Position, Style, Watermark};
+use crate::quota::Limits;
+use crate::storage::s3::S3Config;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// Server settings, read from `config.toml` and `RIH_*` environment variables.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub storage: StorageConfig,
+    pub database: DatabaseConfig,
+    /// Limits for every uploader; users can have their own, which take precedence.
+    pub limits: Limits,
+    pub auth: AuthConfig,
+    pub thumbnails: ThumbnailConfig,
+    pub transforms: TransformConfig,
+    pub variants: VariantConfig,
+    pub metadata: MetadataConfig,
+    pub similarity: SimilarityConfig,
+    pub watermark: WatermarkConfig,
+    pub logging: LoggingConfig,
+    /// Problems worth logging once logging is set up, such as deprecated keys.
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen_address: String,
+    pub port: u16,
+    /// Origins allowed to call the API from a browser; any origin when empty.
+    pub cors_origins: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            listen_address: "0.0.0.0".to_string(),
+            port: 8000,
+            cors_origins: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Backend new uploads go to. Defaults to `telegram` when a bot is configured, else `local`.
+    pub backend: Option<String>,
+    pub telegram: Option<TelegramConfig>,
+    pub local: Option<LocalConfig>,
+    pub s3: Option<S3Config>,
+}
+
+impl StorageConfig {
+    pub fn default_backend(&self) -> &str {
+        match &self.backend {
+            Some(backend) => backend,
+            None if self.telegram.is_some() => "telegram",
+            None => "local",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelegramConfig {
+    pub bot_token: String,
+    pub chat_id: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LocalConfig {
+    /// Files land in `{root}/{year}/{month}/{day}/{uuid}`.
+    pub root: String,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        LocalConfig {
+            root: "data".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: String,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            path: "db.db".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// Require a login or API key to upload, list and delete files.
+    pub enabled: bool,
+    /// Seconds a login lasts.
+    pub session_ttl: u64,
+    /// Let anyone with a link download a file; otherwise only its owner and admins can.
+    pub public_downloads: bool,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            enabled: true,
+            session_ttl: 7 * 24 * 60 * 60,
+            public_downloads: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThumbnailConfig {
+    /// Make thumbnails of raster images when they are uploaded.
+    pub enabled: bool,
+    /// Longest edge of each thumbnail, in pixels.
+    pub sizes: Vec<u32>,
+    /// JPEG quality, 1-100. Images with transparency become PNGs instead.
+    pub quality: u8,
+}
+
+impl Default for ThumbnailConfig {
+    fn default() -> Self {
+        ThumbnailConfig {
+            enabled: true,
+            sizes: vec![128, 256, 512],
+            quality: 80,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransformConfig {
+    /// Serve resized and re-encoded images from `/find/...?preset=` and matching parameters.
+    pub enabled: bool,
+    /// Renderings are kept here by source hash; the directory can be emptied at any time.
+    pub cache_dir: String,
+    /// Quality of lossy formats for presets that don't give one.
+    pub quality: u8,
+    /// The only transforms served, by name.
+    pub presets: BTreeMap<String, Transform>,
+}
+
+impl Default for TransformConfig {
+    fn default() -> Self {
+        let width = |w| Transform {
+            w: Some(w),
+            ..Default::default()
+        };
+        TransformConfig {
+            enabled: true,
+            cache_dir: "cache".to_string(),
+            quality: 80,
+            presets: BTreeMap::from([
+                ("small".to_string(), width(400)),
+                ("medium".to_string(), width(800)),
+                ("large".to_string(), width(1600)),
+            ]),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VariantConfig {
+    /// Formats downloads are converted to for clients that accept them, most preferred first.
+    pub formats: Vec<Format>,
+    /// Convert images when they are uploaded, rather than when first asked for.
+    pub eager: bool,
+    /// Quality of the converted images, 1-100.
+    pub quality: u8,
+}
+
+impl Default for VariantConfig {
+    fn default() -> Self {
+        VariantConfig {
+            formats: vec![Format::Avif, Format::Webp],
+            eager: false,
+            quality: 70,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetadataConfig {
+    /// Remove EXIF, XMP, IPTC and comments from JPEG, PNG, WebP and HEIF uploads.
+    pub strip: bool,
+    /// Users and API keys, by name, that may keep metadata with `?keep_metadata=true`.
+    /// Admins always may.
+    pub trusted: Vec<String>,
+    /// Largest upload, in bytes, read into memory to remove its metadata or to read its
+    /// dimensions, placeholders and hashes. Bigger ones are stored as they are.
+    pub max_size: u64,
+}
+
+impl Default for MetadataConfig {
+    fn default() -> Self {
+        MetadataConfig {
+            strip: true,
+            trusted: Vec::new(),
+            max_size: 50 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimilarityConfig {
+    /// Bits, out of 64, in which perceptual hashes may differ for images to count as similar,
+    /// when `/files/{id}/similar` isn't given a threshold.
+    pub threshold: u32,
+    /// List images similar to an upload in the upload response.
+    pub warn_on_upload: bool,
+}
+
+impl Default for SimilarityConfig {
+    fn default() -> Self {
+        SimilarityConfig {
+            threshold: 10,
+            warn_on_upload: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WatermarkConfig {
+    /// Stamp a watermark on images downloaded by anyone but their owner.
+    pub enabled: bool,
+    /// When watermarked copies are made.
+    pub apply: WatermarkApply,
+    /// Text to stamp, in a built-in pixel font; ASCII only.
+    pub text: Option<String>,
+    /// PNG to stamp instead of text, such as a logo with a transparent background.
+    pub image: Option<String>,
+    pub position: Position,
+    /// 0 (invisible) to 1 (opaque).
+    pub opacity: f32,
+    /// Width of the watermark as a fraction of the image's.
+    pub scale: f32,
+    /// Gap to the image's edges as a fraction of its shorter side.
+    pub margin: f32,
+    /// Quality of watermarked images in lossy formats, 1-100.
+    pub quality: u8,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatermarkApply {
+    /// Make the watermarked copy of an image when it is uploaded.
+    Upload,
+    /// Make it when someone first downloads the image.
+    #[default]
+    Download,
+}
+
+impl Default for WatermarkConfig {
+    fn default() -> Self {
+        WatermarkConfig {
+            enabled: false,
+            apply: WatermarkApply::default(),
+            text: None,
+            image: None,
+            position: Position::default(),
+            opacity: 0.5,
+            scale: 0.25,
+            margin: 0.02,
+            quality: 85,
+        }
+    }
+}
+
+impl WatermarkConfig {
+    /// The configured watermark, reading its image if it has one. `None` when disabled.
+    pub fn load(&self) -> Result<Option<Watermark>, String> {
+        if !self.enabled {
+            return Ok(None);
+        }
+        let style = Style {
+            position: self.position,
+            opacity: self.opacity,
+            scale: self.scale,
+            margin: self.margin,
+            quality: self.quality,
+        };
+        let watermark = match (&self.image, &self.text) {
+            (Some(path), _) => {
+                let image = image::open(path)
+                    .map_err(|e| format!("Failed to read watermark image {}: {}", path, e))?;
+                Watermark::image(&image, style)
+            }
+            (None, Some(text)) => Watermark::text(text, style),
+            (None, None) => return Err("watermark.text or watermark.image must be set".into()),
+        };
+        Ok(Some(watermark))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `error`, `warn`, `info`, `debug` or `trace`. `RUST_LOG` still takes precedence.
+    pub level: String,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            level: "info".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Str,
+    Int,
+    Bool,
+    /// Comma-separated strings
+    List,
+    /// Comma-separated integers
+    IntList,
+}
+
+/// Flat setting names and where they live in the config file.
+///
+/// `RIH_{name}` environment variables override the file. The names are also the keys of the
+/// old flat `config.toml` layout, which is still read, and the unprefixed environment
+/// variables that layout fell back to.
+const SETTINGS: &[(&str, &[&str], Kind)] = &[
+    ("LISTEN_ADDRESS", &["server", "listen_address"], Kind::Str),
+    ("PORT", &["server", "port"], Kind::Int),
+    ("CORS_ORIGINS", &["server", "cors_origins"], Kind::List),
+    ("STORAGE_BACKEND", &["storage", "backend"], Kind::Str),
+    (
+        "TG_BOT_TOKEN",
+        &["storage", "telegram", "bot_token"],
+        Kind::Str,
+    ),
+    ("TG_CHAT_ID", &["storage", "telegram", "chat_id"], Kind::Str),
+    (
+        "LOCAL_STORAGE_ROOT",
+        &["storage", "local", "root"],
+        Kind::Str,
+    ),
+    ("S3_ENDPOINT", &["storage", "s3", "endpoint"], Kind::Str),
+    ("S3_BUCKET", &["storage", "s3", "bucket"], Kind::Str),
+    ("S3_PREFIX", &["storage", "s3", "prefix"], Kind::Str),
+    ("S3_REGION", &["storage", "s3", "region"], Kind::Str),
+    ("S3_ACCESS_KEY", &["storage", "s3", "access_key"], Kind::Str),
+    ("S3_SECRET_KEY", &["storage", "s3", "secret_key"], Kind::Str),
+    (
+        "S3_PATH_STYLE",
+        &["storage", "s3", "path_style"],
+        Kind::Bool,
+    ),
+    (
+        "S3_PRESIGN_EXPIRY",
+        &["storage", "s3", "presign_expiry"],
+        Kind::Int,
+    ),
+    ("DATABASE_PATH", &["database", "path"], Kind::Str),
+    ("MAX_UPLOAD_SIZE", &["limits", "max_upload_size"], Kind::Int),
+    ("MAX_TOTAL_BYTES", &["limits", "max_total_bytes"], Kind::Int),
+    (
+        "MAX_FILES_PER_DAY",
+        &["limits", "max_files_per_day"],
+        Kind::Int,
+    ),
+    ("AUTH_ENABLED", &["auth", "enabled"], Kind::Bool),
+    ("SESSION_TTL", &["auth", "session_ttl"], Kind::Int),
+    (
+        "PUBLIC_DOWNLOADS",
+        &["auth", "public_downloads"],
+        Kind::Bool,
+    ),
+    ("THUMBNAILS_ENABLED", &["thumbnails", "enabled"], Kind::Bool),
+    ("THUMBNAIL_SIZES", &["thumbnails", "sizes"], Kind::IntList),
+    ("THUMBNAIL_QUALITY", &["thumbnails", "quality"], Kind::Int),
+    ("TRANSFORMS_ENABLED", &["transforms", "enabled"], Kind::Bool),
+    (
+        "TRANSFORM_CACHE_DIR",
+        &["transforms", "cache_dir"],
+        Kind::Str,
+    ),
+    ("TRANSFORM_QUALITY", &["transforms", "quality"], Kind::Int),
+    ("VARIANT_FORMATS", &["variants", "formats"], Kind::List),
+    ("VARIANTS_EAGER", &["variants", "eager"], Kind::Bool),
+    ("VARIANT_QUALITY", &["variants", "quality"], Kind::Int),
+    ("METADATA_STRIP", &["metadata", "strip"], Kind::Bool),
+    ("METADATA_TRUSTED", &["metadata", "trusted"], Kind::List),
+    ("METADATA_MAX_SIZE", &["metadata", "max_size"], Kind::Int),
+    (
+        "SIMILARITY_THRESHOLD",
+        &["similarity", "threshold"],
+        Kind::Int,
+    ),
+    (
+        "SIMILARITY_WARN_ON_UPLOAD",
+        &["similarity", "warn_on_upload"],
+        Kind::Bool,
+    ),
+    ("WATERMARK_ENABLED", &["watermark", "enabled"], Kind::Bool),
+    ("WATERMARK_APPLY", &["watermark", "apply"], Kind::Str),
+    ("WATERMARK_TEXT", &["watermark", "text"], Kind::Str),
+    ("WATERMARK_IMAGE", &["watermark", "image"], Kind::Str),
+    ("LOG_LEVEL", &["logging", "level"], Kind::Str),
+];
+
+const MIN_THUMBNAIL_SIZE: u32 = 16;
+const MAX_THUMBNAIL_SIZE: u32 = 2048;
+
+/// Settings that were read from unprefixed environment variables before `RIH_*` existed.
+const LEGACY_ENV: &[&str] = &["TG_BOT_TOKEN", "TG_CHAT_ID"];
+
+impl Config {
+    /// Load the config file at `path` (defaults only when `None`), apply environment
+    /// overrides and validate the result.
+    pub fn load(path: Option<&str>) -> Result<Config, String> {
+        let file = match path {
+            Some(path) => std::fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read config file {}: {}", path, e))?,
+            None => String::new(),
+        };
+        Config::parse(&file, |name| std::env::var(name).ok())
+    }
+
+    /// Build a config from the contents of a config file and an environment lookup.
+    pub fn parse(file: &str, env: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+        let mut value: toml::Value =
+            toml::from_str(file).map_err(|e| format!("Failed to parse config file: {}", e))?;
+        let warnings = upgrade_flat_keys(&mut value)?;
+        apply_env(&mut value, env)?;
+        let mut config =
+            Config::deserialize(value).map_err(|e| format!("Invalid config: {}", e))?;
+        config.validate()?;
+        config.warnings = warnings;
+        Ok(config)
+    }
+
+    /// Check settings that parse but can't work, reporting every problem at once.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        for origin in &self.server.cors_origins {
+            // An origin is scheme://host[:port], nothing more
+            let valid = reqwest::Url::parse(origin).is_ok_and(|url| {
+                url.has_host() && url.origin().ascii_serialization() == origin.as_str()
+            });
+            if !valid {
+                errors.push(format!(
+                    "server.cors_origins entries must look like https://example.com (got {:?})",
+                    origin
+                ));
+            }
+        }
+        let storage = &self.storage;
+        let backend = storage.default_backend();
+        if !["telegram", "local", "s3"].contains(&backend) {
+            errors.push(format!(
+                "storage.backend must be one of telegram, local, s3 (got {:?})",
+                backend
+            ));
+        }
+        match &storage.telegram {
+            Some(telegram) => {
+                if telegram.bot_token.is_empty() {
+                    errors.push("storage.telegram.bot_token must be set".to_string());
+                }
+                if telegram.chat_id.is_empty() {
+                    errors.push("storage.telegram.chat_id must be set".to_string());
+                }
+            }
+            None if backend == "telegram" => errors.push(
+                "[storage.telegram] with bot_token and chat_id must be set for the telegram backend"
+                    .to_string(),
+            ),
+            None => {}
+        }
+        if let Some(local) = &storage.local
+            && local.root.is_empty()
+        {
+            errors.push("storage.local.root must not be empty".to_string());
+        }
+        match &storage.s3 {
+            Some(s3) => {
+                for (name, value) in [
+                    ("bucket", &s3.bucket),
+                    ("access_key", &s3.access_key),
+                    ("secret_key", &s3.secret_key),
+                ] {
+                    if value.is_empty() {
+                        errors.push(format!("storage.s3.{} must be set", name));
+                    }
+                }
+                if reqwest::Url::parse(&s3.endpoint).is_err() {
+                    errors.push(format!(
+                        "storage.s3.endpoint is not a valid URL: {}",
+                        s3.endpoint
+                    ));
+                }
+            }
+            None if backend == "s3" => {
+                errors.push("[storage.s3] must be set for the s3 backend".to_string())
+            }
+            None => {}
+        }
+        if self.database.path.is_empty() {
+            errors.push("database.path must not be empty".to_string());
+        }
+        let limits = &self.limits;
+        for (name, zero) in [
+            ("max_upload_size", limits.max_upload_size == Some(0)),
+            ("max_total_bytes", limits.max_total_bytes == Some(0)),
+            ("max_files_per_day", limits.max_files_per_day == Some(0)),
+        ] {
+            if zero {
+                errors.push(format!("limits.{} must be greater than 0", name));
+            }
+        }
+        if self.metadata.max_size == 0 {
+            errors.push("metadata.max_size must be greater than 0".to_string());
+        }
+        if self.auth.session_ttl == 0 {
+            errors.push("auth.session_ttl must be greater than 0".to_string());
+        }
+        let thumbnails = &self.thumbnails;
+        if thumbnails.enabled && thumbnails.sizes.is_empty() {
+            errors.push("thumbnails.sizes must not be empty".to_string());
+        }
+        if let Some(size) = thumbnails
+            .sizes
+            .iter()
+            .find(|size| !(MIN_THUMBNAIL_SIZE..=MAX_THUMBNAIL_SIZE).contains(*size))
+        {
+            errors.push(format!(
+                "thumbnails.sizes must be between {} and {} (got {})",
+                MIN_THUMBNAIL_SIZE, MAX_THUMBNAIL_SIZE, size
+            ));
+        }
+        if !(1..=100).contains(&thumbnails.quality) {
+            errors.push(format!(
+                "thumbnails.quality must be between 1 and 100 (got {})",
+                thumbnails.quality
+            ));
+        }
+        let transforms = &self.transforms;
+        if transforms.cache_dir.is_empty() {
+            errors.push("transforms.cache_dir must not be empty".to_string());
+        }
+        if !(1..=100).contains(&transforms.quality) {
+            errors.push(format!(
+                "transforms.quality must be between 1 and 100 (got {})",
+                transforms.quality
+            ));
+        }
+        for (name, preset) in &transforms.presets {
+            if let Err(e) = preset.validate() {
+                errors.push(format!("transforms.presets.{}: {}", name, e));
+            }
+        }
+        let variants = &self.variants;
+        for (i, format) in variants.formats.iter().enumerate() {
+            if !matches!(format, Format::Avif | Format::Webp) {
+                errors.push(format!(
+                    "variants.formats may only contain avif and webp (got {})",
+                    format.as_str()
+                ));
+            } else if variants.formats[..i].contains(format) {
+                errors.push(format!("variants.formats lists {} twice", format.as_str()));
+            }
+        }
+        if !(1..=100).contains(&variants.quality) {
+            errors.push(format!(
+                "variants.quality must be between 1 and 100 (got {})",
+                variants.quality
+            ));
+        }
+        if self.similarity.threshold > u64::BITS {
+            errors.push(format!(
+                "similarity.threshold must be at most {} (got {})",
+                u64::BITS,
+                self.similarity.threshold
+            ));
+        }
+        let watermark = &self.watermark;
+        if watermark.enabled {
+            match (&watermark.text, &watermark.image) {
+                (None, None) => {
+                    errors.push("watermark.text or watermark.image must be set".to_string())
+                }
+                (Some(_), Some(_)) => {
+                    errors.push("watermark.text and watermark.image can't both be set".to_string())
+                }
+                (Some(text), None) if text.trim().is_empty() => {
+                    errors.push("watermark.text must not be empty".to_string())
+                }
+                _ => {}
+            }
+        }
+        for (name, value) in [("opacity", watermark.opacity), ("scale", watermark.scale)] {
+            if !(value > 0.0 && value <= 1.0) {
+                errors.push(format!(
+                    "watermark.{} must be above 0 and at most 1 (got {})",
+                    name, value
+                ));
+            }
+        }
+        if !(0.0..0.5).contains(&watermark.margin) {
+            errors.push(format!(
+                "watermark.margin must be at least 0 and below 0.5 (got {})",
+                watermark.margin
+            ));
+        }
+        if !(1..=100).contains(&watermark.quality) {
+            errors.push(format!(
+                "watermark.quality must be between 1 and 100 (got {})",
+                watermark.quality
+            ));
+        }
+        if LevelFilter::from_str(&self.logging.level).is_err() {
+            errors.push(format!(
+                "logging.level must be one of off, error, warn, info, debug, trace (got {:?})",
+                self.logging.level
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid config: {}", errors.join("; ")))
+        }
+    }
+}
+
+/// Move keys of the old flat layout (`TG_BOT_TOKEN = ...`) to their place in the sections,
+/// returning a deprecation warning for each.
+fn upgrade_flat_keys(value: &mut toml::Value) -> Result<Vec<String>, String> {
+    let table = value
+        .as_table_mut()
+        .ok_or("Config file must be a TOML table")?;
+    let mut warnings = Vec::new();
+    for (name, path, _) in SETTINGS {
+        if let Some(setting) = table.remove(*name) {
+            warnings.push(format!(
+                "Config key {} is deprecated, use {} instead",
+                name,
+                path.join(".")
+            ));
+            set_path(table, path, setting)?;
+        }
+    }
+    Ok(warnings)
+}
+
+/// Apply `RIH_*` overrides, and legacy unprefixed variables for settings the file leaves unset.
+fn apply_env(value: &mut toml::Value, env: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+    let table = value.as_table_mut().expect("checked in upgrade_flat_keys");
+    for (name, path, kind) in SETTINGS {
+        let prefixed = format!("RIH_{}", name);
+        let (var, raw) = match env(&prefixed) {
+            Some(raw) => (prefixed, raw),
+            None if LEGACY_ENV.contains(name) && get_path(table, path).is_none() => {
+                match env(name) {
+                    Some(raw) => (name.to_string(), raw),
+                    None => continue,
+                }
+            }
+            None => continue,
+        };
+        let setting = match kind {
+            Kind::Str => toml::Value::String(raw),
+            Kind::Int => toml::Value::Integer(
+                raw.trim()
+                    .parse()
+                    .map_err(|_| format!("{} must be an integer (got {:?})", var, raw))?,
+            ),
+            Kind::Bool => toml::Value::Boolean(
+                raw.trim()
+                    .parse()
+                    .map_err(|_| format!("{} must be true or false (got {:?})", var, raw))?,
+            ),
+            Kind::List => toml::Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| toml::Value::String(item.to_string()))
+                    .collect(),
+            ),
+            Kind::IntList => toml::Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| item.parse().map(toml::Value::Integer))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| {
+                        format!("{} must be comma-separated integers (got {:?})", var, raw)
+                    })?,
+            ),
+        };
+        set_path(table, path, setting)?;
+    }
+    Ok(())
+}
+
+fn get_path<'a>(table: &'a toml::Table, path: &[&str]) -> Option<&'a toml::Value> {
+    let (last, sections) = path.split_last()?;
+    let mut table = table;
+    for section in sections {
+        table = table.get(*section)?.as_table()?;
+    }
+    table.get(*last)
+}
+
+fn set_path(table: &mut toml::Table, path: &[&str], setting: toml::Value) -> Result<(), String> {
+    let (last, sections) = path.split_last().expect("setting paths are not empty");
+    let mut table = table;
+    for section in sections {
+        table = table
+            .entry(section.to_string())
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("Config key {} must be a table", section))?;
+    }
+    table.insert(last.to_string(), setting);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn parse(file: &str, env: &[(&str, &str)]) -> Result<Config, String> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::parse(file, |name| env.get(name).cloned())
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = parse("", &[]).unwrap();
+        assert_eq!(config.server.listen_address, "0.0.0.0");
+        assert_eq!(config.server.port, 8000);
+        assert_eq!(config.storage.default_backend(), "local");
+        assert_eq!(config.database.path, "db.db");
+        assert_eq!(config.limits, Limits::default());
+        assert!(config.auth.enabled);
+        assert!(config.auth.public_downloads);
+        assert_eq!(config.auth.session_ttl, 604800);
+        assert_eq!(config.thumbnails.sizes, [128, 256, 512]);
+        assert_eq!(config.transforms.presets["small"].w, Some(400));
+        assert_eq!(config.variants.formats, [Format::Avif, Format::Webp]);
+        assert!(config.metadata.strip);
+        assert!(config.metadata.trusted.is_empty());
+        assert_eq!(config.metadata.max_size, 50 << 20);
+        assert_eq!(config.similarity.threshold, 10);
+        assert!(config.similarity.warn_on_upload);
+        assert!(!config.watermark.enabled);
+        assert_eq!(config.watermark.apply, WatermarkApply::Download);
+        assert_eq!(config.watermark.position, Position::BottomRight);
+        assert!(config.watermark.load().unwrap().is_none());
+        assert!(config.server.cors_origins.is_empty());
+        assert_eq!(config.logging.level, "info");
+    }
+
+    #[test]
+    fn test_sections() {
+        let config = parse(
+            r#"
+            [server]
+            port = 9000
+
+            [storage]
+            backend = "s3"
+
+            [storage.s3]
+            endpoint = "http://localhost:9000"
+            bucket = "images"
+            access_key = "a"
+            secret_key = "s"
+
+            [limits]
+            max_upload_size = 1024
+            max_files_per_day = 20
+            [transforms.presets]
+            card = { w = 800, h = 600, fit = "cover", format = "webp", q = 80 }
+            "#,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 9000);
+        assert_eq!(config.storage.default_backend(), "s3");
+        let s3 = config.storage.s3.unwrap();
+        assert_eq!(s3.bucket, "images");
+        assert_eq!(s3.region, "us-east-1");
+        assert!(s3.path_style);
+        assert_eq!(config.limits.max_upload_size, Some(1024));
+        assert_eq!(config.limits.max_files_per_day, Some(20));
+        assert_eq!(config.limits.max_total_bytes, None);
+        // Presets in the file replace the built-in ones
+        assert_eq!(config.transforms.presets.len(), 1);
+        assert_eq!(config.transforms.presets["card"].h, Some(600));
+    }
+
+    #[test]
+    fn test_flat_keys() {
+        let config = parse(
+            "TG_BOT_TOKEN = \"1:abc\"\nTG_CHAT_ID = \"42\"\nPORT = 8080\nS3_PATH_STYLE = false\n\
+             S3_BUCKET = \"b\"\nS3_ACCESS_KEY = \"a\"\nS3_SECRET_KEY = \"s\"",
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.storage.default_backend(), "telegram");
+        assert_eq!(config.storage.telegram.unwrap().chat_id, "42");
+        assert_eq!(config.server.port, 8080);
+        assert!(!config.storage.s3.unwrap().path_style);
+        assert!(
+            config
+                .warnings
+                .contains(&"Config key PORT is deprecated, use server.port instead".to_string())
+        );
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let file =
+            "[server]\nport = 9000\n[storage.telegram]\nbot_token = \"1:abc\"\nchat_id = \"42\"";
+        let config = parse(
+            file,
+            &[
+                ("RIH_PORT", "9100"),
+                ("RIH_STORAGE_BACKEND", "local"),
+                ("RIH_LOG_LEVEL", "debug"),
+                ("RIH_AUTH_ENABLED", "false"),
+                ("RIH_THUMBNAIL_SIZES", "64, 320"),
+                ("RIH_VARIANT_FORMATS", "webp"),
+                ("RIH_METADATA_TRUSTED", "alice, photo-bot"),
+                ("RIH_METADATA_MAX_SIZE", "1048576"),
+                ("RIH_SIMILARITY_THRESHOLD", "6"),
+                ("RIH_WATERMARK_ENABLED", "true"),
+                ("RIH_WATERMARK_TEXT", "example.com"),
+                (
+                    "RIH_CORS_ORIGINS",
+                    "https://example.com, http://localhost:3000",
+                ),
+                // The file sets the chat, so the legacy variable is ignored.
+                ("TG_CHAT_ID", "7"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(config.server.port, 9100);
+        assert_eq!(config.storage.default_backend(), "local");
+        assert_eq!(config.logging.level, "debug");
+        assert!(!config.auth.enabled);
+        assert_eq!(config.thumbnails.sizes, [64, 320]);
+        assert_eq!(config.variants.formats, [Format::Webp]);
+        assert_eq!(config.metadata.trusted, ["alice", "photo-bot"]);
+        assert_eq!(config.metadata.max_size, 1 << 20);
+        assert_eq!(config.similarity.threshold, 6);
+        assert!(config.watermark.enabled);
+        assert_eq!(config.watermark.text.as_deref(), Some("example.com"));
+        assert!(config.watermark.load().unwrap().is_some());
+        assert_eq!(
+            config.server.cors_origins,
+            ["https://example.com", "http://localhost:3000"]
+        );
+        assert_eq!(config.storage.telegram.unwrap().chat_id, "42");
+
+        let config = parse("", &[("TG_BOT_TOKEN", "1:abc"), ("TG_CHAT_ID", "7")]).unwrap();
+        assert_eq!(config.storage.default_backend(), "telegram");
+        assert_eq!(config.storage.telegram.unwrap().chat_id, "7");
+
+        let err = parse("", &[("RIH_PORT", "eighty")]).unwrap_err();
+        assert!(err.contains("RIH_PORT must be an integer"), "{}", err);
+
+        let err = parse("", &[("RIH_THUMBNAIL_SIZES", "64,big")]).unwrap_err();
+        assert!(err.contains("comma-separated integers"), "{}", err);
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let err = parse("[storage]\nbackend = \"telegram\"", &[]).unwrap_err();
+        assert!(err.contains("[storage.telegram]"), "{}", err);
+
+        let err = parse(
+            "[storage]\nbackend = \"s3\"\n[storage.s3]\nbucket = \"b\"\n[logging]\nlevel = \"loud\"",
+            &[],
+        )
+        .unwrap_err();
+        assert!(err.contains("storage.s3.access_key must be set"), "{}", err);
+        assert!(err.contains("storage.s3.secret_key must be set"), "{}", err);
+        assert!(err.contains("logging.level"), "{}", err);
+
+        let err = parse("[metadata]\nmax_size = 0", &[]).unwrap_err();
+        assert!(
+            err.contains("metadata.max_size must be greater than 0"),
+            "{}",
+            err
+        );
+
+        let err = parse("[limits]\nmax_total_bytes = 0", &[]).unwrap_err();
+        assert!(
+            err.contains("limits.max_total_bytes must be greater than 0"),
+            "{}",
+            err
+        );
+
+        let err = parse("[thumbnails]\nsizes = [8]\nquality = 0", &[]).unwrap_err();
+        assert!(err.contains("thumbnails.sizes must be between"), "{}", err);
+        assert!(err.contains("thumbnails.quality"), "{}", err);
+
+        let err = parse(
+            "[transforms.presets]\nwide = { w = 100000 }\nbox = { w = 10, fit = \"cover\" }",
+            &[],
+        )
+        .unwrap_err();
+        assert!(
+            err.contains("transforms.presets.wide: w must be"),
+            "{}",
+            err
+        );
+        assert!(err.contains("transforms.presets.box: fit=cover"), "{}", err);
+
+        let err = parse("[variants]\nformats = [\"png\", \"webp\", \"webp\"]", &[]).unwrap_err();
+        assert!(
+            err.contains("only contain avif and webp (got png)"),
+            "{}",
+            err
+        );
+        assert!(err.contains("lists webp twice"), "{}", err);
+
+        let err = parse("[similarity]\nthreshold = 65", &[]).unwrap_err();
+        assert!(
+            err.contains("similarity.threshold must be at most 64"),
+            "{}",
+            err
+        );
+
+        let err = parse("[watermark]\nposition = \"middle\"", &[]).unwrap_err();
+        assert!(err.contains("unknown variant `middle`"), "{}", err);
+        let err = parse(
+            "[watermark]\nenabled = true\nopacity = 0\nmargin = 0.5",
+            &[],
+        )
+        .unwrap_err();
+        assert!(err.contains("watermark.text or watermark.image"), "{}", err);
+        assert!(err.contains("watermark.opacity"), "{}", err);
+        assert!(err.contains("watermark.margin"), "{}", err);
+
+        let err = parse("[storage]\nbackend = \"ftp\"", &[]).unwrap_err();
+        assert!(err.contains("storage.backend must be one of"), "{}", err);
+
+        let err = parse("[server]\ncors_origins = [\"*\", \"https://a.com/\"]", &[]).unwrap_err();
+        assert!(err.contains("\"*\""), "{}", err);
+        assert!(err.contains("\"https://a.com/\""), "{}", err);
+
+        let err = parse("[server]\nprot = 1", &[]).unwrap_err();
+        assert!(err.contains("unknown field `prot`"), "{}", err);
+    }
+}
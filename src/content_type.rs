@@ -0,0 +1,103 @@
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+};
+
+/// How many leading bytes of an upload are inspected to detect its type.
+pub const SNIFF_LEN: usize = 8 * 1024;
+
+/// Served when an upload's type could not be detected, or for records from before detection.
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// MIME type of a file judged from its first bytes, ignoring its name and the client's claim.
+pub fn sniff(head: &[u8]) -> Option<&'static str> {
+    infer::get(head).map(|kind| kind.mime_type())
+}
+
+/// Whether browsers may display a file of this type in place.
+///
+/// Only media types are shown inline; anything that could run script in our origin, such as
+/// HTML or SVG, is always downloaded.
+pub fn is_inline(mime_type: &str) -> bool {
+    ["image/", "video/", "audio/"]
+        .iter()
+        .any(|prefix| mime_type.starts_with(prefix))
+        && mime_type != "image/svg+xml"
+}
+
+/// `Content-Disposition` carrying the original `filename`.
+///
+/// Non-ASCII names are sent RFC 5987 encoded in `filename*`, with an ASCII approximation in
+/// `filename` for older clients.
+pub fn content_disposition(filename: &str, inline: bool) -> ContentDisposition {
+    let fallback: String = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let mut parameters = vec![DispositionParam::Filename(fallback)];
+    if !filename.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: filename.as_bytes().to_vec(),
+        }));
+    }
+    ContentDisposition {
+        disposition: if inline {
+            DispositionType::Inline
+        } else {
+            DispositionType::Attachment
+        },
+        parameters,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sniff() {
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(sniff(png), Some("image/png"));
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0\0\x10JFIF"), Some("image/jpeg"));
+        assert_eq!(sniff(b"<html><script>"), Some("text/html"));
+        assert_eq!(sniff(b"plain text"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn test_is_inline() {
+        assert!(is_inline("image/png"));
+        assert!(is_inline("video/mp4"));
+        assert!(is_inline("audio/mpeg"));
+        assert!(!is_inline("image/svg+xml"));
+        assert!(!is_inline("text/html"));
+        assert!(!is_inline(DEFAULT_MIME_TYPE));
+    }
+
+    #[test]
+    fn test_content_disposition() {
+        assert_eq!(
+            content_disposition("cat.png", true).to_string(),
+            "inline; filename=\"cat.png\""
+        );
+        assert_eq!(
+            content_disposition("a\"b.zip", false).to_string(),
+            "attachment; filename=\"a\\\"b.zip\""
+        );
+        assert_eq!(
+            content_disposition("猫.png", true).to_string(),
+            "inline; filename=\"_.png\"; filename*=UTF-8''%E7%8C%AB.png"
+        );
+        assert_eq!(
+            content_disposition("a\r\nb", false).to_string(),
+            "attachment; filename=\"a__b\""
+        );
+    }
+}
@@ -1,87 +1,397 @@
-use super::models::FileRecord;
-use dotenv::dotenv;
-use log::{debug, error, info};
-use rusqlite::{Connection, Result};
+use super::migrations;
+use super::models::{
+    ApiKey, FilePage, FileQuery, FileRecord, SimilarFile, SortField, SortOrder, Thumbnail, User,
+    Variant, Watermarked,
+};
+use crate::auth::{Scope, format_scopes};
+use crate::imaging::Format;
+use crate::imaging::similarity;
+use crate::probe::MediaInfo;
+use crate::quota::{Limits, Quota, Usage};
+use crate::storage::{ObjectPart, ObjectRef};
+use log::{error, info};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::Value;
+use rusqlite::{OptionalExtension as _, Transaction, TransactionBehavior};
 use std::path::Path;
 
+/// Errors from the connection pool, SQLite, or the blocking task a query ran on.
+pub type DbError = Box<dyn std::error::Error + Send + Sync>;
+pub type Result<T, E = DbError> = std::result::Result<T, E>;
+
+/// Pool of SQLite connections, shared by all workers. Cloning shares the pool.
+#[derive(Clone)]
 pub struct Database {
-    db_path: String,
+    pool: Pool<SqliteConnectionManager>,
 }
 
 impl Database {
-    pub fn new(db_path: &str) -> Self {
-        let db = Database {
-            db_path: db_path.to_string(),
-        };
-        if let Err(e) = db.init_db() {
-            eprintln!("Failed to initialize database: {}", e);
-        }
-        db
+    /// Open (creating if needed) the database at `db_path` and bring its schema up to date.
+    pub fn open(db_path: &str) -> Result<Self> {
+        let db = Self::connect(db_path)?;
+        db.migrate()?;
+        Ok(db)
     }
 
-    pub fn init_db(&self) -> Result<(), Box<dyn std::error::Error>> {
-        if Path::new(&self.db_path).exists() {
+    /// Open the database at `db_path` without touching its schema.
+    pub fn connect(db_path: &str) -> Result<Self> {
+        if Path::new(db_path).exists() {
             info!("Database already exists.");
-            println!("Database already exists.");
-            return Ok(());
         }
+        // WAL lets readers proceed while an upload is being recorded; the busy timeout
+        // makes concurrent writers wait for each other instead of failing.
+        let manager = SqliteConnectionManager::file(db_path).with_init(|conn| {
+            conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")
+        });
+        Ok(Database {
+            pool: Pool::new(manager)?,
+        })
+    }
 
-        let conn = Connection::open(&self.db_path)?;
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS files (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                filename TEXT NOT NULL,
-                file_id TEXT,
-                message_id TEXT,
-                url TEXT NOT NULL,
-                year INTEGER,
-                month INTEGER,
-                day INTEGER,
-                uuid TEXT NOT NULL,
-                upload_time TIMESTAMP DEFAULT CURRENT_TIMESTAMP
-            )",
-            [],
+    fn conn(&self) -> Result<PooledConnection<SqliteConnectionManager>> {
+        Ok(self.pool.get()?)
+    }
+
+    /// Run `f` on the blocking thread pool so SQLite never stalls the async executor.
+    pub async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&Database) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db = self.clone();
+        tokio::task::spawn_blocking(move || f(&db)).await?
+    }
+
+    /// Apply pending schema migrations, returning the versions applied.
+    pub fn migrate(&self) -> Result<Vec<u32>> {
+        let mut conn = self.conn()?;
+        let applied = migrations::migrate(&mut conn)?;
+        info!(
+            "Database schema at version {}",
+            migrations::current_version(&conn)?
+        );
+        Ok(applied)
+    }
+
+    pub fn migration_status(&self) -> Result<Vec<migrations::MigrationStatus>> {
+        Ok(migrations::status(&*self.conn()?)?)
+    }
+
+    /// Insert a new file record using the FileRecord struct, together with the
+    /// chunk list when the object was stored in parts
+    pub fn insert_file(&self, new_file: FileRecord, parts: &[ObjectPart]) -> Result<i64> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO blobs (backend, sha256, size, ref_count) VALUES (?1, ?2, ?3, 1)",
+            rusqlite::params![new_file.backend, new_file.sha256, new_file.size],
         )?;
-        info!("Database initialized.");
-        Ok(())
+        let row_id = insert_file_row(&tx, &new_file, tx.last_insert_rowid())?;
+        for part in parts {
+            tx.execute(
+                "INSERT INTO file_chunks (file_row_id, chunk_index, file_id, message_id, size)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                rusqlite::params![row_id, part.index, part.key, part.handle, part.size],
+            )?;
+        }
+        tx.commit()?;
+        Ok(row_id)
     }
 
-    /// Insert a new file record using the FileRecord struct
-    pub fn insert_file(&self, new_file: FileRecord) -> Result<i64> {
-        let conn = Connection::open(&self.db_path)?;
+    /// Record `new_file` as another reference to an object already stored with the same
+    /// backend, SHA-256 and size, returning its row id and that object. `None` when there is no
+    /// such object and the content has to be stored.
+    pub fn insert_duplicate(&self, mut new_file: FileRecord) -> Result<Option<(i64, ObjectRef)>> {
+        let Some(sha256) = new_file.sha256.clone() else {
+            return Ok(None);
+        };
+        let mut conn = self.conn()?;
+        // Take the write lock up front, so the object can't lose its last reference between
+        // finding it and adding this one
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let existing = tx
+            .query_row(
+                "SELECT files.id, files.blob_id, files.file_id, files.message_id
+                 FROM blobs JOIN files ON files.blob_id = blobs.id
+                 WHERE blobs.backend = ?1 AND blobs.sha256 = ?2 AND blobs.size = ?3
+                 LIMIT 1",
+                rusqlite::params![new_file.backend, sha256, new_file.size],
+                |row| {
+                    Ok((
+                        row.get::<_, i64>(0)?,
+                        row.get::<_, i64>(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let Some((source_id, blob_id, file_id, message_id)) = existing else {
+            return Ok(None);
+        };
+
+        tx.execute(
+            "UPDATE blobs SET ref_count = ref_count + 1 WHERE id = ?1",
+            [blob_id],
+        )?;
+        new_file.file_id = file_id;
+        new_file.message_id = message_id;
+        let row_id = insert_file_row(&tx, &new_file, blob_id)?;
+        tx.execute(
+            "INSERT INTO file_chunks (file_row_id, chunk_index, file_id, message_id, size)
+             SELECT ?1, chunk_index, file_id, message_id, size FROM file_chunks
+             WHERE file_row_id = ?2",
+            [row_id, source_id],
+        )?;
+        tx.commit()?;
+        new_file.id = Some(row_id);
+        Ok(Some((row_id, self.get_object_ref(&new_file)?)))
+    }
+
+    /// Record a stored thumbnail of a stored object
+    pub fn insert_thumbnail(&self, thumbnail: &Thumbnail) -> Result<i64> {
+        let conn = self.conn()?;
         conn.execute(
-            "INSERT INTO files (filename, file_id, message_id, url, year, month, day, uuid)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            "INSERT INTO thumbnails (blob_id, size, width, height, mime_type, byte_size,
+                                     sha256, file_id, message_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
             rusqlite::params![
-                new_file.filename,
-                new_file.file_id,
-                new_file.message_id,
-                new_file.url,
-                new_file.year,
-                new_file.month,
-                new_file.day,
-                new_file.uuid,
+                thumbnail.blob_id,
+                thumbnail.size,
+                thumbnail.width,
+                thumbnail.height,
+                thumbnail.mime_type,
+                thumbnail.byte_size,
+                thumbnail.sha256,
+                thumbnail.file_id,
+                thumbnail.message_id,
             ],
         )?;
         Ok(conn.last_insert_rowid())
     }
-    pub fn get_all_records(&self) -> Result<Vec<FileRecord>> {
-        let conn = Connection::open(&self.db_path)?;
-        let mut stmt = conn.prepare("SELECT * FROM files")?;
-        let rows = stmt.query_map([], |row| FileRecord::from_row(row))?;
 
-        let mut records = Vec::new();
-        for record in rows {
-            let record = record.expect("Failed to map row to FileRecord");
-            records.push(record);
+    /// The largest thumbnail of a stored object that fits `size`. Images smaller than a configured size
+    /// have no thumbnail for it, as the one for the next size down already holds the whole image.
+    pub fn get_thumbnail(&self, blob_id: i64, size: u32) -> Result<Option<Thumbnail>> {
+        let conn = self.conn()?;
+        Ok(conn
+            .query_row(
+                "SELECT * FROM thumbnails WHERE blob_id = ?1 AND size <= ?2
+                 ORDER BY size DESC LIMIT 1",
+                rusqlite::params![blob_id, size],
+                Thumbnail::from_row,
+            )
+            .optional()?)
+    }
+
+    pub fn list_thumbnails(&self, blob_id: i64) -> Result<Vec<Thumbnail>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT * FROM thumbnails WHERE blob_id = ?1 ORDER BY size")?;
+        let thumbnails = stmt
+            .query_map([blob_id], Thumbnail::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(thumbnails)
+    }
+
+    /// Record a converted copy of a stored object. Returns false, recording nothing, when
+    /// another request already recorded one in the same format.
+    pub fn insert_variant(&self, variant: &Variant) -> Result<bool> {
+        let conn = self.conn()?;
+        let stored = variant.stored.as_ref();
+        let inserted = conn.execute(
+            "INSERT INTO variants (blob_id, format, size, sha256, file_id, message_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (blob_id, format) DO NOTHING",
+            rusqlite::params![
+                variant.blob_id,
+                variant.format.as_str(),
+                stored.map(|s| s.size),
+                stored.map(|s| &s.sha256),
+                stored.map(|s| &s.file_id),
+                stored.map(|s| &s.message_id),
+            ],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    pub fn get_variant(&self, blob_id: i64, format: Format) -> Result<Option<Variant>> {
+        let conn = self.conn()?;
+        Ok(conn
+            .query_row(
+                "SELECT * FROM variants WHERE blob_id = ?1 AND format = ?2",
+                rusqlite::params![blob_id, format.as_str()],
+                Variant::from_row,
+            )
+            .optional()?)
+    }
+
+    pub fn list_variants(&self, blob_id: i64) -> Result<Vec<Variant>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT * FROM variants WHERE blob_id = ?1 ORDER BY id")?;
+        let variants = stmt
+            .query_map([blob_id], Variant::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(variants)
+    }
+
+    /// Record a watermarked copy, returning false when one with the same watermark already exists
+    pub fn insert_watermarked(&self, watermarked: &Watermarked) -> Result<bool> {
+        let conn = self.conn()?;
+        let stored = &watermarked.stored;
+        let inserted = conn.execute(
+            "INSERT INTO watermarks (blob_id, fingerprint, size, sha256, file_id, message_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT (blob_id, fingerprint) DO NOTHING",
+            rusqlite::params![
+                watermarked.blob_id,
+                watermarked.fingerprint,
+                stored.size,
+                stored.sha256,
+                stored.file_id,
+                stored.message_id,
+            ],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    pub fn get_watermarked(&self, blob_id: i64, fingerprint: &str) -> Result<Option<Watermarked>> {
+        let conn = self.conn()?;
+        Ok(conn
+            .query_row(
+                "SELECT * FROM watermarks WHERE blob_id = ?1 AND fingerprint = ?2",
+                rusqlite::params![blob_id, fingerprint],
+                Watermarked::from_row,
+            )
+            .optional()?)
+    }
+
+    /// Every watermarked copy of a stored object, including ones made with earlier watermarks
+    pub fn list_watermarked(&self, blob_id: i64) -> Result<Vec<Watermarked>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT * FROM watermarks WHERE blob_id = ?1 ORDER BY id")?;
+        let watermarked = stmt
+            .query_map([blob_id], Watermarked::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(watermarked)
+    }
+
+    /// Chunk list of a file stored in parts, ordered by chunk index; empty for whole files
+    pub fn get_file_parts(&self, file_row_id: i64) -> Result<Vec<ObjectPart>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT chunk_index, file_id, message_id, size FROM file_chunks
+             WHERE file_row_id = ?1 ORDER BY chunk_index",
+        )?;
+        let rows = stmt.query_map([file_row_id], |row| {
+            Ok(ObjectPart {
+                index: row.get(0)?,
+                key: row.get(1)?,
+                handle: row.get(2)?,
+                size: row.get(3)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Reference to the stored object behind `record`, including its chunk list
+    pub fn get_object_ref(&self, record: &FileRecord) -> Result<ObjectRef> {
+        let mut object = record.object_ref();
+        if let Some(id) = record.id {
+            object.parts = self.get_file_parts(id)?;
+        }
+        Ok(object)
+    }
+
+    /// One page of the files matching `query`, with the total number of matches
+    pub fn list_files(&self, query: &FileQuery) -> Result<FilePage> {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        if let Some(owner_id) = query.owned_by {
+            // IS rather than =, so unowned files match a NULL owner
+            conditions.push("owner_id IS ?");
+            params.push(owner_id.map_or(Value::Null, Value::Integer));
+        }
+        // upload_time is stored as "YYYY-MM-DD HH:MM:SS", so dates compare as strings
+        if let Some(from) = query.from {
+            conditions.push("upload_time >= ?");
+            params.push(Value::Text(from.format("%Y-%m-%d").to_string()));
+        }
+        if let Some(to) = query.to.and_then(|to| to.succ_opt()) {
+            conditions.push("upload_time < ?");
+            params.push(Value::Text(to.format("%Y-%m-%d").to_string()));
+        }
+        if let Some(filename) = &query.filename {
+            conditions.push("filename LIKE ? ESCAPE '\\'");
+            params.push(Value::Text(format!("%{}%", escape_like(filename))));
         }
-        Ok(records)
+        if let Some(mime_type) = &query.mime_type {
+            match mime_type.strip_suffix("/*") {
+                Some(family) => {
+                    conditions.push("mime_type LIKE ? ESCAPE '\\'");
+                    params.push(Value::Text(format!("{}/%", escape_like(family))));
+                }
+                None => {
+                    conditions.push("mime_type = ?");
+                    params.push(Value::Text(mime_type.clone()));
+                }
+            }
+        }
+        if let Some(min_size) = query.min_size {
+            conditions.push("size >= ?");
+            params.push(Value::Integer(i64::try_from(min_size).unwrap_or(i64::MAX)));
+        }
+        if let Some(max_size) = query.max_size {
+            conditions.push("size <= ?");
+            params.push(Value::Integer(i64::try_from(max_size).unwrap_or(i64::MAX)));
+        }
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+
+        let conn = self.conn()?;
+        let total: u64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM files {}", filter),
+            rusqlite::params_from_iter(&params),
+            |row| row.get(0),
+        )?;
+
+        let column = match query.sort {
+            SortField::UploadTime => "upload_time",
+            SortField::Filename => "filename",
+            SortField::Size => "size",
+        };
+        let order = match query.order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        let limit = query.limit.clamp(1, FileQuery::MAX_LIMIT);
+        // id breaks ties so pages don't overlap or skip rows with equal sort keys
+        let mut stmt = conn.prepare(&format!(
+            "SELECT * FROM files {} ORDER BY {} {}, id {} LIMIT ? OFFSET ?",
+            filter, column, order, order
+        ))?;
+        params.push(Value::Integer(limit.into()));
+        params.push(Value::Integer(
+            i64::try_from(query.offset).unwrap_or(i64::MAX),
+        ));
+        let files = stmt
+            .query_map(rusqlite::params_from_iter(&params), FileRecord::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(FilePage {
+            total,
+            limit,
+            offset: query.offset,
+            files,
+        })
     }
 
     pub fn get_file_record_by_id(&self, id: i64) -> Result<Option<FileRecord>> {
-        let conn = Connection::open(&self.db_path)?;
+        let conn = self.conn()?;
         let mut stmt = conn.prepare("SELECT * FROM files WHERE id = ?1")?;
-        let mut rows = stmt.query_map([id], |row| FileRecord::from_row(row))?;
+        let mut rows = stmt.query_map([id], FileRecord::from_row)?;
 
         match rows.next() {
             Some(row) => Ok(Some(row?)),
@@ -89,14 +399,14 @@ impl Database {
         }
     }
 
-    fn get_record_by_data_and_uuid(
+    pub fn get_record_by_data_and_uuid(
         &self,
         year: u32,
         month: u32,
         day: u32,
         uuid: &str,
     ) -> Result<Option<FileRecord>> {
-        let conn = Connection::open(&self.db_path)?;
+        let conn = self.conn()?;
         let mut stmt = conn.prepare(
             "SELECT * FROM files WHERE uuid = ?1 AND year = ?2 AND month = ?3 AND day = ?4",
         )?;
@@ -111,137 +421,1096 @@ impl Database {
         }
     }
 
-    pub fn del_record_by_id(&self, id: i64) -> Result<usize> {
-        let conn = Connection::open(&self.db_path)?;
-        let rows_affected = conn.execute("DELETE FROM files WHERE id = ?1", [id])?;
-        if rows_affected == 0 {
-            error!("No record found with id: {}", id);
-        } else {
-            info!("Deleted record with id: {}", id);
+    /// Files whose perceptual hash is within `max_distance` bits of `dhash`, closest first and
+    /// at most `limit` of them. `owned_by` restricts them as in [`FileQuery::owned_by`].
+    pub fn find_similar(
+        &self,
+        dhash: u64,
+        max_distance: u32,
+        owned_by: Option<Option<i64>>,
+        exclude_id: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<SimilarFile>> {
+        let mut matches = Vec::new();
+        {
+            let conn = self.conn()?;
+            // SQLite can't count bits, so every hash is compared here; the scan reads only the index
+            let mut sql = "SELECT id, dhash FROM files WHERE dhash IS NOT NULL".to_string();
+            let mut params = Vec::new();
+            if let Some(owner_id) = owned_by {
+                sql.push_str(" AND owner_id IS ?");
+                params.push(owner_id.map_or(Value::Null, Value::Integer));
+            }
+            let mut stmt = conn.prepare(&sql)?;
+            let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?;
+            for row in rows {
+                let (id, hash) = row?;
+                let Some(hash) = similarity::from_hex(&hash) else {
+                    continue;
+                };
+                let distance = similarity::distance(dhash, hash);
+                if distance <= max_distance && Some(id) != exclude_id {
+                    matches.push((distance, id));
+                }
+            }
+        }
+        matches.sort_unstable();
+        matches.truncate(limit);
+
+        let mut files = Vec::with_capacity(matches.len());
+        for (distance, id) in matches {
+            if let Some(file) = self.get_file_record_by_id(id)? {
+                files.push(SimilarFile { file, distance });
+            }
         }
-        Ok(rows_affected)
+        Ok(files)
+    }
+
+    /// Images uploaded before placeholders or perceptual hashes were recorded, oldest first
+    pub fn list_files_to_backfill(&self) -> Result<Vec<FileRecord>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare(
+            "SELECT * FROM files WHERE (blurhash IS NULL OR dhash IS NULL)
+             AND mime_type LIKE 'image/%' ORDER BY id",
+        )?;
+        let files = stmt
+            .query_map([], FileRecord::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(files)
     }
 
-    /// Fetch the binary content of the file from the URL stored in the database record.
-    pub async fn get_record_content(
+    /// Fill in the media details of a file from a later probe, keeping any already recorded
+    pub fn update_media(&self, id: i64, media: &MediaInfo) -> Result<bool> {
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE files SET
+                 width = COALESCE(width, ?2),
+                 height = COALESCE(height, ?3),
+                 duration = COALESCE(duration, ?4),
+                 frame_count = COALESCE(frame_count, ?5),
+                 dominant_color = COALESCE(dominant_color, ?6),
+                 captured_at = COALESCE(captured_at, ?7),
+                 blurhash = COALESCE(blurhash, ?8),
+                 lqip = COALESCE(lqip, ?9),
+                 dhash = COALESCE(dhash, ?10)
+             WHERE id = ?1",
+            rusqlite::params![
+                id,
+                media.width,
+                media.height,
+                media.duration,
+                media.frame_count,
+                media.dominant_color,
+                media.captured_at,
+                media.blurhash,
+                media.lqip,
+                media.dhash,
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Delete a file record, returning how many records still share its stored object, so the
+    /// object itself is only deleted at zero. `None` if there is no such record.
+    pub fn del_record_by_id(&self, id: i64) -> Result<Option<u64>> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let blob_id: Option<Option<i64>> = tx
+            .query_row("SELECT blob_id FROM files WHERE id = ?1", [id], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        let Some(blob_id) = blob_id else {
+            error!("No record found with id: {}", id);
+            return Ok(None);
+        };
+        tx.execute("DELETE FROM file_chunks WHERE file_row_id = ?1", [id])?;
+        tx.execute("DELETE FROM files WHERE id = ?1", [id])?;
+        let remaining = match blob_id {
+            Some(blob_id) => {
+                let remaining: u64 = tx.query_row(
+                    "UPDATE blobs SET ref_count = ref_count - 1 WHERE id = ?1 RETURNING ref_count",
+                    [blob_id],
+                    |row| row.get(0),
+                )?;
+                if remaining == 0 {
+                    tx.execute("DELETE FROM thumbnails WHERE blob_id = ?1", [blob_id])?;
+                    tx.execute("DELETE FROM variants WHERE blob_id = ?1", [blob_id])?;
+                    tx.execute("DELETE FROM watermarks WHERE blob_id = ?1", [blob_id])?;
+                    tx.execute("DELETE FROM blobs WHERE id = ?1", [blob_id])?;
+                }
+                remaining
+            }
+            None => 0,
+        };
+        tx.commit()?;
+        info!(
+            "Deleted record with id: {} ({} references left)",
+            id, remaining
+        );
+        Ok(Some(remaining))
+    }
+
+    /// Record a new API key by its hash, returning its id
+    pub fn insert_api_key(
         &self,
-        year: u32,
-        month: u32,
-        day: u32,
-        uuid: &str,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let record = self.get_record_by_data_and_uuid(year, month, day, uuid)?;
-        let file_record = record.ok_or("Record not found")?;
-        let url = &file_record.url;
-        info!("Fetching content from URL: {}", url);
-        let response = reqwest::get(url).await.unwrap();
-        info!("Response status code: {}", response.status());
-        if !response.status().is_success() {
-            return Err(format!("Failed to fetch content from URL: {}", url).into());
-        }
-        let content = response.bytes().await.unwrap().to_vec();
-        Ok(content)
+        name: &str,
+        prefix: &str,
+        key_hash: &str,
+        scopes: &[Scope],
+        user_id: Option<i64>,
+        skip_watermark: bool,
+    ) -> Result<i64> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO api_keys (name, prefix, key_hash, scopes, user_id, skip_watermark)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![
+                name,
+                prefix,
+                key_hash,
+                format_scopes(scopes),
+                user_id,
+                skip_watermark
+            ],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// The unrevoked key with this hash, marking it as used just now
+    pub fn use_api_key(&self, key_hash: &str) -> Result<Option<ApiKey>> {
+        let conn = self.conn()?;
+        let key = conn
+            .query_row(
+                "UPDATE api_keys SET last_used_at = CURRENT_TIMESTAMP
+                 WHERE key_hash = ?1 AND revoked_at IS NULL
+                 RETURNING *",
+                [key_hash],
+                ApiKey::from_row,
+            )
+            .optional()?;
+        Ok(key)
+    }
+
+    pub fn list_api_keys(&self) -> Result<Vec<ApiKey>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT * FROM api_keys ORDER BY id")?;
+        let keys = stmt
+            .query_map([], ApiKey::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(keys)
+    }
+
+    /// Revoke a key, returning false if there is no such unrevoked key. Revoked keys are
+    /// kept so listings still show what they were.
+    pub fn revoke_api_key(&self, id: i64) -> Result<bool> {
+        let conn = self.conn()?;
+        let revoked = conn.execute(
+            "UPDATE api_keys SET revoked_at = CURRENT_TIMESTAMP
+             WHERE id = ?1 AND revoked_at IS NULL",
+            [id],
+        )?;
+        Ok(revoked > 0)
+    }
+
+    pub fn insert_user(&self, username: &str, password_hash: &str, is_admin: bool) -> Result<i64> {
+        let conn = self.conn()?;
+        conn.execute(
+            "INSERT INTO users (username, password_hash, is_admin) VALUES (?1, ?2, ?3)",
+            rusqlite::params![username, password_hash, is_admin],
+        )?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// The user with this username, ignoring case, and their password hash
+    pub fn get_login(&self, username: &str) -> Result<Option<(User, String)>> {
+        let conn = self.conn()?;
+        let login = conn
+            .query_row(
+                "SELECT * FROM users WHERE username = ?1",
+                [username],
+                |row| Ok((User::from_row(row)?, row.get("password_hash")?)),
+            )
+            .optional()?;
+        Ok(login)
+    }
+
+    pub fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
+        Ok(self.get_login(username)?.map(|(user, _)| user))
+    }
+
+    pub fn list_users(&self) -> Result<Vec<User>> {
+        let conn = self.conn()?;
+        let mut stmt = conn.prepare("SELECT * FROM users ORDER BY id")?;
+        let users = stmt
+            .query_map([], User::from_row)?
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(users)
+    }
+
+    /// Change a user's password and end their sessions, returning false if there is no such user
+    pub fn set_password(&self, username: &str, password_hash: &str) -> Result<bool> {
+        let mut conn = self.conn()?;
+        let tx = conn.transaction()?;
+        let user_id: Option<i64> = tx
+            .query_row(
+                "UPDATE users SET password_hash = ?1 WHERE username = ?2 RETURNING id",
+                [password_hash, username],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(user_id) = user_id {
+            tx.execute("DELETE FROM sessions WHERE user_id = ?1", [user_id])?;
+        }
+        tx.commit()?;
+        Ok(user_id.is_some())
+    }
+
+    /// Start a session for `user_id` lasting `ttl_secs`, clearing out expired ones
+    pub fn create_session(&self, token_hash: &str, user_id: i64, ttl_secs: u64) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute(
+            "DELETE FROM sessions WHERE expires_at <= CURRENT_TIMESTAMP",
+            [],
+        )?;
+        conn.execute(
+            "INSERT INTO sessions (token_hash, user_id, expires_at)
+             VALUES (?1, ?2, datetime('now', ?3))",
+            rusqlite::params![token_hash, user_id, format!("+{} seconds", ttl_secs)],
+        )?;
+        Ok(())
+    }
+
+    /// The user an unexpired session belongs to
+    pub fn get_session_user(&self, token_hash: &str) -> Result<Option<User>> {
+        let conn = self.conn()?;
+        let user = conn
+            .query_row(
+                "SELECT users.* FROM sessions JOIN users ON users.id = sessions.user_id
+                 WHERE sessions.token_hash = ?1 AND sessions.expires_at > CURRENT_TIMESTAMP",
+                [token_hash],
+                User::from_row,
+            )
+            .optional()?;
+        Ok(user)
+    }
+
+    /// Replace a user's own limits, returning false if there is no such user
+    pub fn set_user_limits(&self, username: &str, limits: &Limits) -> Result<bool> {
+        let conn = self.conn()?;
+        let updated = conn.execute(
+            "UPDATE users SET max_upload_size = ?1, max_total_bytes = ?2, max_files_per_day = ?3
+             WHERE username = ?4",
+            rusqlite::params![
+                limits.max_upload_size,
+                limits.max_total_bytes,
+                limits.max_files_per_day,
+                username
+            ],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Files and bytes stored by `owner_id`, or by nobody for `None`
+    pub fn get_usage(&self, owner_id: Option<i64>) -> Result<Usage> {
+        let conn = self.conn()?;
+        let usage = conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(size), 0),
+                    COALESCE(SUM(upload_time >= date('now')), 0)
+             FROM files WHERE owner_id IS ?1",
+            [owner_id],
+            |row| {
+                Ok(Usage {
+                    files: row.get(0)?,
+                    total_bytes: row.get(1)?,
+                    files_today: row.get(2)?,
+                })
+            },
+        )?;
+        Ok(usage)
+    }
+
+    /// The limits that apply to `owner_id` and what they have used of them
+    pub fn get_quota(&self, owner_id: Option<i64>, defaults: &Limits) -> Result<Quota> {
+        let own_limits = match owner_id {
+            Some(id) => {
+                let conn = self.conn()?;
+                conn.query_row("SELECT * FROM users WHERE id = ?1", [id], User::from_row)
+                    .optional()?
+                    .map(|user| user.limits)
+                    .unwrap_or_default()
+            }
+            None => Limits::default(),
+        };
+        Ok(Quota {
+            limits: own_limits.or(defaults),
+            usage: self.get_usage(owner_id)?,
+        })
+    }
+
+    pub fn delete_session(&self, token_hash: &str) -> Result<()> {
+        let conn = self.conn()?;
+        conn.execute("DELETE FROM sessions WHERE token_hash = ?1", [token_hash])?;
+        Ok(())
+    }
+}
+
+fn insert_file_row(tx: &Transaction, file: &FileRecord, blob_id: i64) -> rusqlite::Result<i64> {
+    tx.execute(
+        "INSERT INTO files (filename, file_id, message_id, url, year, month, day, uuid, backend,
+                            size, sha256, mime_type, owner_id, blob_id, width, height,
+                            duration, frame_count, dominant_color, captured_at, blurhash, lqip,
+                            dhash)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                 ?18, ?19, ?20, ?21, ?22, ?23)",
+        rusqlite::params![
+            file.filename,
+            file.file_id,
+            file.message_id,
+            file.url,
+            file.year,
+            file.month,
+            file.day,
+            file.uuid,
+            file.backend,
+            file.size,
+            file.sha256,
+            file.mime_type,
+            file.owner_id,
+            blob_id,
+            file.media.width,
+            file.media.height,
+            file.media.duration,
+            file.media.frame_count,
+            file.media.dominant_color,
+            file.media.captured_at,
+            file.media.blurhash,
+            file.media.lqip,
+            file.media.dhash,
+        ],
+    )?;
+    Ok(tx.last_insert_rowid())
+}
+
+/// Escape `%`, `_` and the escape character itself for a `LIKE ... ESCAPE '\\'` pattern.
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::StoredVariant;
+    use chrono::NaiveDate;
+    use rusqlite::Connection;
+    use std::path::PathBuf;
 
-    #[test]
-    fn test_database_initialization() {
-        let db = Database::new("test.db");
-        assert!(db.init_db().is_ok());
-        // Clean up test database file
-        std::fs::remove_file("test.db").unwrap();
+    /// Path for a database file named `name` inside a fresh temporary directory.
+    fn temp_db_path(name: &str) -> (PathBuf, String) {
+        let dir = std::env::temp_dir().join(format!("rih-db-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name).to_string_lossy().into_owned();
+        (dir, path)
     }
 
-    #[test]
-    fn test_insert_file() {
-        let db = Database::new("test_files.db");
-        db.init_db().unwrap();
+    fn temp_db(name: &str) -> (PathBuf, Database) {
+        let (dir, path) = temp_db_path(name);
+        (dir, Database::open(&path).unwrap())
+    }
 
-        let file_record = FileRecord::new(
+    fn sample_record(uuid: &str) -> FileRecord {
+        FileRecord::new(
             "test.txt".to_string(),
             "http://example.com/test.txt".to_string(),
             2023,
             10,
             1,
-            "uuid-1234".to_string(),
+            uuid.to_string(),
             "file-id-1234".to_string(),
             "message-id-1234".to_string(),
-        );
+        )
+    }
+
+    #[test]
+    fn test_database_initialization() {
+        let (dir, db) = temp_db("test.db");
+        assert!(db.migrate().unwrap().is_empty());
+        // Clean up test database file
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_on_pool() {
+        let (dir, db) = temp_db("test_pool.db");
+        let mode: String = db
+            .run(|db| {
+                Ok(db
+                    .conn()?
+                    .query_row("PRAGMA journal_mode", [], |r| r.get(0))?)
+            })
+            .await
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        let inserts: Vec<_> = (0..8)
+            .map(|i| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    db.run(move |db| db.insert_file(sample_record(&format!("uuid-{}", i)), &[]))
+                        .await
+                })
+            })
+            .collect();
+        for insert in inserts {
+            insert.await.unwrap().unwrap();
+        }
+        let page = db
+            .run(|db| db.list_files(&FileQuery::default()))
+            .await
+            .unwrap();
+        assert_eq!(page.total, 8);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_open_upgrades_legacy_rows() {
+        let (dir, path) = temp_db_path("test_legacy.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute(
+            "CREATE TABLE files (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                filename TEXT NOT NULL,
+                file_id TEXT,
+                message_id TEXT,
+                url TEXT NOT NULL,
+                year INTEGER,
+                month INTEGER,
+                day INTEGER,
+                uuid TEXT NOT NULL,
+                upload_time TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+            )",
+            [],
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO files (filename, file_id, message_id, url, year, month, day, uuid)
+             VALUES ('a', 'f', 'm', 'https://api.telegram.org/file/bot1:abc/documents/file_1',
+                     2024, 1, 2, 'legacy')",
+            [],
+        )
+        .unwrap();
+
+        let db = Database::open(&path).unwrap();
+        let records = db.list_files(&FileQuery::default()).unwrap().files;
+        assert_eq!(records[0].backend, "telegram");
+        assert_eq!(records[0].url, "/find/2024/1/2/legacy");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 
-        let row_id = db.insert_file(file_record).unwrap();
+    #[test]
+    fn test_insert_file() {
+        let (dir, db) = temp_db("test_insert.db");
+
+        let mut record = sample_record("uuid-1234");
+        record.media = MediaInfo {
+            width: Some(640),
+            height: Some(480),
+            duration: Some(2.5),
+            frame_count: Some(12),
+            dominant_color: Some("#336699".to_string()),
+            captured_at: Some("2024-05-01 12:00:00".to_string()),
+            blurhash: Some("L00000fQfQfQfQfQfQfQfQfQfQfQ".to_string()),
+            lqip: Some("data:image/jpeg;base64,AA==".to_string()),
+            dhash: Some("f0e4c2d7c8a0b1e3".to_string()),
+        };
+        let row_id = db.insert_file(record.clone(), &[]).unwrap();
         println!("Inserted row ID: {}", row_id);
         assert!(row_id > 0);
+        let stored = db.get_file_record_by_id(row_id).unwrap().unwrap();
+        assert_eq!(stored.media, record.media);
 
         // Clean up test database file
-        // std::fs::remove_file("test_insert.db").unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
-    fn test_get_all_records() {
-        let db = Database::new("test_files.db");
-        db.init_db().unwrap();
-        let records = db.get_all_records().unwrap();
-        assert!(!records.is_empty());
-        println!("Retrieved records: {:#?}", records);
+    fn test_update_media() {
+        let (dir, db) = temp_db("test_update_media.db");
+        let mut record = sample_record("uuid-old");
+        record.mime_type = Some("image/png".to_string());
+        record.media.width = Some(10);
+        let id = db.insert_file(record, &[]).unwrap();
+        let mut text = sample_record("uuid-text");
+        text.mime_type = Some("text/plain".to_string());
+        db.insert_file(text, &[]).unwrap();
 
-        // Clean up test database file
-        // std::fs::remove_file("test_get_all.db").unwrap();
+        let pending = db.list_files_to_backfill().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, Some(id));
+
+        let probed = MediaInfo {
+            width: Some(20),
+            height: Some(30),
+            blurhash: Some("LEHV6nWB2yk8pyo0adR*.7kCMdnj".to_string()),
+            dhash: Some("0000ffff0000ffff".to_string()),
+            ..Default::default()
+        };
+        assert!(db.update_media(id, &probed).unwrap());
+        let stored = db.get_file_record_by_id(id).unwrap().unwrap();
+        // Details already recorded are kept
+        assert_eq!(stored.media.width, Some(10));
+        assert_eq!(stored.media.height, Some(30));
+        assert_eq!(stored.media.blurhash, probed.blurhash);
+        assert!(db.list_files_to_backfill().unwrap().is_empty());
+        assert!(!db.update_media(id + 100, &probed).unwrap());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_find_similar() {
+        let (dir, db) = temp_db("test_find_similar.db");
+        let alice = db.insert_user("alice", "hash", false).unwrap();
+        let bob = db.insert_user("bob", "hash", false).unwrap();
+        let mut ids = Vec::new();
+        for (i, (owner_id, dhash)) in [
+            (alice, Some("00000000000000ff")),
+            (alice, Some("00000000000000fe")),
+            (alice, Some("000000000000000f")),
+            (bob, Some("00000000000000ff")),
+            (alice, None),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let mut record = sample_record(&format!("uuid-{}", i));
+            record.owner_id = Some(owner_id);
+            record.media.dhash = dhash.map(str::to_string);
+            ids.push(db.insert_file(record, &[]).unwrap());
+        }
+        let similar = |max_distance, owned_by| -> Vec<(i64, u32)> {
+            db.find_similar(0xff, max_distance, owned_by, Some(ids[0]), 10)
+                .unwrap()
+                .into_iter()
+                .map(|s| (s.file.id.unwrap(), s.distance))
+                .collect()
+        };
+
+        // Closest first, without the file itself or other owners' files
+        assert_eq!(similar(4, Some(Some(alice))), [(ids[1], 1), (ids[2], 4)]);
+        assert_eq!(similar(3, Some(Some(alice))), [(ids[1], 1)]);
+        // Admins see everyone's
+        assert_eq!(similar(1, None), [(ids[3], 0), (ids[1], 1)]);
+        assert!(similar(8, Some(None)).is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_files() {
+        let (dir, db) = temp_db("test_list.db");
+        let uploads = [
+            ("cat.png", "image/png", 300, "2024-01-01 10:00:00"),
+            ("dog.jpg", "image/jpeg", 100, "2024-01-02 10:00:00"),
+            ("notes_1.txt", "text/plain", 200, "2024-01-03 10:00:00"),
+            ("notes%2.txt", "text/plain", 200, "2024-01-04 10:00:00"),
+        ];
+        for (i, (filename, mime_type, size, upload_time)) in uploads.iter().enumerate() {
+            let mut record = sample_record(&format!("uuid-{}", i));
+            record.filename = filename.to_string();
+            record.mime_type = Some(mime_type.to_string());
+            record.size = Some(*size);
+            let id = db.insert_file(record, &[]).unwrap();
+            db.conn()
+                .unwrap()
+                .execute(
+                    "UPDATE files SET upload_time = ?1 WHERE id = ?2",
+                    rusqlite::params![upload_time, id],
+                )
+                .unwrap();
+        }
+        let names = |query: FileQuery| -> (u64, Vec<String>) {
+            let page = db.list_files(&query).unwrap();
+            (
+                page.total,
+                page.files.into_iter().map(|f| f.filename).collect(),
+            )
+        };
+
+        // Newest first by default
+        let (total, files) = names(FileQuery::default());
+        assert_eq!(total, 4);
+        assert_eq!(files, ["notes%2.txt", "notes_1.txt", "dog.jpg", "cat.png"]);
+
+        // Pages share the total and don't overlap on equal sort keys
+        let page = |offset| FileQuery {
+            sort: SortField::Size,
+            order: SortOrder::Asc,
+            limit: 2,
+            offset,
+            ..Default::default()
+        };
+        assert_eq!(
+            names(page(0)),
+            (4, vec!["dog.jpg".into(), "notes_1.txt".into()])
+        );
+        assert_eq!(
+            names(page(2)),
+            (4, vec!["notes%2.txt".into(), "cat.png".into()])
+        );
+        assert_eq!(names(page(4)), (4, vec![]));
+
+        let (total, files) = names(FileQuery {
+            from: NaiveDate::from_ymd_opt(2024, 1, 2),
+            to: NaiveDate::from_ymd_opt(2024, 1, 3),
+            ..Default::default()
+        });
+        assert_eq!(total, 2);
+        assert_eq!(files, ["notes_1.txt", "dog.jpg"]);
+
+        // LIKE wildcards in the search are matched literally
+        let search = |filename: &str| FileQuery {
+            filename: Some(filename.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(names(search("NOTES")).0, 2);
+        assert_eq!(names(search("%")).1, ["notes%2.txt"]);
+        assert_eq!(names(search("_")).1, ["notes_1.txt"]);
+
+        let mime = |mime_type: &str| FileQuery {
+            mime_type: Some(mime_type.to_string()),
+            ..Default::default()
+        };
+        assert_eq!(names(mime("image/*")).0, 2);
+        assert_eq!(names(mime("image/png")).1, ["cat.png"]);
+
+        let (total, files) = names(FileQuery {
+            min_size: Some(150),
+            max_size: Some(250),
+            sort: SortField::Filename,
+            order: SortOrder::Asc,
+            ..Default::default()
+        });
+        assert_eq!(total, 2);
+        assert_eq!(files, ["notes%2.txt", "notes_1.txt"]);
+
+        // Oversized pages are clamped
+        let page = db
+            .list_files(&FileQuery {
+                limit: 100_000,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(page.limit, FileQuery::MAX_LIMIT);
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
     fn test_get_record_by_data_and_uuid() {
-        let db = Database::new("db.db");
-        db.init_db().unwrap();
-
-        // let file_record = FileRecord::new(
-        //     "test3.txt".to_string(),
-        //     "http://example.com/test3.txt".to_string(),
-        //     2023,
-        //     10,
-        //     3,
-        //     "uuid-91011".to_string(),
-        //     "file-id-91011".to_string(),
-        //     "message-id-91011".to_string(),
-        // );
-
-        // db.insert_file(file_record).unwrap();
+        let (dir, db) = temp_db("test_get_by_data.db");
+        let mut record = sample_record("uuid-91011");
+        record.mime_type = Some("image/png".to_string());
+        db.insert_file(record, &[]).unwrap();
+
         let retrieved_record = db
-            .get_record_by_data_and_uuid(2025, 5, 31, "6d477385-6336-4f4f-9b3f-0a45b17db477")
+            .get_record_by_data_and_uuid(2023, 10, 1, "uuid-91011")
             .unwrap();
         assert!(retrieved_record.is_some());
+        assert_eq!(
+            retrieved_record.as_ref().unwrap().mime_type.as_deref(),
+            Some("image/png")
+        );
         println!("Retrieved record: {:#?}", retrieved_record);
 
         // Clean up test database file
-        // std::fs::remove_file("test_get_by_data.db").unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_parts_roundtrip() {
+        let (dir, db) = temp_db("test_parts.db");
+        let parts: Vec<ObjectPart> = (0..3)
+            .map(|i| ObjectPart {
+                index: i,
+                key: format!("file-id-{}", i),
+                handle: format!("{}", 100 + i),
+                size: 10,
+            })
+            .collect();
+
+        let row_id = db.insert_file(sample_record("uuid-parts"), &parts).unwrap();
+        let record = db.get_file_record_by_id(row_id).unwrap().unwrap();
+        let object = db.get_object_ref(&record).unwrap();
+        assert_eq!(object.parts, parts);
+
+        db.del_record_by_id(row_id).unwrap();
+        assert!(db.get_file_parts(row_id).unwrap().is_empty());
+
+        // Clean up test database file
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_api_keys() {
+        let (dir, db) = temp_db("test_api_keys.db");
+        let id = db
+            .insert_api_key(
+                "ci",
+                "rih_0123abcd",
+                "hash",
+                &[Scope::Upload, Scope::List],
+                None,
+                true,
+            )
+            .unwrap();
+        assert!(db.use_api_key("other").unwrap().is_none());
+
+        let key = db.use_api_key("hash").unwrap().unwrap();
+        assert_eq!(key.id, id);
+        assert_eq!(key.name, "ci");
+        assert_eq!(key.scopes, [Scope::Upload, Scope::List]);
+        assert!(key.skip_watermark);
+        assert!(key.last_used_at.is_some());
+
+        assert!(db.revoke_api_key(id).unwrap());
+        assert!(!db.revoke_api_key(id).unwrap());
+        assert!(db.use_api_key("hash").unwrap().is_none());
+        let keys = db.list_api_keys().unwrap();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].revoked_at.is_some());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_users_and_sessions() {
+        let (dir, db) = temp_db("test_users.db");
+        let id = db.insert_user("Alice", "hash-1", false).unwrap();
+        assert!(db.insert_user("alice", "hash-2", false).is_err());
+
+        let (user, hash) = db.get_login("ALICE").unwrap().unwrap();
+        assert_eq!(
+            (user.id, user.username.as_str(), hash.as_str()),
+            (id, "Alice", "hash-1")
+        );
+        assert!(!user.is_admin);
+        assert!(db.get_login("bob").unwrap().is_none());
+
+        db.create_session("token", id, 60).unwrap();
+        db.create_session("stale", id, 0).unwrap();
+        assert_eq!(db.get_session_user("token").unwrap().unwrap().id, id);
+        assert!(db.get_session_user("stale").unwrap().is_none());
+
+        // Changing the password logs the user out everywhere
+        assert!(db.set_password("alice", "hash-3").unwrap());
+        assert!(!db.set_password("bob", "hash-3").unwrap());
+        assert!(db.get_session_user("token").unwrap().is_none());
+        assert_eq!(db.get_login("alice").unwrap().unwrap().1, "hash-3");
+
+        db.create_session("token", id, 60).unwrap();
+        db.delete_session("token").unwrap();
+        assert!(db.get_session_user("token").unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_list_files_owned_by() {
+        let (dir, db) = temp_db("test_list_owned.db");
+        let alice = db.insert_user("alice", "hash", false).unwrap();
+        let bob = db.insert_user("bob", "hash", false).unwrap();
+        for owner_id in [Some(alice), Some(bob), None] {
+            let mut record = sample_record(&format!("uuid-{:?}", owner_id));
+            record.owner_id = owner_id;
+            db.insert_file(record, &[]).unwrap();
+        }
+        let owned_by = |owned_by| {
+            db.list_files(&FileQuery {
+                owned_by,
+                ..Default::default()
+            })
+            .unwrap()
+            .files
+            .into_iter()
+            .map(|f| f.owner_id)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(owned_by(None).len(), 3);
+        assert_eq!(owned_by(Some(Some(bob))), [Some(bob)]);
+        assert_eq!(owned_by(Some(None)), [None]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_quota() {
+        let (dir, db) = temp_db("test_quota.db");
+        let alice = db.insert_user("alice", "hash", false).unwrap();
+        for (i, (owner_id, size)) in [(Some(alice), 100), (Some(alice), 50), (None, 7)]
+            .into_iter()
+            .enumerate()
+        {
+            let mut record = sample_record(&format!("uuid-{}", i));
+            record.owner_id = owner_id;
+            record.size = Some(size);
+            db.insert_file(record, &[]).unwrap();
+        }
+        db.conn()
+            .unwrap()
+            .execute(
+                "UPDATE files SET upload_time = '2000-01-01 00:00:00' WHERE size = 50",
+                [],
+            )
+            .unwrap();
+
+        let defaults = Limits {
+            max_upload_size: Some(10),
+            max_total_bytes: Some(1000),
+            max_files_per_day: None,
+        };
+        let quota = db.get_quota(Some(alice), &defaults).unwrap();
+        assert_eq!(quota.limits, defaults);
+        assert_eq!(
+            quota.usage,
+            Usage {
+                files: 2,
+                total_bytes: 150,
+                files_today: 1
+            }
+        );
+        assert_eq!(db.get_usage(None).unwrap().total_bytes, 7);
+
+        let own = Limits {
+            max_total_bytes: Some(200),
+            ..Default::default()
+        };
+        assert!(db.set_user_limits("alice", &own).unwrap());
+        assert!(!db.set_user_limits("bob", &own).unwrap());
+        let quota = db.get_quota(Some(alice), &defaults).unwrap();
+        assert_eq!(quota.limits.max_total_bytes, Some(200));
+        assert_eq!(quota.limits.max_upload_size, Some(10));
+        assert_eq!(quota.remaining_bytes(), Some(50));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_insert_duplicate() {
+        let (dir, db) = temp_db("test_dedup.db");
+        let with_hash = |uuid: &str, sha256: &str| {
+            let mut record = sample_record(uuid);
+            record.sha256 = Some(sha256.to_string());
+            record.size = Some(11);
+            record
+        };
+        assert!(
+            db.insert_duplicate(with_hash("a", "abc"))
+                .unwrap()
+                .is_none()
+        );
+        let parts: Vec<ObjectPart> = (0..2)
+            .map(|i| ObjectPart {
+                index: i,
+                key: format!("part-{}", i),
+                handle: format!("{}", 100 + i),
+                size: 6,
+            })
+            .collect();
+        let first = db.insert_file(with_hash("a", "abc"), &parts).unwrap();
+        assert!(
+            db.insert_duplicate(with_hash("b", "xyz"))
+                .unwrap()
+                .is_none()
+        );
+
+        let mut copy = with_hash("c", "abc");
+        copy.file_id = "ignored".to_string();
+        let (second, object) = db.insert_duplicate(copy).unwrap().unwrap();
+        assert_eq!(object.key, "file-id-1234");
+        assert_eq!(object.parts, parts);
+        let record = db.get_file_record_by_id(second).unwrap().unwrap();
+        assert_eq!(record.uuid, "c");
+        assert_eq!(
+            db.get_object_ref(&record).unwrap().handle,
+            "message-id-1234"
+        );
+
+        // The object outlives the first record and goes with the last
+        assert_eq!(db.del_record_by_id(first).unwrap(), Some(1));
+        assert_eq!(db.get_file_parts(second).unwrap(), parts);
+        assert_eq!(db.del_record_by_id(second).unwrap(), Some(0));
+        assert!(
+            db.insert_duplicate(with_hash("d", "abc"))
+                .unwrap()
+                .is_none()
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_thumbnails() {
+        let (dir, db) = temp_db("test_thumbnails.db");
+        let mut record = sample_record("t");
+        record.sha256 = Some("abc".to_string());
+        record.size = Some(3);
+        let row_id = db.insert_file(record, &[]).unwrap();
+        let blob_id = db
+            .get_file_record_by_id(row_id)
+            .unwrap()
+            .unwrap()
+            .blob_id
+            .unwrap();
+        // A duplicate uploaded while the first upload's thumbnails are still being made
+        let mut copy = sample_record("u");
+        copy.sha256 = Some("abc".to_string());
+        copy.size = Some(3);
+        let (copy_id, _) = db.insert_duplicate(copy).unwrap().unwrap();
+        for (size, width) in [(128, 128), (256, 200)] {
+            db.insert_thumbnail(&Thumbnail {
+                id: None,
+                blob_id,
+                size,
+                width,
+                height: width / 2,
+                mime_type: "image/jpeg".to_string(),
+                byte_size: 100,
+                sha256: format!("thumb-{}", size),
+                file_id: format!("key-{}", size),
+                message_id: String::new(),
+                created_at: None,
+            })
+            .unwrap();
+        }
+        assert!(db.get_thumbnail(blob_id, 64).unwrap().is_none());
+        assert_eq!(db.get_thumbnail(blob_id, 128).unwrap().unwrap().width, 128);
+        // The image fits 256, so that thumbnail also stands in for larger sizes
+        let largest = db.get_thumbnail(blob_id, 512).unwrap().unwrap();
+        assert_eq!((largest.size, largest.width), (256, 200));
+        assert_eq!(largest.object_ref().key, "key-256");
+
+        // Both records see them, and they go with the last one
+        let copy_blob = db.get_file_record_by_id(copy_id).unwrap().unwrap().blob_id;
+        assert_eq!(copy_blob, Some(blob_id));
+        db.del_record_by_id(row_id).unwrap();
+        assert_eq!(db.list_thumbnails(blob_id).unwrap().len(), 2);
+        db.del_record_by_id(copy_id).unwrap();
+        assert!(db.list_thumbnails(blob_id).unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_variants() {
+        let (dir, db) = temp_db("test_variants.db");
+        let mut record = sample_record("v");
+        record.sha256 = Some("abc".to_string());
+        record.size = Some(3);
+        let first = db.insert_file(record.clone(), &[]).unwrap();
+        let (second, _) = db.insert_duplicate(record).unwrap().unwrap();
+        let blob_id = db
+            .get_file_record_by_id(second)
+            .unwrap()
+            .unwrap()
+            .blob_id
+            .unwrap();
+        assert_eq!(
+            db.get_file_record_by_id(first).unwrap().unwrap().blob_id,
+            Some(blob_id)
+        );
+
+        let webp = Variant {
+            id: None,
+            blob_id,
+            format: Format::Webp,
+            stored: Some(StoredVariant {
+                size: 2,
+                sha256: "webp-hash".to_string(),
+                file_id: "key.webp".to_string(),
+                message_id: String::new(),
+            }),
+            created_at: None,
+        };
+        assert!(db.insert_variant(&webp).unwrap());
+        // A second conversion to the same format loses
+        assert!(!db.insert_variant(&webp).unwrap());
+        let avif = Variant {
+            format: Format::Avif,
+            stored: None,
+            ..webp
+        };
+        assert!(db.insert_variant(&avif).unwrap());
+
+        let found = db.get_variant(blob_id, Format::Webp).unwrap().unwrap();
+        assert_eq!(found.stored.unwrap().object_ref().key, "key.webp");
+        assert!(
+            db.get_variant(blob_id, Format::Avif)
+                .unwrap()
+                .unwrap()
+                .stored
+                .is_none()
+        );
+        assert!(db.get_variant(blob_id, Format::Png).unwrap().is_none());
+
+        let watermarked = Watermarked {
+            blob_id,
+            fingerprint: "0123abcd".to_string(),
+            stored: StoredVariant {
+                size: 4,
+                sha256: "marked-hash".to_string(),
+                file_id: "key-watermark.png".to_string(),
+                message_id: String::new(),
+            },
+        };
+        assert!(db.insert_watermarked(&watermarked).unwrap());
+        assert!(!db.insert_watermarked(&watermarked).unwrap());
+        let found = db.get_watermarked(blob_id, "0123abcd").unwrap().unwrap();
+        assert_eq!(found.stored.sha256, "marked-hash");
+        assert!(db.get_watermarked(blob_id, "other").unwrap().is_none());
+
+        // Cleanup mustn't depend on ON DELETE CASCADE, which only works with foreign keys on
+        let conns: Vec<_> = (0..db.pool.max_size())
+            .map(|_| db.conn().unwrap())
+            .collect();
+        for conn in &conns {
+            conn.execute_batch("PRAGMA foreign_keys = OFF").unwrap();
+        }
+        drop(conns);
+
+        // Variants belong to the stored object, so they go with its last record
+        db.del_record_by_id(first).unwrap();
+        assert_eq!(db.list_variants(blob_id).unwrap().len(), 2);
+        assert_eq!(db.list_watermarked(blob_id).unwrap().len(), 1);
+        db.del_record_by_id(second).unwrap();
+        assert!(db.list_variants(blob_id).unwrap().is_empty());
+        assert!(db.list_watermarked(blob_id).unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
     }
 
     #[test]
     fn test_del_record_by_id() {
-        let db = Database::new("db.db");
-        db.init_db().unwrap();
-
-        // let file_record = FileRecord::new(
-        //     "test4.txt".to_string(),
-        //     "http://example.com/test4.txt".to_string(),
-        //     2023,
-        //     10,
-        //     4,
-        //     "uuid-121314".to_string(),
-        //     "file-id-121314".to_string(),
-        //     "message-id-121314".to_string(),
-        // );
-
-        // let row_id = db.insert_file(file_record).unwrap();
-        let row_id = 181;
-        let rows_deleted = db.del_record_by_id(row_id).unwrap();
-        // assert_eq!(rows_deleted, 1);
-        println!("Deleted record with ID: {}", rows_deleted);
+        let (dir, db) = temp_db("test_del_by_id.db");
+
+        let row_id = db.insert_file(sample_record("uuid-121314"), &[]).unwrap();
+        let remaining = db.del_record_by_id(row_id).unwrap();
+        assert_eq!(remaining, Some(0));
+        assert_eq!(db.del_record_by_id(row_id).unwrap(), None);
+        println!("Deleted record with ID: {}", row_id);
 
         // Clean up test database file
-        // std::fs::remove_file("test_del_by_id.db").unwrap();
+        std::fs::remove_dir_all(dir).unwrap();
     }
 }
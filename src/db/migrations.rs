@@ -0,0 +1,564 @@
+use log::info;
+use rusqlite::{Connection, OptionalExtension as _, Transaction};
+
+/// A forward schema change, applied once and recorded in `schema_version`.
+///
+/// Databases created before migrations existed already have some of these changes, so every
+/// step must be safe to run against a schema that partly has it.
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    up: fn(&Transaction) -> rusqlite::Result<()>,
+}
+
+/// All migrations, in the order they are applied. Only ever append to this list.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create files",
+        up: create_files,
+    },
+    Migration {
+        version: 2,
+        name: "record storage backend",
+        up: add_backend,
+    },
+    Migration {
+        version: 3,
+        name: "create file_chunks",
+        up: create_file_chunks,
+    },
+    Migration {
+        version: 4,
+        name: "server-relative urls",
+        up: rewrite_urls,
+    },
+    Migration {
+        version: 5,
+        name: "record size and sha256",
+        up: add_size_and_sha256,
+    },
+    Migration {
+        version: 6,
+        name: "record mime type",
+        up: add_mime_type,
+    },
+    Migration {
+        version: 7,
+        name: "index files for listing",
+        up: index_files,
+    },
+    Migration {
+        version: 8,
+        name: "create api_keys",
+        up: create_api_keys,
+    },
+    Migration {
+        version: 9,
+        name: "create users and sessions",
+        up: create_users,
+    },
+    Migration {
+        version: 10,
+        name: "per-user limits",
+        up: add_user_limits,
+    },
+    Migration {
+        version: 11,
+        name: "create blobs",
+        up: create_blobs,
+    },
+    Migration {
+        version: 12,
+        name: "create thumbnails",
+        up: create_thumbnails,
+    },
+    Migration {
+        version: 13,
+        name: "create variants",
+        up: create_variants,
+    },
+    Migration {
+        version: 14,
+        name: "record media details",
+        up: add_media_details,
+    },
+    Migration {
+        version: 15,
+        name: "record placeholders",
+        up: add_placeholders,
+    },
+    Migration {
+        version: 16,
+        name: "record perceptual hashes",
+        up: add_dhash,
+    },
+    Migration {
+        version: 17,
+        name: "create watermarks",
+        up: create_watermarks,
+    },
+    Migration {
+        version: 18,
+        name: "let api_keys skip watermarks",
+        up: add_skip_watermark,
+    },
+    Migration {
+        version: 19,
+        name: "key thumbnails by blob",
+        up: key_thumbnails_by_blob,
+    },
+];
+
+/// A migration and when it was applied, if it has been.
+pub struct MigrationStatus {
+    pub version: u32,
+    pub name: &'static str,
+    pub applied_at: Option<String>,
+}
+
+fn ensure_version_table(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Highest applied version, or 0 for a database that has never been migrated.
+pub fn current_version(conn: &Connection) -> rusqlite::Result<u32> {
+    ensure_version_table(conn)?;
+    conn.query_row(
+        "SELECT COALESCE(MAX(version), 0) FROM schema_version",
+        [],
+        |row| row.get(0),
+    )
+}
+
+pub fn status(conn: &Connection) -> rusqlite::Result<Vec<MigrationStatus>> {
+    ensure_version_table(conn)?;
+    let mut stmt = conn.prepare("SELECT applied_at FROM schema_version WHERE version = ?1")?;
+    MIGRATIONS
+        .iter()
+        .map(|migration| {
+            Ok(MigrationStatus {
+                version: migration.version,
+                name: migration.name,
+                applied_at: stmt
+                    .query_row([migration.version], |row| row.get(0))
+                    .optional()?,
+            })
+        })
+        .collect()
+}
+
+/// Apply every pending migration, each in its own transaction, returning the versions applied.
+pub fn migrate(
+    conn: &mut Connection,
+) -> Result<Vec<u32>, Box<dyn std::error::Error + Send + Sync>> {
+    let current = current_version(conn)?;
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if current > latest {
+        return Err(format!(
+            "Database schema version {} is newer than this build supports ({})",
+            current, latest
+        )
+        .into());
+    }
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        info!(
+            "Applying migration {}: {}",
+            migration.version, migration.name
+        );
+        let tx = conn.transaction()?;
+        (migration.up)(&tx)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, name) VALUES (?1, ?2)",
+            rusqlite::params![migration.version, migration.name],
+        )?;
+        tx.commit()?;
+        applied.push(migration.version);
+    }
+    Ok(applied)
+}
+
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>("name"))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+    if !exists {
+        info!("Adding column {}.{}", table, column);
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn create_files(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS files (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            filename TEXT NOT NULL,
+            file_id TEXT,
+            message_id TEXT,
+            url TEXT NOT NULL,
+            year INTEGER,
+            month INTEGER,
+            day INTEGER,
+            uuid TEXT NOT NULL,
+            upload_time TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_backend(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "files", "backend", "TEXT NOT NULL DEFAULT 'telegram'")
+}
+
+fn create_file_chunks(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS file_chunks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            file_row_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+            chunk_index INTEGER NOT NULL,
+            file_id TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            UNIQUE (file_row_id, chunk_index)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Older releases stored Telegram download URLs, which embed the bot token and expire.
+/// Public URLs are server-relative; the Telegram `file_id` is all that is needed to
+/// resolve a fresh download link internally.
+fn rewrite_urls(tx: &Transaction) -> rusqlite::Result<()> {
+    let rewritten = tx.execute(
+        "UPDATE files SET url = '/find/' || year || '/' || month || '/' || day || '/' || uuid
+         WHERE url NOT LIKE '/find/%'",
+        [],
+    )?;
+    if rewritten > 0 {
+        info!("Rewrote {} stored URLs to server-relative paths", rewritten);
+    }
+    Ok(())
+}
+
+fn add_size_and_sha256(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "files", "size", "INTEGER")?;
+    add_column_if_missing(tx, "files", "sha256", "TEXT")
+}
+
+fn add_mime_type(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "files", "mime_type", "TEXT")
+}
+
+/// Indexes backing the `/files` filters and sort orders, and the `/find` lookup.
+fn index_files(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE INDEX IF NOT EXISTS files_upload_time ON files (upload_time);
+         CREATE INDEX IF NOT EXISTS files_filename ON files (filename);
+         CREATE INDEX IF NOT EXISTS files_size ON files (size);
+         CREATE INDEX IF NOT EXISTS files_mime_type ON files (mime_type);
+         CREATE INDEX IF NOT EXISTS files_path ON files (year, month, day, uuid);",
+    )
+}
+
+fn create_api_keys(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS api_keys (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            name TEXT NOT NULL,
+            prefix TEXT NOT NULL,
+            key_hash TEXT NOT NULL UNIQUE,
+            scopes TEXT NOT NULL,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            last_used_at TIMESTAMP,
+            revoked_at TIMESTAMP
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Accounts, their login sessions, and the owner of each file and API key. Files and keys from
+/// before accounts existed have no owner.
+fn create_users(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE IF NOT EXISTS users (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            username TEXT NOT NULL UNIQUE COLLATE NOCASE,
+            password_hash TEXT NOT NULL,
+            is_admin INTEGER NOT NULL DEFAULT 0,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+        CREATE TABLE IF NOT EXISTS sessions (
+            token_hash TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            expires_at TIMESTAMP NOT NULL
+        );",
+    )?;
+    add_column_if_missing(tx, "files", "owner_id", "INTEGER REFERENCES users(id)")?;
+    add_column_if_missing(tx, "api_keys", "user_id", "INTEGER REFERENCES users(id)")?;
+    tx.execute(
+        "CREATE INDEX IF NOT EXISTS files_owner ON files (owner_id)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Overrides of the configured upload limits, and an index for counting an owner's uploads
+/// per day.
+fn add_user_limits(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "users", "max_upload_size", "INTEGER")?;
+    add_column_if_missing(tx, "users", "max_total_bytes", "INTEGER")?;
+    add_column_if_missing(tx, "users", "max_files_per_day", "INTEGER")?;
+    tx.execute_batch(
+        "CREATE INDEX IF NOT EXISTS files_owner_upload_time ON files (owner_id, upload_time);
+         DROP INDEX IF EXISTS files_owner;",
+    )
+}
+
+/// One row per stored object, counting the files that share it. Existing files each get their
+/// own blob, numbered like the file, since nothing was shared before.
+fn create_blobs(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS blobs (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            backend TEXT NOT NULL,
+            sha256 TEXT,
+            size INTEGER,
+            ref_count INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    add_column_if_missing(tx, "files", "blob_id", "INTEGER REFERENCES blobs(id)")?;
+    tx.execute_batch(
+        "INSERT INTO blobs (id, backend, sha256, size, ref_count)
+             SELECT id, backend, sha256, size, 1 FROM files WHERE blob_id IS NULL;
+         UPDATE files SET blob_id = id WHERE blob_id IS NULL;
+         CREATE INDEX IF NOT EXISTS blobs_content ON blobs (backend, sha256, size);
+         CREATE INDEX IF NOT EXISTS files_blob ON files (blob_id);",
+    )
+}
+
+fn create_thumbnails(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS thumbnails (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            file_row_id INTEGER NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+            size INTEGER NOT NULL,
+            width INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            mime_type TEXT NOT NULL,
+            byte_size INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            file_id TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (file_row_id, size)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_variants(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS variants (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            blob_id INTEGER NOT NULL REFERENCES blobs(id) ON DELETE CASCADE,
+            format TEXT NOT NULL,
+            size INTEGER,
+            sha256 TEXT,
+            file_id TEXT,
+            message_id TEXT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (blob_id, format)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// What uploads hold, as read by `probe`. Unknown for files from before.
+fn add_media_details(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "files", "width", "INTEGER")?;
+    add_column_if_missing(tx, "files", "height", "INTEGER")?;
+    add_column_if_missing(tx, "files", "duration", "REAL")?;
+    add_column_if_missing(tx, "files", "frame_count", "INTEGER")?;
+    add_column_if_missing(tx, "files", "dominant_color", "TEXT")?;
+    add_column_if_missing(tx, "files", "captured_at", "TEXT")
+}
+
+/// BlurHash and inlined preview of images; `backfill` fills them in for older files.
+fn add_placeholders(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "files", "blurhash", "TEXT")?;
+    add_column_if_missing(tx, "files", "lqip", "TEXT")
+}
+
+/// Perceptual hashes of images, to find near-duplicates. The index covers the scan of every
+/// hash an owner can see.
+fn add_dhash(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(tx, "files", "dhash", "TEXT")?;
+    tx.execute_batch(
+        "CREATE INDEX IF NOT EXISTS files_dhash ON files (owner_id, dhash)
+         WHERE dhash IS NOT NULL;",
+    )
+}
+
+/// Watermarked copies of stored images, one per watermark they were made with.
+fn create_watermarks(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "CREATE TABLE IF NOT EXISTS watermarks (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            blob_id INTEGER NOT NULL REFERENCES blobs(id) ON DELETE CASCADE,
+            fingerprint TEXT NOT NULL,
+            size INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            file_id TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (blob_id, fingerprint)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+fn add_skip_watermark(tx: &Transaction) -> rusqlite::Result<()> {
+    add_column_if_missing(
+        tx,
+        "api_keys",
+        "skip_watermark",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+}
+
+/// Thumbnails belong to the stored object, like variants, so every record of the same content
+/// finds them however late they were made. The copies made for duplicate records collapse into
+/// one, as they point at the same stored thumbnails.
+fn key_thumbnails_by_blob(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        "CREATE TABLE blob_thumbnails (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            blob_id INTEGER NOT NULL REFERENCES blobs(id) ON DELETE CASCADE,
+            size INTEGER NOT NULL,
+            width INTEGER NOT NULL,
+            height INTEGER NOT NULL,
+            mime_type TEXT NOT NULL,
+            byte_size INTEGER NOT NULL,
+            sha256 TEXT NOT NULL,
+            file_id TEXT NOT NULL,
+            message_id TEXT NOT NULL,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            UNIQUE (blob_id, size)
+         );
+         INSERT OR IGNORE INTO blob_thumbnails (blob_id, size, width, height, mime_type,
+                                                byte_size, sha256, file_id, message_id,
+                                                created_at)
+             SELECT f.blob_id, t.size, t.width, t.height, t.mime_type, t.byte_size, t.sha256,
+                    t.file_id, t.message_id, t.created_at
+             FROM thumbnails t JOIN files f ON f.id = t.file_row_id
+             WHERE f.blob_id IS NOT NULL
+             ORDER BY t.id;
+         DROP TABLE thumbnails;
+         ALTER TABLE blob_thumbnails RENAME TO thumbnails;",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn columns(conn: &Connection, table: &str) -> Vec<String> {
+        let mut stmt = conn
+            .prepare(&format!("PRAGMA table_info({})", table))
+            .unwrap();
+        stmt.query_map([], |row| row.get("name"))
+            .unwrap()
+            .map(|name| name.unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_versions_are_ordered() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version as usize, i + 1, "{}", migration.name);
+        }
+    }
+
+    #[test]
+    fn test_migrate_fresh_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        assert_eq!(current_version(&conn).unwrap(), 0);
+        assert!(
+            status(&conn)
+                .unwrap()
+                .iter()
+                .all(|m| m.applied_at.is_none())
+        );
+
+        let applied = migrate(&mut conn).unwrap();
+        assert_eq!(applied, (1..=MIGRATIONS.len() as u32).collect::<Vec<_>>());
+        assert_eq!(current_version(&conn).unwrap(), MIGRATIONS.len() as u32);
+        assert!(
+            status(&conn)
+                .unwrap()
+                .iter()
+                .all(|m| m.applied_at.is_some())
+        );
+        for column in [
+            "backend",
+            "size",
+            "sha256",
+            "mime_type",
+            "owner_id",
+            "width",
+            "captured_at",
+            "blurhash",
+            "dhash",
+        ] {
+            assert!(columns(&conn, "files").contains(&column.to_string()));
+        }
+        assert!(columns(&conn, "thumbnails").contains(&"blob_id".to_string()));
+        assert!(columns(&conn, "variants").contains(&"blob_id".to_string()));
+        assert!(columns(&conn, "watermarks").contains(&"fingerprint".to_string()));
+        assert!(columns(&conn, "api_keys").contains(&"skip_watermark".to_string()));
+
+        // Nothing left to do the second time.
+        assert!(migrate(&mut conn).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migrate_rejects_newer_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        conn.execute(
+            "INSERT INTO schema_version (version, name) VALUES (999, 'future')",
+            [],
+        )
+        .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+}
@@ -1,4 +1,6 @@
+#[allow(clippy::module_inception)]
 pub mod db;
+pub mod migrations;
 pub mod models;
 
 pub use models::*;
@@ -1,4 +1,8 @@
-use dotenv::dotenv;
+use crate::auth::{Scope, parse_scopes};
+use crate::imaging::Format;
+use crate::probe::MediaInfo;
+use crate::quota::Limits;
+use crate::storage::ObjectRef;
 use rusqlite::{Result as SqliteResult, Row};
 use serde::{Deserialize, Serialize};
 
@@ -15,10 +19,27 @@ pub struct FileRecord {
     pub day: u32,
     pub uuid: String,
     pub upload_time: Option<String>,
+    /// Name of the storage backend holding the file's bytes
+    pub backend: String,
+    /// Size of the stored content in bytes; unknown for files uploaded before it was recorded
+    pub size: Option<u64>,
+    /// Hex SHA-256 of the stored content; unknown for files uploaded before it was recorded
+    pub sha256: Option<String>,
+    /// MIME type detected from the content at upload; unknown for older files
+    pub mime_type: Option<String>,
+    /// User who uploaded the file; none for files from before accounts or keys without a user
+    pub owner_id: Option<i64>,
+    /// Dimensions, duration and the like, read at upload; unknown for older files
+    #[serde(flatten)]
+    pub media: MediaInfo,
+    /// Stored object holding the content, shared by every record with the same content
+    #[serde(skip)]
+    pub blob_id: Option<i64>,
 }
 
 impl FileRecord {
     /// Create a new FileRecord instance
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         filename: String,
         url: String,
@@ -40,6 +61,27 @@ impl FileRecord {
             day,
             uuid,
             upload_time: None,
+            backend: "telegram".to_string(),
+            size: None,
+            sha256: None,
+            mime_type: None,
+            owner_id: None,
+            media: MediaInfo::default(),
+            blob_id: None,
+        }
+    }
+
+    /// Server-relative URL clients use to download a file
+    pub fn public_url(year: u32, month: u32, day: u32, uuid: &str) -> String {
+        format!("/find/{}/{}/{}/{}", year, month, day, uuid)
+    }
+
+    /// Reference to the stored object backing this record, without any chunk list
+    pub fn object_ref(&self) -> ObjectRef {
+        ObjectRef {
+            key: self.file_id.clone(),
+            handle: self.message_id.clone(),
+            parts: Vec::new(),
         }
     }
 
@@ -56,6 +98,294 @@ impl FileRecord {
             day: row.get("day")?,
             uuid: row.get("uuid")?,
             upload_time: row.get("upload_time")?,
+            backend: row.get("backend")?,
+            size: row.get("size")?,
+            sha256: row.get("sha256")?,
+            mime_type: row.get("mime_type")?,
+            owner_id: row.get("owner_id")?,
+            media: MediaInfo {
+                width: row.get("width")?,
+                height: row.get("height")?,
+                duration: row.get("duration")?,
+                frame_count: row.get("frame_count")?,
+                dominant_color: row.get("dominant_color")?,
+                captured_at: row.get("captured_at")?,
+                blurhash: row.get("blurhash")?,
+                lqip: row.get("lqip")?,
+                dhash: row.get("dhash")?,
+            },
+            blob_id: row.get("blob_id")?,
+        })
+    }
+}
+
+/// Filters, ordering and page of a file listing, as given in the `/files` query string
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FileQuery {
+    /// Files uploaded on or after this day (UTC)
+    pub from: Option<chrono::NaiveDate>,
+    /// Files uploaded on or before this day (UTC)
+    pub to: Option<chrono::NaiveDate>,
+    /// Case-insensitive substring of the filename
+    pub filename: Option<String>,
+    /// Exact MIME type, or a whole family such as `image/*`
+    pub mime_type: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub sort: SortField,
+    pub order: SortOrder,
+    pub limit: u32,
+    pub offset: u64,
+    /// Only files owned by this user, or unowned files for `Some(None)`; every file when
+    /// `None`. Set from who is asking, never from the query string.
+    #[serde(skip)]
+    pub owned_by: Option<Option<i64>>,
+}
+
+impl FileQuery {
+    pub const DEFAULT_LIMIT: u32 = 50;
+    pub const MAX_LIMIT: u32 = 500;
+}
+
+impl Default for FileQuery {
+    fn default() -> Self {
+        FileQuery {
+            from: None,
+            to: None,
+            filename: None,
+            mime_type: None,
+            min_size: None,
+            max_size: None,
+            sort: SortField::UploadTime,
+            order: SortOrder::Desc,
+            limit: Self::DEFAULT_LIMIT,
+            offset: 0,
+            owned_by: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortField {
+    UploadTime,
+    Filename,
+    Size,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// One page of a file listing
+#[derive(Debug, Serialize)]
+pub struct FilePage {
+    /// Number of files matching the filters, across all pages
+    pub total: u64,
+    pub limit: u32,
+    pub offset: u64,
+    pub files: Vec<FileRecord>,
+}
+
+/// A file that looks like another, with how many bits their perceptual hashes differ in
+#[derive(Debug, Serialize)]
+pub struct SimilarFile {
+    #[serde(flatten)]
+    pub file: FileRecord,
+    pub distance: u32,
+}
+
+/// An API key, without the key itself; only its hash is stored
+#[derive(Debug, Clone)]
+pub struct ApiKey {
+    pub id: i64,
+    pub name: String,
+    /// First characters of the key, enough to tell keys apart in listings
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    /// User the key acts for; files it uploads belong to them
+    pub user_id: Option<i64>,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+    /// Downloads with this key get images without a watermark
+    pub skip_watermark: bool,
+}
+
+impl ApiKey {
+    /// Convert from SQLite Row to ApiKey
+    pub fn from_row(row: &Row) -> SqliteResult<Self> {
+        let scopes: String = row.get("scopes")?;
+        Ok(Self {
+            id: row.get("id")?,
+            name: row.get("name")?,
+            prefix: row.get("prefix")?,
+            scopes: parse_scopes(&scopes).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+            })?,
+            user_id: row.get("user_id")?,
+            created_at: row.get("created_at")?,
+            last_used_at: row.get("last_used_at")?,
+            revoked_at: row.get("revoked_at")?,
+            skip_watermark: row.get("skip_watermark")?,
+        })
+    }
+}
+
+/// An account that can log in; its password hash is only read when checking a login
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub is_admin: bool,
+    pub created_at: String,
+    /// This user's own limits; unset ones fall back to the config
+    pub limits: Limits,
+}
+
+impl User {
+    /// Convert from SQLite Row to User
+    pub fn from_row(row: &Row) -> SqliteResult<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            username: row.get("username")?,
+            is_admin: row.get("is_admin")?,
+            created_at: row.get("created_at")?,
+            limits: Limits {
+                max_upload_size: row.get("max_upload_size")?,
+                max_total_bytes: row.get("max_total_bytes")?,
+                max_files_per_day: row.get("max_files_per_day")?,
+            },
+        })
+    }
+}
+
+/// A scaled-down copy of an image, stored in the same backend as the original
+#[derive(Debug, Clone, Serialize)]
+pub struct Thumbnail {
+    pub id: Option<i64>,
+    /// Stored object it was made from, shared by every record of the same content
+    pub blob_id: i64,
+    /// Configured size it was made for; the image fits in a square of this many pixels
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub mime_type: String,
+    pub byte_size: u64,
+    pub sha256: String,
+    pub file_id: String,
+    pub message_id: String,
+    pub created_at: Option<String>,
+}
+
+impl Thumbnail {
+    /// Reference to the stored thumbnail; thumbnails are never split into parts
+    pub fn object_ref(&self) -> ObjectRef {
+        ObjectRef {
+            key: self.file_id.clone(),
+            handle: self.message_id.clone(),
+            parts: Vec::new(),
+        }
+    }
+
+    /// Convert from SQLite Row to Thumbnail
+    pub fn from_row(row: &Row) -> SqliteResult<Self> {
+        Ok(Self {
+            id: Some(row.get("id")?),
+            blob_id: row.get("blob_id")?,
+            size: row.get("size")?,
+            width: row.get("width")?,
+            height: row.get("height")?,
+            mime_type: row.get("mime_type")?,
+            byte_size: row.get("byte_size")?,
+            sha256: row.get("sha256")?,
+            file_id: row.get("file_id")?,
+            message_id: row.get("message_id")?,
+            created_at: row.get("created_at")?,
+        })
+    }
+}
+
+/// A stored object's content converted to another format, for clients that accept it
+#[derive(Debug, Clone, Serialize)]
+pub struct Variant {
+    pub id: Option<i64>,
+    pub blob_id: i64,
+    pub format: Format,
+    /// Where the converted copy is stored, with its size and hash. `None` when converting
+    /// didn't make the file smaller, so the original is served instead.
+    pub stored: Option<StoredVariant>,
+    pub created_at: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredVariant {
+    pub size: u64,
+    pub sha256: String,
+    pub file_id: String,
+    pub message_id: String,
+}
+
+impl StoredVariant {
+    pub fn object_ref(&self) -> ObjectRef {
+        ObjectRef {
+            key: self.file_id.clone(),
+            handle: self.message_id.clone(),
+            parts: Vec::new(),
+        }
+    }
+}
+
+/// A watermarked copy of a stored image, served to everyone but its owner
+#[derive(Debug, Clone)]
+pub struct Watermarked {
+    pub blob_id: i64,
+    /// [`crate::imaging::watermark::Watermark::fingerprint`] of the watermark it carries
+    pub fingerprint: String,
+    pub stored: StoredVariant,
+}
+
+impl Watermarked {
+    pub fn from_row(row: &Row) -> SqliteResult<Self> {
+        Ok(Self {
+            blob_id: row.get("blob_id")?,
+            fingerprint: row.get("fingerprint")?,
+            stored: StoredVariant {
+                size: row.get("size")?,
+                sha256: row.get("sha256")?,
+                file_id: row.get("file_id")?,
+                message_id: row.get("message_id")?,
+            },
+        })
+    }
+}
+
+impl Variant {
+    /// Convert from SQLite Row to Variant
+    pub fn from_row(row: &Row) -> SqliteResult<Self> {
+        let format: String = row.get("format")?;
+        let file_id: Option<String> = row.get("file_id")?;
+        let stored = match file_id {
+            Some(file_id) => Some(StoredVariant {
+                size: row.get("size")?,
+                sha256: row.get("sha256")?,
+                file_id,
+                message_id: row.get("message_id")?,
+            }),
+            None => None,
+        };
+        Ok(Self {
+            id: Some(row.get("id")?),
+            blob_id: row.get("blob_id")?,
+            format: format.parse().map_err(|e: String| {
+                rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, e.into())
+            })?,
+            stored,
+            created_at: row.get("created_at")?,
         })
     }
 }
@@ -0,0 +1,284 @@
+use crate::db::{FileRecord, Thumbnail};
+use crate::imaging::Format;
+use actix_web::HttpMessage as _;
+use actix_web::HttpRequest;
+use actix_web::http::header::{
+    Accept, EntityTag, HttpDate, IfModifiedSince, IfNoneMatch, IfRange, Quality, Range,
+};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Which part of a file a download should send.
+#[derive(Debug, PartialEq)]
+pub enum ByteRange {
+    /// The whole file, with status 200.
+    Full,
+    /// `len` bytes starting at `start`, with status 206.
+    Partial { start: u64, len: u64 },
+    /// The requested range lies outside the file: 416.
+    Unsatisfiable,
+}
+
+/// Cache validators of a stored file.
+pub struct Validators {
+    pub etag: Option<EntityTag>,
+    pub last_modified: Option<HttpDate>,
+}
+
+impl Validators {
+    pub fn for_record(record: &FileRecord) -> Self {
+        Validators {
+            // The content hash identifies the exact bytes, so it makes a strong ETag.
+            etag: record.sha256.clone().map(EntityTag::new_strong),
+            last_modified: record.upload_time.as_deref().and_then(parse_sqlite_time),
+        }
+    }
+
+    pub fn for_thumbnail(thumbnail: &Thumbnail) -> Self {
+        Validators {
+            etag: Some(EntityTag::new_strong(thumbnail.sha256.clone())),
+            last_modified: thumbnail.created_at.as_deref().and_then(parse_sqlite_time),
+        }
+    }
+
+    /// Whether the client's cached copy is current and a 304 can be sent instead.
+    pub fn not_modified(&self, req: &HttpRequest) -> bool {
+        // If-None-Match takes precedence; If-Modified-Since is only a fallback.
+        if let Some(if_none_match) = req.get_header::<IfNoneMatch>() {
+            return match (if_none_match, &self.etag) {
+                (IfNoneMatch::Any, _) => true,
+                (IfNoneMatch::Items(tags), Some(etag)) => tags.iter().any(|t| t.weak_eq(etag)),
+                (IfNoneMatch::Items(_), None) => false,
+            };
+        }
+        match (req.get_header::<IfModifiedSince>(), self.last_modified) {
+            (Some(IfModifiedSince(since)), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// The range to serve for a file of `size` bytes.
+    ///
+    /// Only single ranges are honoured; a multi-range request gets the whole file,
+    /// as does a range conditioned on a stale `If-Range`.
+    pub fn range(&self, req: &HttpRequest, size: u64) -> ByteRange {
+        let Some(Range::Bytes(specs)) = req.get_header::<Range>() else {
+            return ByteRange::Full;
+        };
+        if specs.len() != 1 || !self.if_range_matches(req) {
+            return ByteRange::Full;
+        }
+        match specs[0].to_satisfiable_range(size) {
+            Some((start, end)) => ByteRange::Partial {
+                start,
+                len: end - start + 1,
+            },
+            None => ByteRange::Unsatisfiable,
+        }
+    }
+
+    fn if_range_matches(&self, req: &HttpRequest) -> bool {
+        match req.get_header::<IfRange>() {
+            None => true,
+            Some(IfRange::EntityTag(tag)) => {
+                self.etag.as_ref().is_some_and(|etag| tag.strong_eq(etag))
+            }
+            Some(IfRange::Date(date)) => self.last_modified == Some(date),
+        }
+    }
+}
+
+/// Which of `formats` a client takes, by its `Accept` header: those it names with a `q` above
+/// 0, highest rated first and in the order of `formats` among equals. Only types the client
+/// names count; `*/*` and `image/*` don't promise it can decode AVIF or WebP.
+pub fn acceptable(req: &HttpRequest, formats: &[Format]) -> Vec<Format> {
+    let Some(Accept(items)) = req.get_header::<Accept>() else {
+        return Vec::new();
+    };
+    let rating = |format: Format| {
+        items
+            .iter()
+            .filter(|item| item.item.essence_str() == format.mime_type())
+            .map(|item| item.quality)
+            .max()
+            .unwrap_or(Quality::ZERO)
+    };
+    let mut rated: Vec<(Format, Quality)> = formats
+        .iter()
+        .map(|&format| (format, rating(format)))
+        .filter(|&(_, quality)| quality > Quality::ZERO)
+        .collect();
+    // Stable, so equals keep their order
+    rated.sort_by_key(|&(_, quality)| std::cmp::Reverse(quality));
+    rated.into_iter().map(|(format, _)| format).collect()
+}
+
+/// Index of the smallest of `sizes`, the earliest among equals, or `None` when the original of
+/// `original_size` bytes is no bigger. An original of unknown size loses to any of them.
+pub fn smallest(original_size: Option<u64>, sizes: &[u64]) -> Option<usize> {
+    let (index, &size) = sizes
+        .iter()
+        .enumerate()
+        .min_by_key(|&(index, &size)| (size, index))?;
+    original_size
+        .is_none_or(|original| size < original)
+        .then_some(index)
+}
+
+/// Parse SQLite's `CURRENT_TIMESTAMP` format (`YYYY-MM-DD HH:MM:SS`, UTC).
+fn parse_sqlite_time(value: &str) -> Option<HttpDate> {
+    let time = chrono::NaiveDateTime::parse_from_str(value, "%Y-%m-%d %H:%M:%S").ok()?;
+    let secs = u64::try_from(time.and_utc().timestamp()).ok()?;
+    Some(HttpDate::from(UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn validators() -> Validators {
+        let mut record = FileRecord::new(
+            "a.png".to_string(),
+            FileRecord::public_url(2024, 1, 2, "u"),
+            2024,
+            1,
+            2,
+            "u".to_string(),
+            "key".to_string(),
+            String::new(),
+        );
+        record.sha256 = Some("abc123".to_string());
+        record.upload_time = Some("2024-01-02 03:04:05".to_string());
+        Validators::for_record(&record)
+    }
+
+    #[test]
+    fn test_validators_for_record() {
+        let v = validators();
+        assert_eq!(v.etag.unwrap().to_string(), "\"abc123\"");
+        assert_eq!(
+            v.last_modified.unwrap().to_string(),
+            "Tue, 02 Jan 2024 03:04:05 GMT"
+        );
+    }
+
+    #[test]
+    fn test_not_modified() {
+        let v = validators();
+        let req = |name: &str, value: &str| {
+            TestRequest::default()
+                .insert_header((name, value))
+                .to_http_request()
+        };
+        assert!(!v.not_modified(&TestRequest::default().to_http_request()));
+        assert!(v.not_modified(&req("If-None-Match", "\"abc123\"")));
+        assert!(v.not_modified(&req("If-None-Match", "W/\"abc123\"")));
+        assert!(v.not_modified(&req("If-None-Match", "\"x\", \"abc123\"")));
+        assert!(v.not_modified(&req("If-None-Match", "*")));
+        assert!(!v.not_modified(&req("If-None-Match", "\"other\"")));
+        assert!(v.not_modified(&req("If-Modified-Since", "Tue, 02 Jan 2024 03:04:05 GMT")));
+        assert!(!v.not_modified(&req("If-Modified-Since", "Mon, 01 Jan 2024 00:00:00 GMT")));
+        // If-None-Match wins over If-Modified-Since.
+        let both = TestRequest::default()
+            .insert_header(("If-None-Match", "\"other\""))
+            .insert_header(("If-Modified-Since", "Wed, 03 Jan 2024 00:00:00 GMT"))
+            .to_http_request();
+        assert!(!v.not_modified(&both));
+    }
+
+    #[test]
+    fn test_range() {
+        let v = validators();
+        let range = |value: &str| {
+            let req = TestRequest::default()
+                .insert_header(("Range", value))
+                .to_http_request();
+            v.range(&req, 100)
+        };
+        assert_eq!(
+            v.range(&TestRequest::default().to_http_request(), 100),
+            ByteRange::Full
+        );
+        assert_eq!(range("bytes=0-9"), ByteRange::Partial { start: 0, len: 10 });
+        assert_eq!(
+            range("bytes=90-"),
+            ByteRange::Partial { start: 90, len: 10 }
+        );
+        assert_eq!(range("bytes=-5"), ByteRange::Partial { start: 95, len: 5 });
+        assert_eq!(
+            range("bytes=50-500"),
+            ByteRange::Partial { start: 50, len: 50 }
+        );
+        assert_eq!(range("bytes=100-"), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-1,5-6"), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_if_range() {
+        let v = validators();
+        let range = |if_range: &str| {
+            let req = TestRequest::default()
+                .insert_header(("Range", "bytes=0-9"))
+                .insert_header(("If-Range", if_range))
+                .to_http_request();
+            v.range(&req, 100)
+        };
+        let partial = ByteRange::Partial { start: 0, len: 10 };
+        assert_eq!(range("\"abc123\""), partial);
+        assert_eq!(range("Tue, 02 Jan 2024 03:04:05 GMT"), partial);
+        assert_eq!(range("\"other\""), ByteRange::Full);
+        assert_eq!(range("W/\"abc123\""), ByteRange::Full);
+        assert_eq!(range("Wed, 03 Jan 2024 00:00:00 GMT"), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_acceptable() {
+        let acceptable = |accept: &str| {
+            let req = TestRequest::default()
+                .insert_header(("Accept", accept))
+                .to_http_request();
+            acceptable(&req, &[Format::Avif, Format::Webp])
+        };
+        // What browsers send for images
+        assert_eq!(
+            acceptable("image/avif,image/webp,image/apng,*/*;q=0.8"),
+            [Format::Avif, Format::Webp]
+        );
+        assert_eq!(acceptable("image/webp,*/*"), [Format::Webp]);
+        assert_eq!(
+            acceptable("image/avif;q=0.5, image/webp"),
+            [Format::Webp, Format::Avif]
+        );
+        assert_eq!(
+            acceptable("image/avif;q=0, image/webp;q=0.1"),
+            [Format::Webp]
+        );
+        assert_eq!(acceptable("*/*"), []);
+        assert_eq!(acceptable("image/*"), []);
+        let no_accept = TestRequest::default().to_http_request();
+        assert_eq!(super::acceptable(&no_accept, &[Format::Webp]), []);
+    }
+
+    #[test]
+    fn test_smallest() {
+        // A browser takes AVIF and WebP, and the WebP copy came out smaller
+        let req = TestRequest::default()
+            .insert_header(("Accept", "image/avif,image/webp,*/*"))
+            .to_http_request();
+        let formats = acceptable(&req, &[Format::Avif, Format::Webp]);
+        let sizes: Vec<u64> = formats
+            .iter()
+            .map(|format| match format {
+                Format::Avif => 900,
+                _ => 600,
+            })
+            .collect();
+        let index = smallest(Some(1000), &sizes).unwrap();
+        assert_eq!(formats[index], Format::Webp);
+
+        assert_eq!(smallest(Some(600), &[900, 600]), None);
+        assert_eq!(smallest(Some(1000), &[600, 600]), Some(0));
+        assert_eq!(smallest(None, &[900, 600]), Some(1));
+        assert_eq!(smallest(Some(1000), &[]), None);
+    }
+}
@@ -0,0 +1,633 @@
+//! Removing EXIF, XMP, IPTC and comments from uploaded images without re-encoding them.
+//!
+//! The pixels are copied untouched. An EXIF orientation other than upright is kept, in an EXIF
+//! block holding nothing else, so the image still displays the right way up.
+
+use image::metadata::Orientation;
+use std::io;
+
+/// Types metadata can be removed from, by detected MIME type.
+const STRIPPABLE_TYPES: &[&str] = &[
+    "image/jpeg",
+    "image/png",
+    "image/webp",
+    "image/heif",
+    "image/avif",
+];
+
+pub fn is_strippable(mime_type: &str) -> bool {
+    STRIPPABLE_TYPES.contains(&mime_type)
+}
+
+/// `data` without its metadata. Fails on files too damaged to be taken apart, so nothing is
+/// stored with its metadata by mistake.
+pub fn strip(mime_type: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+    match mime_type {
+        "image/jpeg" => strip_jpeg(data),
+        "image/png" => strip_png(data),
+        "image/webp" => strip_webp(data),
+        "image/heif" | "image/avif" => blank_heif(data),
+        _ => Ok(data.to_vec()),
+    }
+}
+
+fn malformed(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("malformed {}", what))
+}
+
+/// A TIFF structure (the body of an EXIF block) holding only `orientation`.
+fn orientation_tiff(orientation: Orientation) -> Vec<u8> {
+    let mut tiff = b"MM\0\x2a\0\0\0\x08".to_vec();
+    tiff.extend_from_slice(&1u16.to_be_bytes());
+    // Tag 0x0112, type SHORT, one value, padded to four bytes
+    tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, orientation.to_exif(), 0, 0]);
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+    tiff
+}
+
+/// The orientation to keep from an EXIF block, if it isn't upright.
+fn kept_orientation(tiff: &[u8]) -> Option<Orientation> {
+    Orientation::from_exif_chunk(tiff).filter(|o| *o != Orientation::NoTransforms)
+}
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Drop every APPn segment but JFIF, ICC profiles and Adobe's colour transform, all comments,
+/// and anything after the end of the image, such as the extra images of MPF files.
+fn strip_jpeg(data: &[u8]) -> io::Result<Vec<u8>> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(malformed("JPEG"));
+    }
+    let mut kept: Vec<&[u8]> = Vec::new();
+    let mut orientation = None;
+    let mut pos = 2;
+    loop {
+        if data.len() < pos + 2 || data[pos] != 0xFF {
+            return Err(malformed("JPEG"));
+        }
+        let marker = data[pos + 1];
+        match marker {
+            // Fill byte
+            0xFF => {
+                pos += 1;
+                continue;
+            }
+            0xD9 => {
+                kept.push(&data[pos..pos + 2]);
+                break;
+            }
+            0x01 | 0xD0..=0xD7 => {
+                kept.push(&data[pos..pos + 2]);
+                pos += 2;
+                continue;
+            }
+            _ => {}
+        }
+        let len = usize::from(u16::from_be_bytes([
+            *data.get(pos + 2).ok_or_else(|| malformed("JPEG"))?,
+            *data.get(pos + 3).ok_or_else(|| malformed("JPEG"))?,
+        ]));
+        let end = pos + 2 + len;
+        if len < 2 || end > data.len() {
+            return Err(malformed("JPEG"));
+        }
+        let body = &data[pos + 4..end];
+        let keep = match marker {
+            0xE0 => body.starts_with(b"JFIF\0") || body.starts_with(b"JFXX\0"),
+            0xE1 => {
+                if let Some(tiff) = body.strip_prefix(EXIF_HEADER) {
+                    orientation = orientation.or_else(|| kept_orientation(tiff));
+                }
+                false
+            }
+            0xE2 => body.starts_with(b"ICC_PROFILE\0"),
+            0xEE => body.starts_with(b"Adobe"),
+            0xE3..=0xED | 0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            kept.push(&data[pos..end]);
+        }
+        pos = end;
+        if marker == 0xDA {
+            // Entropy-coded data runs to the next marker that isn't a stuffed byte or restart
+            let is_marker =
+                |at: usize| data[at] == 0xFF && !matches!(data[at + 1], 0x00 | 0xD0..=0xD7 | 0xFF);
+            let mut scan = pos;
+            while scan + 1 < data.len() && !is_marker(scan) {
+                scan += 1;
+            }
+            if scan + 1 >= data.len() {
+                return Err(malformed("JPEG"));
+            }
+            kept.push(&data[pos..scan]);
+            pos = scan;
+        }
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&[0xFF, 0xD8]);
+    let mut segments = kept.into_iter().peekable();
+    // The EXIF block goes after JFIF's, which must come first
+    if let Some(jfif) = segments.next_if(|s| s.starts_with(&[0xFF, 0xE0])) {
+        out.extend_from_slice(jfif);
+    }
+    if let Some(orientation) = orientation {
+        let tiff = orientation_tiff(orientation);
+        let len = (2 + EXIF_HEADER.len() + tiff.len()) as u16;
+        out.extend_from_slice(&[0xFF, 0xE1]);
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(EXIF_HEADER);
+        out.extend_from_slice(&tiff);
+    }
+    for segment in segments {
+        out.extend_from_slice(segment);
+    }
+    Ok(out)
+}
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Drop text, timestamp and EXIF chunks, and anything after the end of the image.
+fn strip_png(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut rest = data
+        .strip_prefix(PNG_SIGNATURE)
+        .ok_or_else(|| malformed("PNG"))?;
+    let mut out = PNG_SIGNATURE.to_vec();
+    let mut orientation = None;
+    loop {
+        if rest.len() < 12 {
+            return Err(malformed("PNG"));
+        }
+        let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let chunk = rest.get(..12 + len).ok_or_else(|| malformed("PNG"))?;
+        let kind = &chunk[4..8];
+        match kind {
+            b"eXIf" => orientation = orientation.or_else(|| kept_orientation(&chunk[8..8 + len])),
+            b"tEXt" | b"zTXt" | b"iTXt" | b"tIME" => {}
+            b"IDAT" => {
+                // eXIf must come before the image data
+                if let Some(orientation) = orientation.take() {
+                    write_png_chunk(&mut out, b"eXIf", &orientation_tiff(orientation));
+                }
+                out.extend_from_slice(chunk);
+            }
+            _ => out.extend_from_slice(chunk),
+        }
+        rest = &rest[12 + len..];
+        if kind == b"IEND" {
+            return Ok(out);
+        }
+    }
+}
+
+fn write_png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(kind);
+    crc.update(body);
+    out.extend_from_slice(&crc.finalize().to_be_bytes());
+}
+
+/// VP8X flags saying EXIF and XMP chunks are present.
+const WEBP_EXIF_FLAG: u8 = 0x08;
+const WEBP_XMP_FLAG: u8 = 0x04;
+
+/// Drop the EXIF and XMP chunks and clear their flags.
+fn strip_webp(data: &[u8]) -> io::Result<Vec<u8>> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err(malformed("WebP"));
+    }
+    let riff_len = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+    let mut rest = data
+        .get(12..8 + riff_len)
+        .ok_or_else(|| malformed("WebP"))?;
+    let mut chunks: Vec<&[u8]> = Vec::new();
+    let mut orientation = None;
+    while !rest.is_empty() {
+        if rest.len() < 8 {
+            return Err(malformed("WebP"));
+        }
+        let len = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        // Chunks are padded to an even length
+        let padded = (8 + len + 1) & !1;
+        let chunk = rest
+            .get(..padded.min(rest.len()))
+            .ok_or_else(|| malformed("WebP"))?;
+        if chunk.len() < 8 + len {
+            return Err(malformed("WebP"));
+        }
+        match &chunk[..4] {
+            b"EXIF" => {
+                let body = &chunk[8..8 + len];
+                let tiff = body.strip_prefix(EXIF_HEADER).unwrap_or(body);
+                orientation = orientation.or_else(|| kept_orientation(tiff));
+            }
+            b"XMP " => {}
+            _ => chunks.push(chunk),
+        }
+        rest = &rest[chunk.len()..];
+    }
+
+    let mut body = b"WEBP".to_vec();
+    for chunk in chunks {
+        let start = body.len();
+        body.extend_from_slice(chunk);
+        if &chunk[..4] == b"VP8X" && chunk.len() > 8 {
+            body[start + 8] &= !(WEBP_EXIF_FLAG | WEBP_XMP_FLAG);
+            if orientation.is_some() {
+                body[start + 8] |= WEBP_EXIF_FLAG;
+            }
+        }
+    }
+    // EXIF goes after the image data; only the extended format can carry it
+    if let Some(orientation) = orientation
+        && body[4..8] == *b"VP8X"
+    {
+        let tiff = orientation_tiff(orientation);
+        body.extend_from_slice(b"EXIF");
+        body.extend_from_slice(&(tiff.len() as u32).to_le_bytes());
+        body.extend_from_slice(&tiff);
+    }
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Ok(out)
+}
+
+/// An ISO base media file box: its type, and where its body lies in the file.
+pub struct IsoBox {
+    pub kind: [u8; 4],
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The boxes in `data[start..end]`, as in HEIF images and MP4 video.
+pub fn iso_boxes(data: &[u8], start: usize, end: usize) -> io::Result<Vec<IsoBox>> {
+    let mut boxes = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        let header = data
+            .get(pos..pos + 8)
+            .ok_or_else(|| malformed("ISO media file"))?;
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let (body, box_end) = match size {
+            0 => (pos + 8, end),
+            1 => {
+                let large = data
+                    .get(pos + 8..pos + 16)
+                    .ok_or_else(|| malformed("ISO media file"))?;
+                let size = u64::from_be_bytes(large.try_into().unwrap());
+                (pos + 16, pos.saturating_add(size as usize))
+            }
+            _ => (pos + 8, pos + size),
+        };
+        if box_end > end || box_end < body {
+            return Err(malformed("ISO media file"));
+        }
+        boxes.push(IsoBox {
+            kind,
+            start: body,
+            end: box_end,
+        });
+        pos = box_end;
+    }
+    Ok(boxes)
+}
+
+/// Reads big-endian fields of a box body, failing past its end.
+struct Fields<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Fields<'_> {
+    fn uint(&mut self, bytes: usize) -> io::Result<u64> {
+        let field = self
+            .data
+            .get(self.pos..self.pos + bytes)
+            .ok_or_else(|| malformed("HEIF"))?;
+        self.pos += bytes;
+        Ok(field.iter().fold(0, |value, b| value << 8 | u64::from(*b)))
+    }
+
+    fn string(&mut self) -> io::Result<&[u8]> {
+        let rest = self.data.get(self.pos..).ok_or_else(|| malformed("HEIF"))?;
+        let len = rest.iter().position(|b| *b == 0).unwrap_or(rest.len());
+        self.pos += len + 1;
+        Ok(&rest[..len])
+    }
+}
+
+/// Overwrite the EXIF and XMP items of a HEIF (HEIC or AVIF) file in place. Every offset in
+/// the file stays valid; EXIF becomes an empty block and XMP blank space. Orientation is kept
+/// by the image's own rotation properties, which EXIF doesn't override.
+fn blank_heif(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut out = data.to_vec();
+    let top = iso_boxes(data, 0, data.len())?;
+    let Some(meta) = top.iter().find(|b| &b.kind == b"meta") else {
+        return Ok(out);
+    };
+    // `meta` is a full box: version and flags come before its children
+    let children = iso_boxes(data, meta.start + 4, meta.end)?;
+
+    let mut exif_items = Vec::new();
+    let mut xmp_items = Vec::new();
+    if let Some(iinf) = children.iter().find(|b| &b.kind == b"iinf") {
+        let version = Fields {
+            data: &data[..iinf.end],
+            pos: iinf.start,
+        }
+        .uint(1)?;
+        let entries_start = iinf.start + 4 + if version == 0 { 2 } else { 4 };
+        for infe in iso_boxes(data, entries_start, iinf.end)? {
+            if &infe.kind != b"infe" {
+                continue;
+            }
+            let mut fields = Fields {
+                data: &data[..infe.end],
+                pos: infe.start,
+            };
+            let version = fields.uint(1)?;
+            if version < 2 {
+                continue;
+            }
+            fields.uint(3)?;
+            let id = fields.uint(if version == 2 { 2 } else { 4 })?;
+            fields.uint(2)?;
+            let item_type = fields.uint(4)?.to_be_bytes();
+            match &item_type[4..] {
+                b"Exif" => exif_items.push(id),
+                b"mime" => {
+                    fields.string()?;
+                    if fields.string()? == b"application/rdf+xml" {
+                        xmp_items.push(id);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    if exif_items.is_empty() && xmp_items.is_empty() {
+        return Ok(out);
+    }
+
+    let iloc = children
+        .iter()
+        .find(|b| &b.kind == b"iloc")
+        .ok_or_else(|| malformed("HEIF"))?;
+    let idat = children.iter().find(|b| &b.kind == b"idat");
+    let mut fields = Fields {
+        data: &data[..iloc.end],
+        pos: iloc.start,
+    };
+    let version = fields.uint(1)?;
+    fields.uint(3)?;
+    let sizes = fields.uint(1)?;
+    let (offset_size, length_size) = ((sizes >> 4) as usize, (sizes & 0xF) as usize);
+    let sizes = fields.uint(1)?;
+    let base_offset_size = (sizes >> 4) as usize;
+    let index_size = if version > 0 {
+        (sizes & 0xF) as usize
+    } else {
+        0
+    };
+    let count = fields.uint(if version < 2 { 2 } else { 4 })?;
+    for _ in 0..count {
+        let id = fields.uint(if version < 2 { 2 } else { 4 })?;
+        let method = if version > 0 {
+            fields.uint(2)? & 0xF
+        } else {
+            0
+        };
+        fields.uint(2)?;
+        let base = fields.uint(base_offset_size)?;
+        let extents = fields.uint(2)?;
+        let blank: Option<&[u8]> = if exif_items.contains(&id) {
+            // An empty TIFF structure, after the four bytes giving its offset
+            Some(b"\0\0\0\0MM\0\x2a\0\0\0\x08\0\0\0\0\0\0")
+        } else if xmp_items.contains(&id) {
+            Some(b"")
+        } else {
+            None
+        };
+        let mut written = 0;
+        for _ in 0..extents {
+            fields.uint(index_size)?;
+            let offset = fields.uint(offset_size)?;
+            let length = fields.uint(length_size)?;
+            let Some(blank) = blank else {
+                continue;
+            };
+            let origin = match (method, idat) {
+                (0, _) => 0,
+                (1, Some(idat)) => idat.start,
+                _ => return Err(malformed("HEIF")),
+            };
+            // A length of zero means the rest of the file, which metadata never is
+            let start = (origin as u64)
+                .checked_add(base)
+                .and_then(|start| start.checked_add(offset));
+            let end = start.and_then(|start| start.checked_add(length));
+            let extent = match (start, end) {
+                (Some(start), Some(end)) if length > 0 && end <= out.len() as u64 => {
+                    &mut out[start as usize..end as usize]
+                }
+                _ => return Err(malformed("HEIF")),
+            };
+            let filler = if xmp_items.contains(&id) { b' ' } else { 0 };
+            for (i, byte) in extent.iter_mut().enumerate() {
+                *byte = blank.get(written + i).copied().unwrap_or(filler);
+            }
+            written += extent.len();
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageDecoder, ImageReader, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    fn orientation_of(data: &[u8]) -> Orientation {
+        let mut decoder = ImageReader::new(Cursor::new(data))
+            .with_guessed_format()
+            .unwrap()
+            .into_decoder()
+            .unwrap();
+        decoder.orientation().unwrap()
+    }
+
+    fn exif_with_gps() -> Vec<u8> {
+        // Orientation 6 and a GPS IFD pointer, which is what must go
+        let mut tiff = b"MM\0\x2a\0\0\0\x08\0\x02".to_vec();
+        tiff.extend_from_slice(&[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0]);
+        tiff.extend_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 0x1A]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(b"GPS 51.5N 0.12W");
+        tiff
+    }
+
+    fn image() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_pixel(8, 4, Rgb([10, 20, 30])))
+    }
+
+    #[test]
+    fn test_strip_jpeg() {
+        let plain = crate::imaging::encode(&image(), crate::imaging::Format::Jpeg, 80).unwrap();
+        let mut exif = vec![0xFF, 0xE1];
+        let body = [EXIF_HEADER, &exif_with_gps()].concat();
+        exif.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+        exif.extend_from_slice(&body);
+        let comment = b"\xFF\xFE\0\x07hello";
+        let tagged = [&plain[..2], &exif, comment, &plain[2..], b"trailer"].concat();
+
+        let stripped = strip("image/jpeg", &tagged).unwrap();
+        assert!(!stripped.windows(3).any(|w| w == b"GPS"));
+        assert!(!stripped.windows(5).any(|w| w == b"hello"));
+        assert!(stripped.ends_with(&[0xFF, 0xD9]));
+        assert_eq!(orientation_of(&stripped), Orientation::Rotate90);
+        let decoded = image::load_from_memory(&stripped).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (8, 4));
+        // Nothing to remove from an upright image without metadata
+        assert_eq!(strip("image/jpeg", &plain).unwrap(), plain);
+        assert!(strip("image/jpeg", &plain[..plain.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn test_strip_png() {
+        let plain = crate::imaging::encode(&image(), crate::imaging::Format::Png, 80).unwrap();
+        let ihdr_end = PNG_SIGNATURE.len() + 25;
+        let mut chunks = Vec::new();
+        write_png_chunk(&mut chunks, b"tEXt", b"Author\0Someone");
+        write_png_chunk(&mut chunks, b"eXIf", &exif_with_gps());
+        let tagged = [&plain[..ihdr_end], &chunks, &plain[ihdr_end..]].concat();
+
+        let stripped = strip("image/png", &tagged).unwrap();
+        assert!(!stripped.windows(3).any(|w| w == b"GPS"));
+        assert!(!stripped.windows(7).any(|w| w == b"Someone"));
+        assert_eq!(orientation_of(&stripped), Orientation::Rotate90);
+        image::load_from_memory(&stripped).unwrap();
+        assert_eq!(strip("image/png", &plain).unwrap(), plain);
+    }
+
+    #[test]
+    fn test_strip_webp() {
+        let plain = crate::imaging::encode(&image(), crate::imaging::Format::Webp, 80).unwrap();
+        // Turn the simple file into an extended one carrying EXIF and XMP
+        let mut body = b"WEBP".to_vec();
+        body.extend_from_slice(b"VP8X\x0a\0\0\0");
+        body.extend_from_slice(&[WEBP_EXIF_FLAG | WEBP_XMP_FLAG, 0, 0, 0, 7, 0, 0, 3, 0, 0]);
+        body.extend_from_slice(&plain[12..]);
+        let exif = exif_with_gps();
+        body.extend_from_slice(b"EXIF");
+        body.extend_from_slice(&(exif.len() as u32).to_le_bytes());
+        body.extend_from_slice(&exif);
+        if exif.len() % 2 == 1 {
+            body.push(0);
+        }
+        body.extend_from_slice(b"XMP \x05\0\0\0<x/>!\0");
+        let tagged = [b"RIFF", &(body.len() as u32).to_le_bytes()[..], &body].concat();
+        assert_eq!(orientation_of(&tagged), Orientation::Rotate90);
+
+        let stripped = strip("image/webp", &tagged).unwrap();
+        assert!(!stripped.windows(3).any(|w| w == b"GPS"));
+        assert!(!stripped.windows(4).any(|w| w == b"<x/>"));
+        assert_eq!(stripped[20] & WEBP_XMP_FLAG, 0);
+        assert_eq!(orientation_of(&stripped), Orientation::Rotate90);
+        let decoded = image::load_from_memory(&stripped).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (8, 4));
+        assert_eq!(strip("image/webp", &plain).unwrap(), plain);
+    }
+
+    fn iso_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&((body.len() + 8) as u32).to_be_bytes()[..], kind, body].concat()
+    }
+
+    #[test]
+    fn test_blank_heif() {
+        let exif = [&[0, 0, 0, 0][..], &exif_with_gps()].concat();
+        let xmp = b"<x:xmpmeta>secret</x:xmpmeta>";
+        let mut infe_exif = vec![2, 0, 0, 0, 0, 1, 0, 0];
+        infe_exif.extend_from_slice(b"Exif\0");
+        let mut infe_xmp = vec![2, 0, 0, 0, 0, 2, 0, 0];
+        infe_xmp.extend_from_slice(b"mime\0application/rdf+xml\0");
+        let iinf = iso_box(
+            b"iinf",
+            &[
+                &[0, 0, 0, 0, 0, 2][..],
+                &iso_box(b"infe", &infe_exif),
+                &iso_box(b"infe", &infe_xmp),
+            ]
+            .concat(),
+        );
+        let ftyp = iso_box(b"ftyp", b"heic\0\0\0\0mif1heic");
+        // iloc: version 0, 4-byte offsets and lengths, no base offset, two items of one extent
+        let iloc_len = 8 + 8 + 2 * 14;
+        let meta_len = 8 + 4 + iinf.len() + iloc_len;
+        let mdat_body_start = (ftyp.len() + meta_len + 8) as u32;
+        let mut iloc = vec![0, 0, 0, 0, 0x44, 0x00, 0, 2];
+        for (id, offset, len) in [
+            (1u16, mdat_body_start, exif.len()),
+            (2, mdat_body_start + exif.len() as u32, xmp.len()),
+        ] {
+            iloc.extend_from_slice(&id.to_be_bytes());
+            iloc.extend_from_slice(&[0, 0, 0, 1]);
+            iloc.extend_from_slice(&offset.to_be_bytes());
+            iloc.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+        let meta = iso_box(
+            b"meta",
+            &[&[0, 0, 0, 0][..], &iinf, &iso_box(b"iloc", &iloc)].concat(),
+        );
+        assert_eq!(meta.len(), meta_len);
+        let mdat = iso_box(b"mdat", &[&exif[..], xmp, b"pixels"].concat());
+        let file = [ftyp, meta, mdat].concat();
+
+        let blanked = strip("image/heif", &file).unwrap();
+        assert_eq!(blanked.len(), file.len());
+        assert!(!blanked.windows(3).any(|w| w == b"GPS"));
+        assert!(!blanked.windows(6).any(|w| w == b"secret"));
+        assert!(blanked.ends_with(b"pixels"));
+        let start = mdat_body_start as usize;
+        assert_eq!(&blanked[start + 4..start + 8], b"MM\0\x2a");
+        assert_eq!(strip("image/heif", &blanked).unwrap(), blanked);
+    }
+
+    #[test]
+    fn test_blank_heif_malformed() {
+        let ftyp = iso_box(b"ftyp", b"heic\0\0\0\0mif1heic");
+        // An empty `iinf` as the last thing in the file
+        let meta = iso_box(
+            b"meta",
+            &[&[0, 0, 0, 0][..], &iso_box(b"iinf", b"")].concat(),
+        );
+        let err = strip("image/heif", &[&ftyp[..], &meta].concat()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // An EXIF extent whose offset plus length doesn't fit in 64 bits
+        let mut infe = vec![2, 0, 0, 0, 0, 1, 0, 0];
+        infe.extend_from_slice(b"Exif\0");
+        let iinf = iso_box(
+            b"iinf",
+            &[&[0, 0, 0, 0, 0, 1][..], &iso_box(b"infe", &infe)].concat(),
+        );
+        // iloc: version 0, 8-byte offsets and lengths, 8-byte base offset, one item
+        let mut iloc = vec![0, 0, 0, 0, 0x88, 0x80, 0, 1, 0, 1, 0, 0];
+        iloc.extend_from_slice(&1u64.to_be_bytes());
+        iloc.extend_from_slice(&[0, 1]);
+        iloc.extend_from_slice(&(u64::MAX - 1).to_be_bytes());
+        iloc.extend_from_slice(&4u64.to_be_bytes());
+        let meta = iso_box(
+            b"meta",
+            &[&[0, 0, 0, 0][..], &iinf, &iso_box(b"iloc", &iloc)].concat(),
+        );
+        let err = strip("image/heif", &[&ftyp[..], &meta].concat()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,183 @@
+pub mod metadata;
+pub mod placeholder;
+pub mod similarity;
+pub mod thumbnail;
+pub mod transform;
+pub mod watermark;
+
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, ImageDecoder, ImageError, ImageReader, ImageResult};
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Raster formats the `image` crate can decode, by detected MIME type.
+const RASTER_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/bmp",
+];
+
+pub fn is_raster(mime_type: &str) -> bool {
+    RASTER_TYPES.contains(&mime_type)
+}
+
+/// Decode the image at `path`, turned upright according to its EXIF orientation.
+pub fn open(path: &Path) -> ImageResult<DynamicImage> {
+    let mut decoder = ImageReader::open(path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+/// Formats images are re-encoded to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+}
+
+/// AV1 encoder speed, 1 (slowest, smallest) to 10. Past 8 files grow quickly for little gain.
+const AVIF_SPEED: u8 = 8;
+
+impl Format {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpeg",
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Format::Jpeg => "image/jpeg",
+            Format::Png => "image/png",
+            Format::Webp => "image/webp",
+            Format::Avif => "image/avif",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Jpeg => "jpg",
+            Format::Png => "png",
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+        }
+    }
+
+    /// The format to re-encode an image of `mime_type` in when none is asked for: its own,
+    /// or PNG for formats that can't be written.
+    pub fn for_source(mime_type: &str) -> Format {
+        match mime_type {
+            "image/jpeg" => Format::Jpeg,
+            "image/webp" => Format::Webp,
+            "image/avif" => Format::Avif,
+            _ => Format::Png,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpeg" | "jpg" => Ok(Format::Jpeg),
+            "png" => Ok(Format::Png),
+            "webp" => Ok(Format::Webp),
+            "avif" => Ok(Format::Avif),
+            _ => Err(format!("unknown image format: {}", s)),
+        }
+    }
+}
+
+/// Encode `image` as `format`. `quality` (1-100) applies to the lossy formats.
+pub fn encode(image: &DynamicImage, format: Format, quality: u8) -> ImageResult<Vec<u8>> {
+    let mut data = Vec::new();
+    match format {
+        Format::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut data, quality);
+            image.to_rgb8().write_with_encoder(encoder)?;
+        }
+        Format::Png => {
+            image.write_to(&mut Cursor::new(&mut data), image::ImageFormat::Png)?;
+        }
+        Format::Webp => {
+            // The `image` crate only writes lossless WebP
+            let encoded = if image.color().has_alpha() {
+                let rgba = image.to_rgba8();
+                webp::Encoder::from_rgba(&rgba, rgba.width(), rgba.height())
+                    .encode_simple(false, f32::from(quality))
+            } else {
+                let rgb = image.to_rgb8();
+                webp::Encoder::from_rgb(&rgb, rgb.width(), rgb.height())
+                    .encode_simple(false, f32::from(quality))
+            };
+            let encoded = encoded.map_err(|e| {
+                ImageError::IoError(std::io::Error::other(format!(
+                    "WebP encoding failed: {:?}",
+                    e
+                )))
+            })?;
+            data.extend_from_slice(&encoded);
+        }
+        Format::Avif => {
+            let encoder = AvifEncoder::new_with_speed_quality(&mut data, AVIF_SPEED, quality);
+            if image.color().has_alpha() {
+                image.to_rgba8().write_with_encoder(encoder)?;
+            } else {
+                image.to_rgb8().write_with_encoder(encoder)?;
+            }
+        }
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    #[test]
+    fn test_is_raster() {
+        assert!(is_raster("image/png"));
+        assert!(!is_raster("image/svg+xml"));
+        assert!(!is_raster("application/pdf"));
+    }
+
+    #[test]
+    fn test_encode() {
+        let image =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(40, 20, Rgba([9, 99, 199, 128])));
+        for format in [Format::Jpeg, Format::Png, Format::Webp, Format::Avif] {
+            let data = encode(&image, format, 75).unwrap();
+            assert_eq!(
+                crate::content_type::sniff(&data),
+                Some(format.mime_type()),
+                "{:?}",
+                format
+            );
+            assert_eq!(format.as_str().parse(), Ok(format));
+            // There is no AVIF decoder to check the result with
+            if format != Format::Avif {
+                let decoded = image::load_from_memory(&data).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), (40, 20));
+            }
+        }
+        assert_eq!(Format::for_source("image/gif"), Format::Png);
+    }
+}
@@ -0,0 +1,155 @@
+//! Stand-ins shown while an image loads: a BlurHash string and a tiny inlined copy.
+
+use super::{Format, encode};
+use base64::Engine as _;
+use image::DynamicImage;
+use std::f32::consts::PI;
+
+/// Horizontal and vertical BlurHash components; 4x3 suits most photos.
+const COMPONENTS: (u32, u32) = (4, 3);
+/// BlurHash only captures broad colour, so it is computed on a small copy.
+const BLURHASH_SOURCE_SIZE: u32 = 32;
+/// Longest edge of the inlined copy, in pixels.
+const LQIP_SIZE: u32 = 16;
+const LQIP_QUALITY: u8 = 50;
+
+/// The [BlurHash](https://blurha.sh) of `image`.
+pub fn blurhash(image: &DynamicImage) -> String {
+    let small = image
+        .thumbnail(BLURHASH_SOURCE_SIZE, BLURHASH_SOURCE_SIZE)
+        .to_rgb8();
+    let (width, height) = small.dimensions();
+    let linear: Vec<[f32; 3]> = small.pixels().map(|p| p.0.map(srgb_to_linear)).collect();
+
+    let (x_components, y_components) = COMPONENTS;
+    let mut factors = Vec::with_capacity((x_components * y_components) as usize);
+    for j in 0..y_components {
+        for i in 0..x_components {
+            let normalisation = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut factor = [0.0f32; 3];
+            for y in 0..height {
+                for x in 0..width {
+                    let basis = normalisation
+                        * (PI * i as f32 * x as f32 / width as f32).cos()
+                        * (PI * j as f32 * y as f32 / height as f32).cos();
+                    let pixel = linear[(y * width + x) as usize];
+                    for (sum, value) in factor.iter_mut().zip(pixel) {
+                        *sum += basis * value;
+                    }
+                }
+            }
+            factors.push(factor.map(|sum| sum / (width * height) as f32));
+        }
+    }
+
+    let mut hash = String::with_capacity(28);
+    encode_base83((x_components - 1) + (y_components - 1) * 9, 1, &mut hash);
+    let (dc, ac) = factors.split_first().expect("at least one component");
+    let maximum = if ac.is_empty() {
+        encode_base83(0, 1, &mut hash);
+        1.0
+    } else {
+        let actual = ac.iter().flatten().fold(0.0f32, |max, v| max.max(v.abs()));
+        let quantised = ((actual * 166.0 - 0.5).floor()).clamp(0.0, 82.0) as u32;
+        encode_base83(quantised, 1, &mut hash);
+        (quantised + 1) as f32 / 166.0
+    };
+    let [r, g, b] = dc.map(linear_to_srgb);
+    encode_base83((r << 16) | (g << 8) | b, 4, &mut hash);
+    for factor in ac {
+        let [r, g, b] = factor.map(|v| {
+            let scaled = (v / maximum).signum() * (v / maximum).abs().sqrt();
+            (scaled * 9.0 + 9.5).floor().clamp(0.0, 18.0) as u32
+        });
+        encode_base83(r * 19 * 19 + g * 19 + b, 2, &mut hash);
+    }
+    hash
+}
+
+/// A copy of `image` a few pixels across, as a `data:` URI to inline in a page. PNG when the
+/// image has transparency, JPEG otherwise.
+pub fn lqip(image: &DynamicImage) -> Option<String> {
+    let format = if image.color().has_alpha() {
+        Format::Png
+    } else {
+        Format::Jpeg
+    };
+    let data = encode(&image.thumbnail(LQIP_SIZE, LQIP_SIZE), format, LQIP_QUALITY).ok()?;
+    Some(format!(
+        "data:{};base64,{}",
+        format.mime_type(),
+        base64::engine::general_purpose::STANDARD.encode(data)
+    ))
+}
+
+const BASE83: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn encode_base83(value: u32, length: u32, out: &mut String) {
+    for i in 1..=length {
+        let digit = (value / 83u32.pow(length - i)) % 83;
+        out.push(BASE83[digit as usize] as char);
+    }
+}
+
+fn srgb_to_linear(value: u8) -> f32 {
+    let v = f32::from(value) / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> u32 {
+    let v = value.clamp(0.0, 1.0);
+    let srgb = if v <= 0.003_130_8 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (srgb * 255.0 + 0.5) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    #[test]
+    fn test_blurhash() {
+        let red = DynamicImage::ImageRgb8(RgbImage::from_pixel(50, 30, Rgb([255, 0, 0])));
+        let hash = blurhash(&red);
+        // 4x3 components, the scale of the rest, #ff0000, then eleven more components
+        assert_eq!(hash.len(), 28);
+        assert_eq!(&hash[..1], "L");
+        assert_eq!(&hash[2..6], "TI:j");
+        assert_eq!(blurhash(&red), hash);
+
+        // Light on the left and dark on the right varies far more than a flat image
+        let mut split = RgbImage::from_pixel(40, 40, Rgb([250, 250, 250]));
+        for x in 20..40 {
+            for y in 0..40 {
+                split.put_pixel(x, y, Rgb([5, 5, 5]));
+            }
+        }
+        let split = blurhash(&DynamicImage::ImageRgb8(split));
+        let scale = |hash: &str| BASE83.iter().position(|c| *c == hash.as_bytes()[1]);
+        assert!(scale(&split) > scale(&hash));
+    }
+
+    #[test]
+    fn test_lqip() {
+        let photo = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 200, Rgb([1, 2, 3])));
+        let uri = lqip(&photo).unwrap();
+        let data = uri.strip_prefix("data:image/jpeg;base64,").unwrap();
+        let data = base64::engine::general_purpose::STANDARD
+            .decode(data)
+            .unwrap();
+        let decoded = image::load_from_memory(&data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (16, 8));
+
+        let clear = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([0, 0, 0, 0])));
+        assert!(lqip(&clear).unwrap().starts_with("data:image/png;base64,"));
+    }
+}
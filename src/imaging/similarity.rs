@@ -0,0 +1,84 @@
+//! Perceptual hashes, which stay close for resized or recompressed copies of an image.
+
+use image::DynamicImage;
+use image::imageops::FilterType;
+
+/// The 64-bit difference hash (dHash) of `image`: it is shrunk to 9x8 grey pixels and each bit
+/// says whether a pixel is brighter than its right-hand neighbour.
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y).0[0] > small.get_pixel(x + 1, y).0[0];
+            hash = hash << 1 | u64::from(brighter);
+        }
+    }
+    hash
+}
+
+/// Number of bits in which two hashes differ.
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// A hash as stored and returned by the API: 16 hex digits.
+pub fn to_hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+pub fn from_hex(hash: &str) -> Option<u64> {
+    u64::from_str_radix(hash, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    /// Something like a screenshot: a light page with dark blocks of "text".
+    fn screenshot(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let (u, v) = (x * 40 / width, y * 30 / height);
+            if (u % 7 < 5 && v % 3 == 1) || (u < 8 && v < 4) {
+                Rgb([30, 30, 40])
+            } else {
+                Rgb([240, 240, 235])
+            }
+        }))
+    }
+
+    #[test]
+    fn test_dhash() {
+        let original = screenshot(1200, 900);
+        let hash = dhash(&original);
+        assert_eq!(from_hex(&to_hex(hash)), Some(hash));
+
+        // A smaller copy, re-encoded as a JPEG, is still close
+        let mut jpeg = Vec::new();
+        original
+            .resize(600, 450, FilterType::Lanczos3)
+            .to_rgb8()
+            .write_to(
+                &mut std::io::Cursor::new(&mut jpeg),
+                image::ImageFormat::Jpeg,
+            )
+            .unwrap();
+        let copy = image::load_from_memory(&jpeg).unwrap();
+        assert!(distance(hash, dhash(&copy)) <= 3);
+
+        // A different picture is not
+        let other = DynamicImage::ImageRgb8(RgbImage::from_fn(1200, 900, |x, y| {
+            Rgb([(x * 255 / 1200) as u8, (y * 255 / 900) as u8, 128])
+        }));
+        assert!(distance(hash, dhash(&other)) > 10);
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(distance(0, 0), 0);
+        assert_eq!(distance(0b1011, 0b0001), 2);
+        assert_eq!(distance(0, u64::MAX), 64);
+        assert_eq!(from_hex("not hex"), None);
+    }
+}
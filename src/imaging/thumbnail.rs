@@ -0,0 +1,83 @@
+use super::{Format, encode};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageResult};
+
+/// An encoded thumbnail, ready to be stored.
+pub struct ThumbnailImage {
+    /// The configured size it was made for: the longest edge it may have.
+    pub size: u32,
+    pub width: u32,
+    pub height: u32,
+    pub format: Format,
+    pub data: Vec<u8>,
+}
+
+/// Scale `image` down to fit each of `sizes`, smallest first.
+///
+/// Sizes the image already fits are not scaled up: the first of them gets a copy at the
+/// original dimensions and larger ones none, as they would be the same.
+pub fn generate(
+    image: &DynamicImage,
+    sizes: &[u32],
+    quality: u8,
+) -> ImageResult<Vec<ThumbnailImage>> {
+    let mut sizes = sizes.to_vec();
+    sizes.sort_unstable();
+    sizes.dedup();
+    let mut thumbnails = Vec::new();
+    for size in sizes {
+        let fits = image.width() <= size && image.height() <= size;
+        let scaled = if fits {
+            image.clone()
+        } else {
+            image.resize(size, size, FilterType::Triangle)
+        };
+        // JPEG for opaque images, PNG where transparency has to be kept
+        let format = if scaled.color().has_alpha() {
+            Format::Png
+        } else {
+            Format::Jpeg
+        };
+        thumbnails.push(ThumbnailImage {
+            size,
+            width: scaled.width(),
+            height: scaled.height(),
+            format,
+            data: encode(&scaled, format, quality)?,
+        });
+        if fits {
+            break;
+        }
+    }
+    Ok(thumbnails)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage, Rgba, RgbaImage};
+
+    #[test]
+    fn test_generate() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 200, Rgb([200, 10, 10])));
+        let thumbnails = generate(&image, &[1024, 512, 100, 256, 100], 80).unwrap();
+        let dimensions: Vec<_> = thumbnails
+            .iter()
+            .map(|t| (t.size, t.width, t.height))
+            .collect();
+        // Nothing is scaled up, and 1024 would be the same as the copy made for 512
+        assert_eq!(
+            dimensions,
+            [(100, 100, 50), (256, 256, 128), (512, 400, 200)]
+        );
+        assert!(thumbnails.iter().all(|t| t.format == Format::Jpeg));
+        let decoded = image::load_from_memory(&thumbnails[0].data).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (100, 50));
+
+        let small = DynamicImage::ImageRgba8(RgbaImage::from_pixel(30, 60, Rgba([0, 0, 0, 0])));
+        let thumbnails = generate(&small, &[128, 256], 80).unwrap();
+        assert_eq!(thumbnails.len(), 1);
+        assert_eq!((thumbnails[0].width, thumbnails[0].height), (30, 60));
+        assert_eq!(thumbnails[0].format, Format::Png);
+    }
+}
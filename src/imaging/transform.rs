@@ -0,0 +1,355 @@
+use super::{Format, encode};
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageResult};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Largest width or height a transform may ask for.
+pub const MAX_DIMENSION: u32 = 4096;
+
+/// How an image is fitted into the requested box.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    /// Scale down to fit inside the box, keeping the aspect ratio
+    #[default]
+    Contain,
+    /// Scale to cover the box, keeping the aspect ratio, and crop the overflow around the middle
+    Cover,
+    /// Stretch to exactly the box
+    Fill,
+}
+
+impl Fit {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Fill => "fill",
+        }
+    }
+}
+
+/// A resize and re-encode of an image, as named by a preset or spelled out in a download's
+/// query string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Transform {
+    /// Width of the box, in pixels
+    pub w: Option<u32>,
+    /// Height of the box, in pixels
+    pub h: Option<u32>,
+    pub fit: Fit,
+    /// Output format; the source's own by default
+    pub format: Option<Format>,
+    /// Quality of lossy formats, 1-100
+    pub q: Option<u8>,
+}
+
+impl Transform {
+    /// Check the values are usable, naming the first that isn't.
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [("w", self.w), ("h", self.h)] {
+            if let Some(value) = value
+                && !(1..=MAX_DIMENSION).contains(&value)
+            {
+                return Err(format!(
+                    "{} must be between 1 and {} (got {})",
+                    name, MAX_DIMENSION, value
+                ));
+            }
+        }
+        if self.fit != Fit::Contain && (self.w.is_none() || self.h.is_none()) {
+            return Err(format!("fit={} needs both w and h", self.fit.as_str()));
+        }
+        if let Some(q) = self.q
+            && !(1..=100).contains(&q)
+        {
+            return Err(format!("q must be between 1 and 100 (got {})", q));
+        }
+        if self.w.is_none() && self.h.is_none() && self.format.is_none() {
+            return Err("a transform needs w, h or format".to_string());
+        }
+        Ok(())
+    }
+
+    /// File name of the rendering in `format` at `quality`, unique to its parameters.
+    pub fn cache_name(&self, format: Format, quality: u8) -> String {
+        let dimension = |value: Option<u32>| value.map_or("auto".to_string(), |v| v.to_string());
+        format!(
+            "{}x{}-{}-q{}.{}",
+            dimension(self.w),
+            dimension(self.h),
+            self.fit.as_str(),
+            quality,
+            format.extension()
+        )
+    }
+
+    /// The rendering that matches a thumbnail of `size` in `format`: fitted into the square and
+    /// never enlarged.
+    pub fn thumbnail(size: u32, format: Format, quality: u8) -> Self {
+        Transform {
+            w: Some(size),
+            h: Some(size),
+            fit: Fit::Contain,
+            format: Some(format),
+            q: Some(quality),
+        }
+    }
+
+    /// Resize `image` into the box. `Contain` never enlarges an image that already fits.
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let filter = FilterType::CatmullRom;
+        match (self.fit, self.w, self.h) {
+            (_, None, None) => image.clone(),
+            (Fit::Cover, Some(w), Some(h)) => image.resize_to_fill(w, h, filter),
+            (Fit::Fill, Some(w), Some(h)) => image.resize_exact(w, h, filter),
+            (_, w, h) => {
+                let (w, h) = (w.unwrap_or(u32::MAX), h.unwrap_or(u32::MAX));
+                if image.width() <= w && image.height() <= h {
+                    image.clone()
+                } else {
+                    image.resize(w, h, filter)
+                }
+            }
+        }
+    }
+
+    pub fn render(
+        &self,
+        image: &DynamicImage,
+        format: Format,
+        quality: u8,
+    ) -> ImageResult<Vec<u8>> {
+        encode(&self.apply(image), format, quality)
+    }
+}
+
+/// The transform parameters of a download's query string.
+#[derive(Debug, Default, Deserialize)]
+pub struct TransformQuery {
+    /// Name of a configured preset
+    pub preset: Option<String>,
+    pub w: Option<u32>,
+    pub h: Option<u32>,
+    pub fit: Option<Fit>,
+    pub format: Option<Format>,
+    pub q: Option<u8>,
+}
+
+impl TransformQuery {
+    /// The transform asked for, or `None` for the original. Only `presets` are served, whether
+    /// asked for by name or with the same parameters.
+    pub fn resolve(
+        &self,
+        presets: &BTreeMap<String, Transform>,
+    ) -> Result<Option<Transform>, String> {
+        let spelled_out = Transform {
+            w: self.w,
+            h: self.h,
+            fit: self.fit.unwrap_or_default(),
+            format: self.format,
+            q: self.q,
+        };
+        let parameters = spelled_out != Transform::default() || self.fit.is_some();
+        match &self.preset {
+            Some(_) if parameters => {
+                Err("Give either a preset or parameters, not both".to_string())
+            }
+            Some(name) => match presets.get(name) {
+                Some(transform) => Ok(Some(*transform)),
+                None => Err(format!("Unknown preset {:?}", name)),
+            },
+            None if parameters => {
+                if presets.values().any(|preset| *preset == spelled_out) {
+                    Ok(Some(spelled_out))
+                } else {
+                    Err("These parameters don't match any preset".to_string())
+                }
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Renderings of stored images on local disk, under the source's content hash.
+pub struct TransformCache {
+    dir: PathBuf,
+}
+
+impl TransformCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        TransformCache { dir: dir.into() }
+    }
+
+    /// Where the rendering `name` of the source with hash `source` is kept.
+    pub fn path(&self, source: &str, name: &str) -> PathBuf {
+        self.source_dir(source).join(name)
+    }
+
+    fn source_dir(&self, source: &str) -> PathBuf {
+        let shard = source.get(..2).unwrap_or(source);
+        self.dir.join(shard).join(source)
+    }
+
+    /// Save a rendering, replacing the file in one step so readers never see half of it.
+    pub async fn store(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let partial = path.with_extension(format!("partial-{}", uuid::Uuid::new_v4()));
+        tokio::fs::write(&partial, data).await?;
+        if let Err(e) = tokio::fs::rename(&partial, path).await {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Drop every rendering of a source that is no longer stored.
+    pub async fn remove(&self, source: &str) {
+        let dir = self.source_dir(source);
+        match tokio::fs::remove_dir_all(&dir).await {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!(
+                "Failed to remove cached renderings {}: {}",
+                dir.display(),
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn presets() -> BTreeMap<String, Transform> {
+        BTreeMap::from([
+            (
+                "small".to_string(),
+                Transform {
+                    w: Some(400),
+                    ..Default::default()
+                },
+            ),
+            (
+                "card".to_string(),
+                Transform {
+                    w: Some(800),
+                    h: Some(600),
+                    fit: Fit::Cover,
+                    format: Some(Format::Webp),
+                    q: Some(80),
+                },
+            ),
+        ])
+    }
+
+    fn query(s: &str) -> TransformQuery {
+        actix_web::web::Query::<TransformQuery>::from_query(s)
+            .unwrap()
+            .into_inner()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let presets = presets();
+        assert_eq!(query("").resolve(&presets), Ok(None));
+        assert_eq!(
+            query("preset=card").resolve(&presets),
+            Ok(Some(presets["card"]))
+        );
+        assert_eq!(
+            query("w=800&h=600&fit=cover&format=webp&q=80").resolve(&presets),
+            Ok(Some(presets["card"]))
+        );
+        assert_eq!(
+            query("w=400&fit=contain").resolve(&presets),
+            Ok(Some(presets["small"]))
+        );
+        assert!(query("w=401").resolve(&presets).is_err());
+        assert!(
+            query("w=800&h=600&fit=cover&format=webp")
+                .resolve(&presets)
+                .is_err()
+        );
+        assert!(query("preset=huge").resolve(&presets).is_err());
+        assert!(query("preset=small&w=400").resolve(&presets).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(presets().values().all(|preset| preset.validate().is_ok()));
+        let cover_width_only = Transform {
+            w: Some(100),
+            fit: Fit::Cover,
+            ..Default::default()
+        };
+        assert!(
+            cover_width_only
+                .validate()
+                .unwrap_err()
+                .contains("needs both")
+        );
+        assert!(Transform::default().validate().is_err());
+        let too_wide = Transform {
+            w: Some(MAX_DIMENSION + 1),
+            ..Default::default()
+        };
+        assert!(too_wide.validate().is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 200, Rgb([1, 2, 3])));
+        let size = |transform: Transform| {
+            let out = transform.apply(&image);
+            (out.width(), out.height())
+        };
+        let card = presets()["card"];
+        assert_eq!(size(card), (800, 600));
+        assert_eq!(size(presets()["small"]), (400, 200));
+        let contain = Transform {
+            w: Some(100),
+            h: Some(100),
+            ..Default::default()
+        };
+        assert_eq!(size(contain), (100, 50));
+        let fill = Transform {
+            fit: Fit::Fill,
+            ..contain
+        };
+        assert_eq!(size(fill), (100, 100));
+        let height_only = Transform {
+            h: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(size(height_only), (100, 50));
+        assert_eq!(card.cache_name(Format::Webp, 80), "800x600-cover-q80.webp");
+        assert_eq!(
+            height_only.cache_name(Format::Png, 80),
+            "autox50-contain-q80.png"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let dir = std::env::temp_dir().join(format!("rih-transforms-{}", uuid::Uuid::new_v4()));
+        let cache = TransformCache::new(&dir);
+        let path = cache.path("abcdef", "100xauto-contain-q80.jpg");
+        assert_eq!(path, dir.join("ab/abcdef/100xauto-contain-q80.jpg"));
+        cache.store(&path, b"rendered").await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"rendered");
+        cache.remove("abcdef").await;
+        assert!(!path.exists());
+        cache.remove("abcdef").await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
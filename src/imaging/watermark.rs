@@ -0,0 +1,374 @@
+//! Stamping a text or image watermark onto pictures.
+
+use super::{Format, encode};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageResult, Rgba, RgbaImage};
+use serde::Deserialize;
+use sha2::{Digest as _, Sha256};
+use std::sync::Arc;
+
+/// Where on an image the watermark goes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Position {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    #[default]
+    BottomRight,
+}
+
+impl Position {
+    /// Offset of a `mark`-sized box in a `canvas`-sized one, `margin` pixels from the edges.
+    fn offset(self, canvas: (u32, u32), mark: (u32, u32), margin: u32) -> (i64, i64) {
+        let place = |canvas: u32, mark: u32, start: bool, end: bool| -> i64 {
+            let (canvas, mark, margin) = (i64::from(canvas), i64::from(mark), i64::from(margin));
+            match (start, end) {
+                (true, _) => margin,
+                (_, true) => canvas - mark - margin,
+                _ => (canvas - mark) / 2,
+            }
+        };
+        use Position::*;
+        let left = matches!(self, TopLeft | Left | BottomLeft);
+        let right = matches!(self, TopRight | Right | BottomRight);
+        let top = matches!(self, TopLeft | Top | TopRight);
+        let bottom = matches!(self, BottomLeft | Bottom | BottomRight);
+        (
+            place(canvas.0, mark.0, left, right),
+            place(canvas.1, mark.1, top, bottom),
+        )
+    }
+}
+
+/// How a watermark is laid over images.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Style {
+    pub position: Position,
+    /// 0 (invisible) to 1 (as drawn)
+    pub opacity: f32,
+    /// Width of the watermark as a fraction of the image's
+    pub scale: f32,
+    /// Gap to the image's edges as a fraction of its shorter side
+    pub margin: f32,
+    /// Quality of watermarked images in lossy formats, 1-100
+    pub quality: u8,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            position: Position::default(),
+            opacity: 0.5,
+            scale: 0.25,
+            margin: 0.02,
+            quality: 85,
+        }
+    }
+}
+
+/// A watermark, ready to be stamped onto images. Clones share the drawing.
+#[derive(Clone)]
+pub struct Watermark {
+    mark: Arc<RgbaImage>,
+    /// Text is drawn with a pixel font, which stays crisp when scaled without smoothing
+    pixelated: bool,
+    style: Style,
+    fingerprint: String,
+}
+
+impl Watermark {
+    /// A watermark of `text` in white with a dark shadow, so it shows on light and dark
+    /// pictures alike. Only ASCII is drawn; other characters show as `?`.
+    pub fn text(text: &str, style: Style) -> Self {
+        let glyphs: Vec<&[u8; 8]> = text
+            .chars()
+            .map(|c| {
+                let index = if c.is_ascii_graphic() || c == ' ' {
+                    c as usize - 0x20
+                } else {
+                    usize::from(b'?' - 0x20)
+                };
+                &FONT[index]
+            })
+            .collect();
+        // One extra pixel each way for the shadow
+        let width = (glyphs.len() as u32 * 8).max(1) + 1;
+        let mut mark = RgbaImage::new(width, 9);
+        for (shift, colour) in [(1, Rgba([0, 0, 0, 160])), (0, Rgba([255, 255, 255, 255]))] {
+            for (i, glyph) in glyphs.iter().enumerate() {
+                for (y, row) in glyph.iter().enumerate() {
+                    for x in 0..8 {
+                        if row >> x & 1 == 1 {
+                            let px = i as u32 * 8 + x + shift;
+                            mark.put_pixel(px, y as u32 + shift, colour);
+                        }
+                    }
+                }
+            }
+        }
+        Self::new(mark, true, style)
+    }
+
+    /// A watermark of a picture, such as a logo with a transparent background.
+    pub fn image(image: &DynamicImage, style: Style) -> Self {
+        Self::new(image.to_rgba8(), false, style)
+    }
+
+    fn new(mark: RgbaImage, pixelated: bool, style: Style) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(mark.width().to_le_bytes());
+        hasher.update(mark.as_raw());
+        hasher.update(format!("{:?} {}", style, pixelated).as_bytes());
+        Watermark {
+            mark: Arc::new(mark),
+            pixelated,
+            style,
+            fingerprint: hex::encode(&hasher.finalize()[..8]),
+        }
+    }
+
+    /// Identifies the watermark and its style, so copies made with different ones are told
+    /// apart.
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    /// `image` with the watermark on it.
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let mut canvas = image.to_rgba8();
+        let (width, height) = canvas.dimensions();
+        let Style {
+            position,
+            opacity,
+            scale,
+            margin,
+            quality: _,
+        } = self.style;
+        let margin = (margin * width.min(height) as f32).round() as u32;
+        // As wide as asked, but never past the margins
+        let (mark_width, mark_height) = self.mark.dimensions();
+        let fit = (scale * width as f32 / mark_width as f32)
+            .min(width.saturating_sub(2 * margin) as f32 / mark_width as f32)
+            .min(height.saturating_sub(2 * margin) as f32 / mark_height as f32);
+        let size = (
+            (mark_width as f32 * fit).round() as u32,
+            (mark_height as f32 * fit).round() as u32,
+        );
+        if size.0 == 0 || size.1 == 0 {
+            return image.clone();
+        }
+        let filter = if self.pixelated {
+            FilterType::Nearest
+        } else {
+            FilterType::CatmullRom
+        };
+        let mut mark = imageops::resize(self.mark.as_ref(), size.0, size.1, filter);
+        for pixel in mark.pixels_mut() {
+            pixel.0[3] = (f32::from(pixel.0[3]) * opacity).round() as u8;
+        }
+        let (x, y) = position.offset((width, height), size, margin);
+        imageops::overlay(&mut canvas, &mark, x, y);
+        if image.color().has_alpha() {
+            DynamicImage::ImageRgba8(canvas)
+        } else {
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(canvas).to_rgb8())
+        }
+    }
+
+    pub fn render(&self, image: &DynamicImage, format: Format) -> ImageResult<Vec<u8>> {
+        encode(&self.apply(image), format, self.style.quality)
+    }
+}
+
+/// 8x8 pixel glyphs of printable ASCII, from the public domain font8x8. Each byte is a row,
+/// top first, with the lowest bit leftmost.
+const FONT: [[u8; 8]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00], // !
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // "
+    [0x36, 0x36, 0x7F, 0x36, 0x7F, 0x36, 0x36, 0x00], // #
+    [0x0C, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x0C, 0x00], // $
+    [0x00, 0x63, 0x33, 0x18, 0x0C, 0x66, 0x63, 0x00], // %
+    [0x1C, 0x36, 0x1C, 0x6E, 0x3B, 0x33, 0x6E, 0x00], // &
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // '
+    [0x18, 0x0C, 0x06, 0x06, 0x06, 0x0C, 0x18, 0x00], // (
+    [0x06, 0x0C, 0x18, 0x18, 0x18, 0x0C, 0x06, 0x00], // )
+    [0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00], // *
+    [0x00, 0x0C, 0x0C, 0x3F, 0x0C, 0x0C, 0x00, 0x00], // +
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ,
+    [0x00, 0x00, 0x00, 0x3F, 0x00, 0x00, 0x00, 0x00], // -
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x00], // .
+    [0x60, 0x30, 0x18, 0x0C, 0x06, 0x03, 0x01, 0x00], // /
+    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00], // 0
+    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00], // 1
+    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00], // 2
+    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00], // 3
+    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00], // 4
+    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00], // 5
+    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00], // 6
+    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00], // 7
+    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00], // 8
+    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00], // 9
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00], // :
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ;
+    [0x18, 0x0C, 0x06, 0x03, 0x06, 0x0C, 0x18, 0x00], // <
+    [0x00, 0x00, 0x3F, 0x00, 0x00, 0x3F, 0x00, 0x00], // =
+    [0x06, 0x0C, 0x18, 0x30, 0x18, 0x0C, 0x06, 0x00], // >
+    [0x1E, 0x33, 0x30, 0x18, 0x0C, 0x00, 0x0C, 0x00], // ?
+    [0x3E, 0x63, 0x7B, 0x7B, 0x7B, 0x03, 0x1E, 0x00], // @
+    [0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00], // A
+    [0x3F, 0x66, 0x66, 0x3E, 0x66, 0x66, 0x3F, 0x00], // B
+    [0x3C, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3C, 0x00], // C
+    [0x1F, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1F, 0x00], // D
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x46, 0x7F, 0x00], // E
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x06, 0x0F, 0x00], // F
+    [0x3C, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7C, 0x00], // G
+    [0x33, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x33, 0x00], // H
+    [0x1E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // I
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E, 0x00], // J
+    [0x67, 0x66, 0x36, 0x1E, 0x36, 0x66, 0x67, 0x00], // K
+    [0x0F, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7F, 0x00], // L
+    [0x63, 0x77, 0x7F, 0x7F, 0x6B, 0x63, 0x63, 0x00], // M
+    [0x63, 0x67, 0x6F, 0x7B, 0x73, 0x63, 0x63, 0x00], // N
+    [0x1C, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1C, 0x00], // O
+    [0x3F, 0x66, 0x66, 0x3E, 0x06, 0x06, 0x0F, 0x00], // P
+    [0x1E, 0x33, 0x33, 0x33, 0x3B, 0x1E, 0x38, 0x00], // Q
+    [0x3F, 0x66, 0x66, 0x3E, 0x36, 0x66, 0x67, 0x00], // R
+    [0x1E, 0x33, 0x07, 0x0E, 0x38, 0x33, 0x1E, 0x00], // S
+    [0x3F, 0x2D, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // T
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3F, 0x00], // U
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // V
+    [0x63, 0x63, 0x63, 0x6B, 0x7F, 0x77, 0x63, 0x00], // W
+    [0x63, 0x63, 0x36, 0x1C, 0x1C, 0x36, 0x63, 0x00], // X
+    [0x33, 0x33, 0x33, 0x1E, 0x0C, 0x0C, 0x1E, 0x00], // Y
+    [0x7F, 0x63, 0x31, 0x18, 0x4C, 0x66, 0x7F, 0x00], // Z
+    [0x1E, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1E, 0x00], // [
+    [0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x40, 0x00], // \
+    [0x1E, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1E, 0x00], // ]
+    [0x08, 0x1C, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // ^
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF], // _
+    [0x0C, 0x0C, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00], // `
+    [0x00, 0x00, 0x1E, 0x30, 0x3E, 0x33, 0x6E, 0x00], // a
+    [0x07, 0x06, 0x06, 0x3E, 0x66, 0x66, 0x3B, 0x00], // b
+    [0x00, 0x00, 0x1E, 0x33, 0x03, 0x33, 0x1E, 0x00], // c
+    [0x38, 0x30, 0x30, 0x3E, 0x33, 0x33, 0x6E, 0x00], // d
+    [0x00, 0x00, 0x1E, 0x33, 0x3F, 0x03, 0x1E, 0x00], // e
+    [0x1C, 0x36, 0x06, 0x0F, 0x06, 0x06, 0x0F, 0x00], // f
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x1F], // g
+    [0x07, 0x06, 0x36, 0x6E, 0x66, 0x66, 0x67, 0x00], // h
+    [0x0C, 0x00, 0x0E, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // i
+    [0x30, 0x00, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E], // j
+    [0x07, 0x06, 0x66, 0x36, 0x1E, 0x36, 0x67, 0x00], // k
+    [0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // l
+    [0x00, 0x00, 0x33, 0x7F, 0x7F, 0x6B, 0x63, 0x00], // m
+    [0x00, 0x00, 0x1F, 0x33, 0x33, 0x33, 0x33, 0x00], // n
+    [0x00, 0x00, 0x1E, 0x33, 0x33, 0x33, 0x1E, 0x00], // o
+    [0x00, 0x00, 0x3B, 0x66, 0x66, 0x3E, 0x06, 0x0F], // p
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x78], // q
+    [0x00, 0x00, 0x3B, 0x6E, 0x66, 0x06, 0x0F, 0x00], // r
+    [0x00, 0x00, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x00], // s
+    [0x08, 0x0C, 0x3E, 0x0C, 0x0C, 0x2C, 0x18, 0x00], // t
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x33, 0x6E, 0x00], // u
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // v
+    [0x00, 0x00, 0x63, 0x6B, 0x7F, 0x7F, 0x36, 0x00], // w
+    [0x00, 0x00, 0x63, 0x36, 0x1C, 0x36, 0x63, 0x00], // x
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x3E, 0x30, 0x1F], // y
+    [0x00, 0x00, 0x3F, 0x19, 0x0C, 0x26, 0x3F, 0x00], // z
+    [0x38, 0x0C, 0x0C, 0x07, 0x0C, 0x0C, 0x38, 0x00], // {
+    [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00], // |
+    [0x07, 0x0C, 0x0C, 0x38, 0x0C, 0x0C, 0x07, 0x00], // }
+    [0x6E, 0x3B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ~
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::imaging::transform::Transform;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn test_text() {
+        let mark = Watermark::text("Hi é", Style::default());
+        // Four glyphs and the shadow
+        assert_eq!(mark.mark.dimensions(), (33, 9));
+        // The top row of H: two columns set on each side
+        let row: Vec<bool> = (0..8)
+            .map(|x| mark.mark.get_pixel(x, 0).0 == [255, 255, 255, 255])
+            .collect();
+        assert_eq!(row, [true, true, false, false, true, true, false, false]);
+        // é is drawn as ?
+        assert_eq!(FONT[usize::from(b'?' - 0x20)][0], 0x1E);
+        assert_ne!(
+            mark.fingerprint(),
+            Watermark::text("Hi!", Style::default()).fingerprint()
+        );
+        let faint = Style {
+            opacity: 0.2,
+            ..Style::default()
+        };
+        assert_ne!(
+            mark.fingerprint(),
+            Watermark::text("Hi é", faint).fingerprint()
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let black = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 200, Rgb([0, 0, 0])));
+        let logo = DynamicImage::ImageRgb8(RgbImage::from_pixel(20, 10, Rgb([255, 255, 255])));
+        let style = Style {
+            opacity: 0.5,
+            scale: 0.25,
+            margin: 0.05,
+            ..Style::default()
+        };
+        let mark = Watermark::image(&logo, style);
+
+        let stamped = mark.apply(&black).to_rgb8();
+        assert_eq!(stamped.dimensions(), (400, 200));
+        // 100x50 in the bottom-right corner, 10 pixels in, at half strength
+        assert_eq!(stamped.get_pixel(340, 170).0, [128, 128, 128]);
+        assert_eq!(stamped.get_pixel(289, 139).0, [0, 0, 0]);
+        assert_eq!(stamped.get_pixel(391, 191).0, [0, 0, 0]);
+        assert_eq!(stamped.get_pixel(10, 10).0, [0, 0, 0]);
+
+        let style = Style {
+            position: Position::TopLeft,
+            scale: 10.0,
+            ..style
+        };
+        let mark = Watermark::image(&logo, style);
+        let stamped = mark.apply(&black).to_rgb8();
+        // Never larger than fits inside the margins
+        assert_eq!(stamped.get_pixel(10, 10).0, [128, 128, 128]);
+        assert_eq!(stamped.get_pixel(370, 190).0, [0, 0, 0]);
+        assert_eq!(stamped.get_pixel(9, 9).0, [0, 0, 0]);
+        assert!(!mark.apply(&black).color().has_alpha());
+    }
+
+    #[test]
+    fn test_stamped_thumbnail() {
+        // Others get thumbnails rendered from the watermarked copy, so the mark shows there too
+        let black = DynamicImage::ImageRgb8(RgbImage::from_pixel(400, 200, Rgb([0, 0, 0])));
+        let logo = DynamicImage::ImageRgb8(RgbImage::from_pixel(20, 10, Rgb([255, 255, 255])));
+        let style = Style {
+            opacity: 1.0,
+            scale: 0.25,
+            margin: 0.05,
+            ..Style::default()
+        };
+        let stamped = Watermark::image(&logo, style).apply(&black);
+        let thumbnail = Transform::thumbnail(100, Format::Jpeg, 80)
+            .apply(&stamped)
+            .to_rgb8();
+        assert_eq!(thumbnail.dimensions(), (100, 50));
+        assert_eq!(thumbnail.get_pixel(85, 42).0, [255, 255, 255]);
+        assert_eq!(thumbnail.get_pixel(10, 10).0, [0, 0, 0]);
+    }
+}
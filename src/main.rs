@@ -1,103 +1,326 @@
 use actix_cors::Cors;
 use actix_multipart::Multipart;
-use actix_web::{App, HttpResponse, HttpServer, Responder, delete, get, post};
-use db::FileRecord;
+use actix_web::{App, HttpResponse, HttpServer, Responder, delete, get, post, route};
+use db::{FileQuery, FileRecord, StoredVariant, Thumbnail, Variant, Watermarked};
 use futures_util::StreamExt as _;
-use reqwest;
-use std::env;
-use telegram::Bot;
-mod telegram;
-use telegram::api::TelegramBot;
+mod auth;
+mod cli;
+mod config;
+mod content_type;
 mod db;
-use actix_files::NamedFile;
+mod download;
+mod imaging;
+mod probe;
+mod quota;
+mod storage;
+mod telegram;
+use actix_web::http::header::{self, EntityTag};
 use actix_web::web;
+use auth::{Identity, RequireScope, Scope};
 use chrono::Datelike;
 use clap::{Arg, Command};
+use config::{Config, WatermarkApply};
 use db::db::Database;
+use download::{ByteRange, Validators};
+use imaging::transform::{Transform, TransformCache, TransformQuery};
+use imaging::watermark::Watermark;
+use imaging::{Format, similarity};
 use log::{debug, error, info, warn};
+use quota::QuotaError;
+use sha2::{Digest as _, Sha256};
+use std::io;
+use storage::{
+    Backend, ByteStream, ObjectRef, Spool, Storage, StorageBackend, StreamDigest, fill_buffer,
+    is_too_large, limit_stream, stream_once,
+};
 
 #[get("/")]
 async fn hello() -> impl Responder {
     HttpResponse::Ok().body("Hello world!")
 }
 
-#[get("/getUpdates")]
-async fn get_updates() -> impl Responder {
-    let res_string = reqwest::get(
-        "https://api.telegram.org/bot7280383975:AAFAGg3HkTVoiK0ttxwDbQ1FJPQNIWMQA60/getUpdates",
-    )
-    .await
-    .unwrap()
-    .text()
-    .await
-    .unwrap();
-    HttpResponse::Ok().body(res_string)
+#[get("/getUpdates", wrap = "RequireScope(Scope::Admin)")]
+async fn get_updates(storage: web::Data<Storage>) -> impl Responder {
+    let Some(bot) = storage.telegram() else {
+        return HttpResponse::NotFound().json(serde_json::json!({
+            "detail": "Telegram storage is not configured"
+        }));
+    };
+    match bot.get_updates().await {
+        Ok(res_string) => HttpResponse::Ok().body(res_string),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": e.to_string()
+        })),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct UploadQuery {
+    /// Store the file with its EXIF and other metadata, for trusted uploaders
+    #[serde(default)]
+    keep_metadata: bool,
 }
 
-#[post("/upload")]
-async fn upload_file(mut payload: Multipart) -> impl Responder {
-    while let Some(item) = payload.next().await {
+#[post("/upload", wrap = "RequireScope(Scope::Upload)")]
+async fn upload_file(
+    config: web::Data<Config>,
+    db: web::Data<Database>,
+    storage: web::Data<Storage>,
+    watermark: web::Data<Option<Watermark>>,
+    identity: Identity,
+    query: web::Query<UploadQuery>,
+    mut payload: Multipart,
+) -> impl Responder {
+    if query.keep_metadata
+        && !identity.is_admin()
+        && !config.metadata.trusted.contains(&identity.name)
+    {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "message": "Only trusted uploaders can keep metadata"
+        }));
+    }
+    // Refuse before reading anything when the owner has no room left
+    let defaults = config.limits;
+    let owner_id = identity.user_id;
+    let allowance = match db.run(move |db| db.get_quota(owner_id, &defaults)).await {
+        Ok(quota) => quota.allowance(),
+        Err(e) => {
+            error!("Failed to look up quota: {}", e);
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "message": "Failed to check quota",
+                "error": e.to_string()
+            }));
+        }
+    };
+    let allowance = match allowance {
+        Ok(allowance) => allowance,
+        Err(e @ QuotaError::DailyFiles(_)) => {
+            return HttpResponse::TooManyRequests().json(serde_json::json!({
+                "message": "Upload limit reached",
+                "error": e.to_string()
+            }));
+        }
+        Err(e @ QuotaError::StorageFull(_)) => return too_large(&e),
+    };
+    if let Some(item) = payload.next().await {
         match item {
-            Ok(mut field) => {
+            Ok(field) => {
                 let content_disposition = field.content_disposition();
                 let filename = content_disposition
                     .and_then(|cd| cd.get_filename())
                     .map(|f| f.to_string())
                     .unwrap_or_else(|| "uploaded_file".to_string());
 
-                // Save the file to memory
-                let mut file_bytes = Vec::new();
-                while let Some(chunk) = field.next().await {
-                    let data = match chunk {
-                        Ok(data) => data,
+                let now = chrono::Local::now();
+                let current_year: u32 = now.year() as u32;
+                let current_month: u32 = now.month();
+                let current_day: u32 = now.day();
+                let uuid = uuid::Uuid::new_v4().to_string();
+                let key = format!(
+                    "{}/{}/{}/{}",
+                    current_year, current_month, current_day, uuid
+                );
+                // Stream the file straight into the storage backend. Fused because a
+                // multipart field panics if polled again after it has ended.
+                let data: ByteStream = Box::pin(
+                    field
+                        .map(|chunk| chunk.map_err(|e| io::Error::other(e.to_string())))
+                        .fuse(),
+                );
+                // Cut the upload off as soon as it goes over the size limit or the quota
+                let data = match allowance {
+                    Some(allowance) => limit_stream(data, allowance.max_bytes, allowance.message),
+                    None => data,
+                };
+                let (mut data, digest) = StreamDigest::wrap(data);
+                // Detect the type from the first bytes, then put them back in front of the rest
+                let mut head = Vec::new();
+                if let Err(e) = fill_buffer(&mut data, &mut head, content_type::SNIFF_LEN).await {
+                    if e.kind() == io::ErrorKind::FileTooLarge {
+                        return too_large(&e);
+                    }
+                    return HttpResponse::BadRequest().json(serde_json::json!({
+                        "message": "Failed to read upload",
+                        "error": e.to_string()
+                    }));
+                }
+                let mime_type =
+                    content_type::sniff(&head).unwrap_or(content_type::DEFAULT_MIME_TYPE);
+                debug!("Detected {} as {}", filename, mime_type);
+                let data: ByteStream = Box::pin(stream_once(head).chain(data));
+                // Receive the whole file before storing it, so content that is already stored
+                // is never sent to the backend again
+                let spool = match Spool::write(data).await {
+                    Ok(spool) => spool,
+                    Err(e) if e.kind() == io::ErrorKind::FileTooLarge => return too_large(&e),
+                    Err(e) => {
+                        return HttpResponse::BadRequest().json(serde_json::json!({
+                            "message": "Failed to read upload",
+                            "error": e.to_string()
+                        }));
+                    }
+                };
+                let (mut sha256, mut size) = digest.borrow().finish();
+                // Location, camera serials and the like leave with the file unless removed here
+                let mut strip = config.metadata.strip
+                    && !query.keep_metadata
+                    && imaging::metadata::is_strippable(mime_type);
+                // Stripping reads the whole file, so big ones are stored as they are
+                if strip && size > config.metadata.max_size {
+                    warn!(
+                        "Not removing metadata from {}: {} bytes is over metadata.max_size",
+                        key, size
+                    );
+                    strip = false;
+                }
+                // Stripping takes the capture date with it, so that alone is read beforehand
+                let captured_at = if strip {
+                    capture_date(&spool, config.metadata.max_size).await
+                } else {
+                    None
+                };
+                if strip {
+                    match strip_metadata(&spool, mime_type).await {
+                        Ok((stripped_sha256, stripped_size)) => {
+                            sha256 = stripped_sha256;
+                            size = stripped_size;
+                        }
                         Err(e) => {
-                            return HttpResponse::InternalServerError()
-                                .body(format!("Cannot read file: {}", e));
+                            return HttpResponse::BadRequest().json(serde_json::json!({
+                                "message": "Failed to remove metadata",
+                                "error": e.to_string()
+                            }));
                         }
-                    };
-                    file_bytes.extend_from_slice(&data);
+                    }
                 }
-
-                let bot = TelegramBot::new(
-                    &env::var("TG_BOT_TOKEN").expect("TG_BOT_TOKEN must be set"),
-                    &env::var("TG_CHAT_ID").expect("TG_CHAT_ID must be set"),
+                // Probed as stored, so the placeholders and hashes describe what is served
+                let mut media = probe_media(&spool, mime_type, config.metadata.max_size).await;
+                if strip {
+                    media.captured_at = captured_at;
+                }
+                let backend = storage.default_backend();
+                let url = FileRecord::public_url(current_year, current_month, current_day, &uuid);
+                let mut record = FileRecord::new(
+                    filename.clone(),
+                    url.clone(),
+                    current_year,
+                    current_month,
+                    current_day,
+                    uuid,
+                    String::new(),
+                    String::new(),
                 );
-                // Send the file to Telegram
-                match bot.send_document(file_bytes, &filename).await {
-                    Ok(res) => {
-                        let db = Database::new("db.db");
-                        let now = chrono::Local::now();
-                        let current_year: u32 = now.year() as u32;
-                        let current_month: u32 = now.month();
-                        let current_day: u32 = now.day();
-                        let uuid = uuid::Uuid::new_v4().to_string();
-                        db.init_db().unwrap();
-                        if let Ok(row_id) = db.insert_file(FileRecord::new(
-                            filename.clone(),
-                            res.file_url.clone(),   // Placeholder URL
-                            current_year,           // Example year
-                            current_month,          // Example month
-                            current_day,            // Example day
-                            uuid,                   // Placeholder UUID
-                            res.file_id.clone(),    // Use the file_id from the response
-                            res.message_id.clone(), // Placeholder message ID
-                        )) {
-                            return HttpResponse::Ok().json(serde_json::json!({
-                                "message": "File uploaded successfully",
-                                "file_id": res.file_id,
-                                "message_id": res.message_id,
-                                "url": res.file_url,
-                                "row_id": row_id,
-                            }));
+                record.backend = backend.name().to_string();
+                record.sha256 = Some(sha256);
+                record.size = Some(size);
+                record.mime_type = Some(mime_type.to_string());
+                record.owner_id = identity.user_id;
+                let (blurhash, lqip) = (media.blurhash.clone(), media.lqip.clone());
+                let dhash = media.dhash.as_deref().and_then(similarity::from_hex);
+                record.media = media;
+
+                let duplicate = record.clone();
+                let stored = match db.run(move |db| db.insert_duplicate(duplicate)).await {
+                    Ok(Some((row_id, object))) => {
+                        debug!("{} has the same content as a stored file", key);
+                        Ok((row_id, object, true))
+                    }
+                    Ok(None) => {
+                        let data = match spool.read().await {
+                            Ok(data) => data,
+                            Err(e) => {
+                                error!("Failed to read back upload {}: {}", key, e);
+                                return HttpResponse::InternalServerError().json(
+                                    serde_json::json!({
+                                        "message": "Failed to store file",
+                                        "error": e.to_string()
+                                    }),
+                                );
+                            }
+                        };
+                        let object = match backend.put(&key, &filename, data).await {
+                            Ok(object) => object,
+                            Err(e) if is_too_large(e.as_ref()) => return too_large(&e),
+                            Err(e) => {
+                                return HttpResponse::InternalServerError().json(
+                                    serde_json::json!({
+                                        "message": "Failed to store file",
+                                        "error": e.to_string()
+                                    }),
+                                );
+                            }
+                        };
+                        record.file_id = object.key.clone();
+                        record.message_id = object.handle.clone();
+                        let parts = object.parts.clone();
+                        db.run(move |db| db.insert_file(record, &parts))
+                            .await
+                            .map(|row_id| (row_id, object, false))
+                    }
+                    Err(e) => Err(e),
+                };
+                return match stored {
+                    Ok((row_id, object, deduplicated)) => {
+                        // Duplicates share the thumbnails made for the first upload
+                        let thumbnails = if deduplicated {
+                            db.run(move |db| match db.get_file_record_by_id(row_id)? {
+                                Some(FileRecord {
+                                    blob_id: Some(blob_id),
+                                    ..
+                                }) => db.list_thumbnails(blob_id),
+                                _ => Ok(Vec::new()),
+                            })
+                            .await
+                            .map(|thumbnails| thumbnails.iter().map(|t| t.size).collect())
+                            .unwrap_or_default()
+                        } else {
+                            let sizes = store_thumbnails(
+                                &config, &db, backend, &spool, &key, mime_type, row_id,
+                            )
+                            .await;
+                            store_variants(&config, &db, backend, &spool, row_id).await;
+                            if let Some(watermark) = watermark.as_ref() {
+                                store_watermarked(&config, &db, backend, &spool, row_id, watermark)
+                                    .await;
+                            }
+                            sizes
+                        };
+                        let similar = match dhash {
+                            Some(dhash) if config.similarity.warn_on_upload => {
+                                near_duplicates(&config, &db, &identity, dhash, row_id).await
+                            }
+                            _ => Vec::new(),
+                        };
+                        let mut response = serde_json::json!({
+                            "message": "File uploaded successfully",
+                            "file_id": object.key,
+                            "message_id": object.handle,
+                            "url": url,
+                            "mime_type": mime_type,
+                            "row_id": row_id,
+                            "deduplicated": deduplicated,
+                            "thumbnails": thumbnails,
+                            "metadata_stripped": strip,
+                            "blurhash": blurhash,
+                            "lqip": lqip,
+                            "similar": similar,
+                        });
+                        if !similar.is_empty() {
+                            response["warning"] = serde_json::json!(format!(
+                                "Similar to {} image(s) already uploaded",
+                                similar.len()
+                            ));
                         }
+                        HttpResponse::Ok().json(response)
                     }
                     Err(e) => {
-                        return HttpResponse::InternalServerError().json(serde_json::json!({
-                            "message": "Failed to send to Telegram",
+                        error!("Failed to record upload {}: {}", key, e);
+                        HttpResponse::InternalServerError().json(serde_json::json!({
+                            "message": "Failed to record file",
                             "error": e.to_string()
-                        }));
+                        }))
                     }
-                }
+                };
             }
             Err(e) => {
                 return HttpResponse::InternalServerError()
@@ -108,91 +331,1370 @@ async fn upload_file(mut payload: Multipart) -> impl Responder {
     HttpResponse::BadRequest().body("No file field received")
 }
 
-#[get("/files")]
-async fn get_files() -> impl Responder {
-    let db = Database::new("db.db");
-    db.init_db().unwrap();
-    match db.get_all_records() {
-        Ok(records) => HttpResponse::Ok().json(records),
+/// Make and store the configured thumbnails of an uploaded raster image, returning the sizes
+/// stored. Failures are only logged; the upload itself has succeeded.
+async fn store_thumbnails(
+    config: &Config,
+    db: &Database,
+    backend: &Backend,
+    spool: &Spool,
+    key: &str,
+    mime_type: &str,
+    row_id: i64,
+) -> Vec<u32> {
+    let settings = config.thumbnails.clone();
+    if !settings.enabled || !imaging::is_raster(mime_type) {
+        return Vec::new();
+    }
+    // Thumbnails belong to the stored object, which records of the same content share
+    let blob_id = match db.run(move |db| db.get_file_record_by_id(row_id)).await {
+        Ok(Some(FileRecord {
+            blob_id: Some(blob_id),
+            ..
+        })) => blob_id,
+        Ok(_) => return Vec::new(),
+        Err(e) => {
+            warn!("Failed to look up upload {}: {}", row_id, e);
+            return Vec::new();
+        }
+    };
+    let path = spool.path().to_path_buf();
+    let generated = tokio::task::spawn_blocking(move || {
+        let image = imaging::open(&path)?;
+        imaging::thumbnail::generate(&image, &settings.sizes, settings.quality)
+    })
+    .await;
+    let images = match generated {
+        Ok(Ok(images)) => images,
+        Ok(Err(e)) => {
+            warn!("Failed to make thumbnails of {}: {}", key, e);
+            return Vec::new();
+        }
+        Err(e) => {
+            error!("Thumbnail task failed for {}: {}", key, e);
+            return Vec::new();
+        }
+    };
+
+    let mut sizes = Vec::new();
+    for image in images {
+        let thumb_key = format!("{}.thumb{}", key, image.size);
+        let file_name = format!("thumb{}.{}", image.size, image.format.extension());
+        let sha256 = hex::encode(Sha256::digest(&image.data));
+        let byte_size = image.data.len() as u64;
+        let object = match backend
+            .put(&thumb_key, &file_name, stream_once(image.data))
+            .await
+        {
+            Ok(object) => object,
+            Err(e) => {
+                warn!("Failed to store thumbnail {}: {}", thumb_key, e);
+                continue;
+            }
+        };
+        let record = Thumbnail {
+            id: None,
+            blob_id,
+            size: image.size,
+            width: image.width,
+            height: image.height,
+            mime_type: image.format.mime_type().to_string(),
+            byte_size,
+            sha256,
+            file_id: object.key.clone(),
+            message_id: object.handle.clone(),
+            created_at: None,
+        };
+        match db.run(move |db| db.insert_thumbnail(&record)).await {
+            Ok(_) => sizes.push(image.size),
+            Err(e) => {
+                warn!("Failed to record thumbnail {}: {}", thumb_key, e);
+                if let Err(e) = backend.delete(&object).await {
+                    warn!("Failed to delete unrecorded thumbnail {}: {}", thumb_key, e);
+                }
+            }
+        }
+    }
+    debug!("Stored thumbnails of {}: {:?}", key, sizes);
+    sizes
+}
+
+fn too_large(e: &dyn std::fmt::Display) -> HttpResponse {
+    HttpResponse::PayloadTooLarge().json(serde_json::json!({
+        "message": "File too large",
+        "error": e.to_string()
+    }))
+}
+
+#[get("/files", wrap = "RequireScope(Scope::List)")]
+async fn get_files(
+    db: web::Data<Database>,
+    identity: Identity,
+    query: web::Query<FileQuery>,
+) -> impl Responder {
+    let mut query = query.into_inner();
+    query.owned_by = identity.owned_by();
+    match db.run(move |db| db.list_files(&query)).await {
+        Ok(page) => HttpResponse::Ok().json(page),
         Err(e) => HttpResponse::InternalServerError().body(format!("Error fetching files: {}", e)),
     }
 }
 
-#[get("/find/{year}/{month}/{day}/{uuid}")]
-async fn get_file(path: actix_web::web::Path<(u32, u32, u32, String)>) -> impl Responder {
+#[derive(serde::Deserialize)]
+struct SimilarQuery {
+    /// Bits in which perceptual hashes may differ; `similarity.threshold` when absent
+    threshold: Option<u32>,
+}
+
+/// Most files `/files/{id}/similar` returns, closest first.
+const MAX_SIMILAR: usize = 100;
+
+#[get("/files/{id}/similar", wrap = "RequireScope(Scope::List)")]
+async fn get_similar_files(
+    config: web::Data<Config>,
+    db: web::Data<Database>,
+    identity: Identity,
+    path: web::Path<i64>,
+    query: web::Query<SimilarQuery>,
+) -> impl Responder {
+    let id = path.into_inner();
+    let threshold = query.threshold.unwrap_or(config.similarity.threshold);
+    if threshold > u64::BITS {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "message": format!("threshold must be at most {}", u64::BITS)
+        }));
+    }
+    let record = match db.run(move |db| db.get_file_record_by_id(id)).await {
+        // Someone else's file is treated as missing
+        Ok(Some(record)) if identity.can_access(record.owner_id) => record,
+        Ok(_) => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "detail": "File not found in database"
+            }));
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+    let Some(dhash) = record.media.dhash.as_deref().and_then(similarity::from_hex) else {
+        return HttpResponse::UnprocessableEntity().json(serde_json::json!({
+            "message": "File has no perceptual hash; only images have one"
+        }));
+    };
+    let owned_by = identity.owned_by();
+    match db
+        .run(move |db| db.find_similar(dhash, threshold, owned_by, Some(id), MAX_SIMILAR))
+        .await
+    {
+        Ok(similar) => HttpResponse::Ok().json(serde_json::json!({
+            "id": id,
+            "threshold": threshold,
+            "files": similar,
+        })),
+        Err(e) => {
+            error!("Failed to find files like {}: {}", id, e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }
+    }
+}
+
+/// Look up the file a download link points to, and check the caller may download it. Also
+/// returns who the caller is, when that matters for what they are sent.
+async fn find_download(
+    req: &actix_web::HttpRequest,
+    config: &Config,
+    db: &Database,
+    year: u32,
+    month: u32,
+    day: u32,
+    uuid: &str,
+) -> Result<(FileRecord, ObjectRef, Option<Identity>), HttpResponse> {
+    let lookup = {
+        let uuid = uuid.to_string();
+        db.run(
+            move |db| match db.get_record_by_data_and_uuid(year, month, day, &uuid)? {
+                Some(record) => {
+                    let object = db.get_object_ref(&record)?;
+                    Ok(Some((record, object)))
+                }
+                None => Ok(None),
+            },
+        )
+        .await
+    };
+    let (record, object) = match lookup {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            return Err(HttpResponse::NotFound().json(serde_json::json!({
+                "detail": "File not found in database"
+            })));
+        }
+        Err(e) => {
+            return Err(HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            })));
+        }
+    };
+    if config.auth.public_downloads {
+        // Only watermarks tell callers apart; a bad credential just makes them anonymous
+        let identity = if config.watermark.enabled {
+            auth::identify(req).await.ok().flatten()
+        } else {
+            None
+        };
+        return Ok((record, object, identity));
+    }
+    match auth::identify(req).await {
+        Ok(Some(identity)) if identity.can_access(record.owner_id) => {
+            Ok((record, object, Some(identity)))
+        }
+        // Don't reveal that someone else's file exists
+        Ok(Some(_)) => Err(HttpResponse::NotFound().json(serde_json::json!({
+            "detail": "File not found in database"
+        }))),
+        Ok(None) => Err(HttpResponse::Unauthorized().json(serde_json::json!({
+            "message": "Log in or send an API key"
+        }))),
+        Err(response) => Err(response),
+    }
+}
+
+#[route("/find/{year}/{month}/{day}/{uuid}", method = "GET", method = "HEAD")]
+#[allow(clippy::too_many_arguments)]
+async fn get_file(
+    req: actix_web::HttpRequest,
+    config: web::Data<Config>,
+    db: web::Data<Database>,
+    storage: web::Data<Storage>,
+    watermark: web::Data<Option<Watermark>>,
+    conversions: web::Data<Conversions>,
+    path: actix_web::web::Path<(u32, u32, u32, String)>,
+    query: web::Query<TransformQuery>,
+) -> impl Responder {
     let (year, month, day, uuid) = path.into_inner();
+    let transform = match query.resolve(&config.transforms.presets) {
+        Ok(Some(_)) if !config.transforms.enabled => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "message": "Image transforms are disabled"
+            }));
+        }
+        Ok(transform) => transform,
+        Err(message) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "message": message,
+                "presets": config.transforms.presets,
+            }));
+        }
+    };
+    let (record, object, identity) =
+        match find_download(&req, &config, &db, year, month, day, &uuid).await {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+    let Some(backend) = storage.backend(&record.backend) else {
+        error!("Storage backend not configured: {}", record.backend);
+        return HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Storage backend not configured: {}", record.backend)
+        }));
+    };
+
+    let Some(watermark) = watermark
+        .as_ref()
+        .as_ref()
+        .filter(|_| is_watermarkable(&record))
+    else {
+        return serve_original(
+            &req,
+            &config,
+            &db,
+            &storage,
+            &conversions,
+            backend,
+            &record,
+            object,
+            transform,
+        )
+        .await;
+    };
+    let original = skips_watermark(&config, identity.as_ref(), &record);
+    let mut response = if original {
+        serve_original(
+            &req,
+            &config,
+            &db,
+            &storage,
+            &conversions,
+            backend,
+            &record,
+            object,
+            transform,
+        )
+        .await
+    } else {
+        serve_watermarked(
+            &req, &config, &db, backend, &record, &object, watermark, transform,
+        )
+        .await
+    };
+    vary_by_identity(&mut response, original);
+    response
+}
+
+/// Send a file as stored, `transform`ed or in a format the client prefers.
+#[allow(clippy::too_many_arguments)]
+async fn serve_original(
+    req: &actix_web::HttpRequest,
+    config: &web::Data<Config>,
+    db: &web::Data<Database>,
+    storage: &web::Data<Storage>,
+    conversions: &web::Data<Conversions>,
+    backend: &Backend,
+    record: &FileRecord,
+    object: ObjectRef,
+    transform: Option<Transform>,
+) -> HttpResponse {
+    if let Some(transform) = transform {
+        // Files from before hashes were recorded are cached under their UUID instead
+        let source = record.sha256.as_deref().unwrap_or(&record.uuid);
+        return serve_transformed(req, config, backend, record, &object, source, transform).await;
+    }
+
+    // Images are sent as AVIF or WebP to clients that take them, so caches must tell them apart
+    let convertible = has_variants(config, record);
+    let mut download = Download::original(record, object);
+    let formats = if convertible {
+        download::acceptable(req, &config.variants.formats)
+    } else {
+        Vec::new()
+    };
+    // Of the copies already made in formats the client takes, the smallest is sent; the
+    // original goes out while the missing ones are converted
+    let mut variants: Vec<(Format, StoredVariant)> = Vec::new();
+    let mut missing = false;
+    for format in formats {
+        if Some(format.mime_type()) == record.mime_type.as_deref() {
+            continue;
+        }
+        let Some(blob_id) = record.blob_id else {
+            break;
+        };
+        match db.run(move |db| db.get_variant(blob_id, format)).await {
+            Ok(Some(variant)) => {
+                if let Some(stored) = variant.stored {
+                    variants.push((format, stored));
+                }
+            }
+            Ok(None) => missing = true,
+            Err(e) => warn!(
+                "Failed to look up {} copy of {}: {}",
+                format.as_str(),
+                record.uuid,
+                e
+            ),
+        }
+    }
+    if missing {
+        convert_in_background(config, db, storage, conversions, record, &download.object);
+    }
+    let sizes: Vec<u64> = variants.iter().map(|(_, stored)| stored.size).collect();
+    if let Some(index) = download::smallest(record.size, &sizes) {
+        let (format, stored) = variants.swap_remove(index);
+        download = Download::variant(record, format, stored);
+    }
+    let mut response = serve_download(req, backend, download).await;
+    if convertible {
+        response
+            .headers_mut()
+            .append(header::VARY, header::HeaderValue::from_static("Accept"));
+    }
+    response
+}
+
+/// What a download sends: the stored original or a converted copy of it.
+struct Download {
+    object: ObjectRef,
+    size: Option<u64>,
+    mime_type: String,
+    filename: String,
+    validators: Validators,
+}
+
+impl Download {
+    fn original(record: &FileRecord, object: ObjectRef) -> Self {
+        Download {
+            object,
+            size: record.size,
+            mime_type: record
+                .mime_type
+                .clone()
+                .unwrap_or_else(|| content_type::DEFAULT_MIME_TYPE.to_string()),
+            filename: record.filename.clone(),
+            validators: Validators::for_record(record),
+        }
+    }
+
+    fn variant(record: &FileRecord, format: Format, stored: StoredVariant) -> Self {
+        let validators = Validators {
+            etag: Some(EntityTag::new_strong(stored.sha256.clone())),
+            last_modified: Validators::for_record(record).last_modified,
+        };
+        Download {
+            object: stored.object_ref(),
+            size: Some(stored.size),
+            mime_type: format.mime_type().to_string(),
+            filename: with_extension(&record.filename, format),
+            validators,
+        }
+    }
+}
+
+fn with_extension(filename: &str, format: Format) -> String {
+    std::path::Path::new(filename)
+        .with_extension(format.extension())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Send a stored object, answering conditional and range requests.
+async fn serve_download(
+    req: &actix_web::HttpRequest,
+    backend: &Backend,
+    download: Download,
+) -> HttpResponse {
+    let Download {
+        object,
+        size,
+        mime_type,
+        filename,
+        validators,
+    } = download;
+    if validators.not_modified(req) {
+        debug!("{} not modified", filename);
+        let mut response = HttpResponse::NotModified();
+        if let Some(etag) = &validators.etag {
+            response.insert_header(header::ETag(etag.clone()));
+        }
+        return response.finish();
+    }
 
-    let db = Database::new("db.db");
-    db.init_db().unwrap();
-
-    match db.get_record_content(year, month, day, &uuid).await {
-        Ok(content) => HttpResponse::Ok()
-            .content_type("application/octet-stream")
-            .append_header((
-                "Content-Disposition",
-                format!("attachment; filename={}", uuid),
-            ))
-            .body(content),
+    if let Some(url) = backend.presigned_url(&object) {
+        debug!(
+            "Redirecting {} to presigned {} URL",
+            filename,
+            backend.name()
+        );
+        return HttpResponse::Found()
+            .append_header(("Location", url))
+            .finish();
+    }
+
+    // Records from before sizes were stored fall back to asking the backend.
+    let size = match size {
+        Some(size) => Some(size),
+        None => backend.stat(&object).await.ok().and_then(|stat| stat.size),
+    };
+    let range = match size {
+        Some(size) => validators.range(req, size),
+        None => ByteRange::Full,
+    };
+
+    let mut response = match range {
+        ByteRange::Full => HttpResponse::Ok(),
+        ByteRange::Partial { start, len } => {
+            let mut response = HttpResponse::PartialContent();
+            response.insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                range: Some((start, start + len - 1)),
+                instance_length: size,
+            }));
+            response
+        }
+        ByteRange::Unsatisfiable => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: size,
+                }))
+                .finish();
+        }
+    };
+    response
+        .content_type(mime_type.as_str())
+        .insert_header(content_type::content_disposition(
+            &filename,
+            content_type::is_inline(&mime_type),
+        ))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+    if let Some(etag) = validators.etag {
+        response.insert_header(header::ETag(etag));
+    }
+    if let Some(last_modified) = validators.last_modified {
+        response.insert_header(header::LastModified(last_modified));
+    }
+
+    info!(
+        "Fetching content of {} from {} storage",
+        filename,
+        backend.name()
+    );
+    let content = match range {
+        ByteRange::Partial { start, len } => {
+            response.no_chunking(len);
+            backend.get_range(&object, start, len).await
+        }
+        _ => {
+            if let Some(size) = size {
+                response.no_chunking(size);
+            }
+            backend.get(&object).await
+        }
+    };
+    match content {
+        Ok(content) => response.streaming(content),
         Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
             "error": e.to_string()
         })),
     }
 }
 
-#[delete("/del/{file_id}")]
-async fn delete_file(path: actix_web::web::Path<i64>) -> impl Responder {
-    let file_id = path.into_inner();
+/// Whether downloads of a file may be converted to `variants.formats`. Animated GIFs would
+/// lose their animation, so GIFs never are.
+fn has_variants(config: &Config, record: &FileRecord) -> bool {
+    let mime_type = record.mime_type.as_deref().unwrap_or_default();
+    !config.variants.formats.is_empty()
+        && imaging::is_raster(mime_type)
+        && mime_type != "image/gif"
+        && record.blob_id.is_some()
+}
 
-    debug!("Try to delete file_id: {}", file_id);
+/// Whether downloads of a file carry the watermark. Animations would lose their frames, so
+/// only still raster images do.
+fn is_watermarkable(record: &FileRecord) -> bool {
+    let mime_type = record.mime_type.as_deref().unwrap_or_default();
+    imaging::is_raster(mime_type)
+        && mime_type != "image/gif"
+        && record.media.frame_count.is_none_or(|frames| frames <= 1)
+        && record.blob_id.is_some()
+}
 
-    let db = Database::new("db.db");
-    db.init_db().unwrap();
+/// Whether `identity` downloads `record` without the watermark. Without authentication nobody
+/// is known, so everyone gets it.
+fn skips_watermark(config: &Config, identity: Option<&Identity>, record: &FileRecord) -> bool {
+    config.auth.enabled
+        && identity.is_some_and(|identity| identity.skips_watermark(record.owner_id))
+}
 
-    match db.get_file_record_by_id(file_id) {
-        Ok(Some(record)) => {
-            debug!("DB record: {:?}", record);
+/// Mark a download of a watermarked file as depending on who asks. The owner gets the
+/// `original` and everyone else the watermarked copy, so caches must tell them apart and shared
+/// ones may only keep the latter.
+fn vary_by_identity(response: &mut HttpResponse, original: bool) {
+    if original {
+        response.headers_mut().insert(
+            header::CACHE_CONTROL,
+            header::HeaderValue::from_static("private"),
+        );
+    }
+    // In the same header as `Accept`, since the CORS middleware only extends the first one
+    let credentials = "Cookie, Authorization, X-API-Key";
+    let vary = match response.headers().get(header::VARY) {
+        Some(vary) => format!("{}, {}", vary.to_str().unwrap_or_default(), credentials),
+        None => credentials.to_string(),
+    };
+    if let Ok(vary) = header::HeaderValue::from_str(&vary) {
+        response.headers_mut().insert(header::VARY, vary);
+    }
+}
 
-            let tg_file_id = &record.file_id;
-            let tg_message_id = &record.message_id;
+/// Send the watermarked copy of an image, or `transform` of it, making the copy if needed.
+#[allow(clippy::too_many_arguments)]
+async fn serve_watermarked(
+    req: &actix_web::HttpRequest,
+    config: &Config,
+    db: &Database,
+    backend: &Backend,
+    record: &FileRecord,
+    object: &ObjectRef,
+    watermark: &Watermark,
+    transform: Option<Transform>,
+) -> HttpResponse {
+    let stored = match find_watermarked(db, backend, record, object, watermark).await {
+        Ok(stored) => stored,
+        // Never the original instead, which is what the watermark keeps from others
+        Err(e) => {
+            error!("Failed to watermark {}: {}", record.uuid, e);
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "message": "Failed to watermark image",
+                "error": e.to_string()
+            }));
+        }
+    };
+    if let Some(transform) = transform {
+        let object = stored.object_ref();
+        return serve_transformed(
+            req,
+            config,
+            backend,
+            record,
+            &object,
+            &stored.sha256,
+            transform,
+        )
+        .await;
+    }
+    let format = Format::for_source(record.mime_type.as_deref().unwrap_or_default());
+    serve_download(req, backend, Download::variant(record, format, stored)).await
+}
 
-            debug!(
-                "tg_file_id: {}, tg_message_id: {}",
-                tg_file_id, tg_message_id
-            );
+/// The stored watermarked copy of a file's content, making it now if there is none yet.
+async fn find_watermarked(
+    db: &Database,
+    backend: &Backend,
+    record: &FileRecord,
+    object: &ObjectRef,
+    watermark: &Watermark,
+) -> Result<StoredVariant, Box<dyn std::error::Error>> {
+    let blob_id = record
+        .blob_id
+        .ok_or("File has no stored object to watermark")?;
+    let fingerprint = watermark.fingerprint().to_string();
+    let existing = db.run(move |db| db.get_watermarked(blob_id, &fingerprint));
+    if let Some(watermarked) = existing
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?
+    {
+        return Ok(watermarked.stored);
+    }
+    debug!("Watermarking {}", record.uuid);
+    let spool = Spool::write(backend.get(object).await?).await?;
+    make_watermarked(db, backend, record, blob_id, spool.path(), watermark).await
+}
+
+/// Stamp the watermark onto the image at `source`, store the result and record it for the
+/// stored object `blob_id`.
+async fn make_watermarked(
+    db: &Database,
+    backend: &Backend,
+    record: &FileRecord,
+    blob_id: i64,
+    source: &std::path::Path,
+    watermark: &Watermark,
+) -> Result<StoredVariant, Box<dyn std::error::Error>> {
+    let path = source.to_path_buf();
+    let format = Format::for_source(record.mime_type.as_deref().unwrap_or_default());
+    let stamp = watermark.clone();
+    let data = tokio::task::spawn_blocking(move || {
+        let image = imaging::open(&path)?;
+        stamp.render(&image, format)
+    })
+    .await??;
+    let fingerprint = watermark.fingerprint().to_string();
+    let key = format!(
+        "{}/{}/{}/{}-watermark-{}.{}",
+        record.year,
+        record.month,
+        record.day,
+        record.uuid,
+        fingerprint,
+        format.extension()
+    );
+    let sha256 = hex::encode(Sha256::digest(&data));
+    let size = data.len() as u64;
+    let file_name = with_extension(&record.filename, format);
+    let object = backend.put(&key, &file_name, stream_once(data)).await?;
+    let watermarked = Watermarked {
+        blob_id,
+        fingerprint: fingerprint.clone(),
+        stored: StoredVariant {
+            size,
+            sha256,
+            file_id: object.key,
+            message_id: object.handle,
+        },
+    };
+    let recorded = watermarked.clone();
+    let inserted = db.run(move |db| db.insert_watermarked(&recorded));
+    if inserted
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?
+    {
+        return Ok(watermarked.stored);
+    }
+    // Another request watermarked it first; keep theirs
+    if let Err(e) = backend.delete(&watermarked.stored.object_ref()).await {
+        warn!("Failed to delete surplus watermarked copy: {}", e);
+    }
+    db.run(move |db| db.get_watermarked(blob_id, &fingerprint))
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?
+        .map(|watermarked| watermarked.stored)
+        .ok_or_else(|| "Watermarked copy disappeared while stamping".into())
+}
+
+/// Stored objects whose variants are being made in the background.
+#[derive(Default)]
+struct Conversions(std::sync::Mutex<std::collections::HashSet<i64>>);
+
+/// Marks a stored object as being converted until dropped.
+struct Converting {
+    conversions: web::Data<Conversions>,
+    blob_id: i64,
+}
+
+impl Conversions {
+    /// Claim `blob_id`, unless another task is already converting it.
+    fn start(conversions: &web::Data<Conversions>, blob_id: i64) -> Option<Converting> {
+        let mut converting = conversions.0.lock().unwrap_or_else(|e| e.into_inner());
+        converting.insert(blob_id).then(|| Converting {
+            conversions: conversions.clone(),
+            blob_id,
+        })
+    }
+}
 
-            let bot = TelegramBot::new(
-                &env::var("TG_BOT_TOKEN").expect("TG_BOT_TOKEN must be set"),
-                &env::var("TG_CHAT_ID").expect("TG_CHAT_ID must be set"),
+impl Drop for Converting {
+    fn drop(&mut self) {
+        let mut converting = self.conversions.0.lock().unwrap_or_else(|e| e.into_inner());
+        converting.remove(&self.blob_id);
+    }
+}
+
+/// Convert a file's content to every `variants.formats` not tried yet, after the response
+/// has been sent. The original is fetched once for all of them.
+fn convert_in_background(
+    config: &web::Data<Config>,
+    db: &web::Data<Database>,
+    storage: &web::Data<Storage>,
+    conversions: &web::Data<Conversions>,
+    record: &FileRecord,
+    object: &ObjectRef,
+) {
+    let Some(blob_id) = record.blob_id else {
+        return;
+    };
+    let Some(converting) = Conversions::start(conversions, blob_id) else {
+        return;
+    };
+    let (config, db, storage) = (config.clone(), db.clone(), storage.clone());
+    let (record, object) = (record.clone(), object.clone());
+    actix_web::rt::spawn(async move {
+        let _converting = converting;
+        let mut formats = Vec::new();
+        for &format in &config.variants.formats {
+            if Some(format.mime_type()) == record.mime_type.as_deref() {
+                continue;
+            }
+            match db.run(move |db| db.get_variant(blob_id, format)).await {
+                Ok(None) => formats.push(format),
+                Ok(Some(_)) => {}
+                Err(e) => warn!(
+                    "Failed to look up {} copy of {}: {}",
+                    format.as_str(),
+                    record.uuid,
+                    e
+                ),
+            }
+        }
+        if formats.is_empty() {
+            return;
+        }
+        let Some(backend) = storage.backend(&record.backend) else {
+            return;
+        };
+        debug!("Converting {} in the background", record.uuid);
+        let spool: Result<Spool, Box<dyn std::error::Error>> = match backend.get(&object).await {
+            Ok(data) => Spool::write(data).await.map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        match spool {
+            Ok(spool) => {
+                make_variants(&config, &db, backend, &record, blob_id, &spool, &formats).await
+            }
+            Err(e) => warn!("Failed to fetch {} for converting: {}", record.uuid, e),
+        }
+    });
+}
+
+/// Convert the image at `source` to `format`, store the result if it is smaller than the
+/// original and record either outcome for the stored object `blob_id`.
+async fn make_variant(
+    config: &Config,
+    db: &Database,
+    backend: &Backend,
+    record: &FileRecord,
+    blob_id: i64,
+    source: &std::path::Path,
+    format: Format,
+) -> Result<Variant, Box<dyn std::error::Error>> {
+    let path = source.to_path_buf();
+    let quality = config.variants.quality;
+    let data = tokio::task::spawn_blocking(move || {
+        let image = imaging::open(&path)?;
+        imaging::encode(&image, format, quality)
+    })
+    .await??;
+    let size = data.len() as u64;
+    let stored = if record.size.is_none_or(|original| size < original) {
+        let key = format!(
+            "{}/{}/{}/{}.{}",
+            record.year,
+            record.month,
+            record.day,
+            record.uuid,
+            format.extension()
+        );
+        let sha256 = hex::encode(Sha256::digest(&data));
+        let file_name = with_extension(&record.filename, format);
+        let object = backend.put(&key, &file_name, stream_once(data)).await?;
+        Some(StoredVariant {
+            size,
+            sha256,
+            file_id: object.key,
+            message_id: object.handle,
+        })
+    } else {
+        None
+    };
+    let variant = Variant {
+        id: None,
+        blob_id,
+        format,
+        stored,
+        created_at: None,
+    };
+    let recorded = variant.clone();
+    let inserted = db.run(move |db| db.insert_variant(&recorded));
+    if inserted
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?
+    {
+        return Ok(variant);
+    }
+    // Another request converted it first; keep theirs
+    if let Some(stored) = &variant.stored
+        && let Err(e) = backend.delete(&stored.object_ref()).await
+    {
+        warn!("Failed to delete surplus {} copy: {}", format.as_str(), e);
+    }
+    db.run(move |db| db.get_variant(blob_id, format))
+        .await
+        .map_err(|e| e as Box<dyn std::error::Error>)?
+        .ok_or_else(|| "Variant disappeared while converting".into())
+}
+
+/// Dimensions, duration and the like of the upload in `spool`.
+async fn probe_media(spool: &Spool, mime_type: &'static str, max_size: u64) -> probe::MediaInfo {
+    let path = spool.path().to_path_buf();
+    tokio::task::spawn_blocking(move || probe::probe(&path, mime_type, max_size))
+        .await
+        .unwrap_or_default()
+}
+
+/// When the photo in `spool` was taken, according to its EXIF.
+async fn capture_date(spool: &Spool, max_size: u64) -> Option<String> {
+    let path = spool.path().to_path_buf();
+    tokio::task::spawn_blocking(move || probe::captured_at(&path, max_size))
+        .await
+        .unwrap_or_default()
+}
+
+/// Files the uploader can see that look like the image just stored as `row_id`, for the upload
+/// response. Failures only cost the warning.
+async fn near_duplicates(
+    config: &Config,
+    db: &Database,
+    identity: &Identity,
+    dhash: u64,
+    row_id: i64,
+) -> Vec<serde_json::Value> {
+    let threshold = config.similarity.threshold;
+    let owned_by = identity.owned_by();
+    let found = db
+        .run(move |db| db.find_similar(dhash, threshold, owned_by, Some(row_id), 10))
+        .await;
+    match found {
+        Ok(similar) => similar
+            .into_iter()
+            .map(|s| {
+                serde_json::json!({
+                    "id": s.file.id,
+                    "filename": s.file.filename,
+                    "url": s.file.url,
+                    "distance": s.distance,
+                })
+            })
+            .collect(),
+        Err(e) => {
+            warn!("Failed to look for images like upload {}: {}", row_id, e);
+            Vec::new()
+        }
+    }
+}
+
+/// Remove the metadata of the image in `spool`, returning the hash and size of what is left.
+async fn strip_metadata(spool: &Spool, mime_type: &'static str) -> io::Result<(String, u64)> {
+    let path = spool.path().to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let data = std::fs::read(&path)?;
+        let stripped = imaging::metadata::strip(mime_type, &data)?;
+        if stripped != data {
+            std::fs::write(&path, &stripped)?;
+        }
+        Ok((
+            hex::encode(Sha256::digest(&stripped)),
+            stripped.len() as u64,
+        ))
+    })
+    .await?
+}
+
+/// Convert a new upload to every `variants.formats`, when that is done eagerly.
+async fn store_variants(
+    config: &Config,
+    db: &Database,
+    backend: &Backend,
+    spool: &Spool,
+    row_id: i64,
+) {
+    if !config.variants.eager {
+        return;
+    }
+    let record = match db.run(move |db| db.get_file_record_by_id(row_id)).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Failed to look up upload {}: {}", row_id, e);
+            return;
+        }
+    };
+    let Some(blob_id) = record.blob_id.filter(|_| has_variants(config, &record)) else {
+        return;
+    };
+    let formats: Vec<Format> = (config.variants.formats.iter().copied())
+        .filter(|format| Some(format.mime_type()) != record.mime_type.as_deref())
+        .collect();
+    make_variants(config, db, backend, &record, blob_id, spool, &formats).await;
+}
+
+/// Convert the upload in `spool` to each of `formats`, logging the ones that fail.
+async fn make_variants(
+    config: &Config,
+    db: &Database,
+    backend: &Backend,
+    record: &FileRecord,
+    blob_id: i64,
+    spool: &Spool,
+    formats: &[Format],
+) {
+    for &format in formats {
+        if let Err(e) =
+            make_variant(config, db, backend, record, blob_id, spool.path(), format).await
+        {
+            warn!(
+                "Failed to convert {} to {}: {}",
+                record.uuid,
+                format.as_str(),
+                e
             );
+        }
+    }
+}
 
-            let chat_id = env::var("TG_CHAT_ID").expect("TG_CHAT_ID must be set");
-            debug!("chat_id: {}", chat_id);
+/// Watermark a new upload, when that is done at upload rather than on first download.
+async fn store_watermarked(
+    config: &Config,
+    db: &Database,
+    backend: &Backend,
+    spool: &Spool,
+    row_id: i64,
+    watermark: &Watermark,
+) {
+    if config.watermark.apply != WatermarkApply::Upload {
+        return;
+    }
+    let record = match db.run(move |db| db.get_file_record_by_id(row_id)).await {
+        Ok(Some(record)) => record,
+        Ok(None) => return,
+        Err(e) => {
+            warn!("Failed to look up upload {}: {}", row_id, e);
+            return;
+        }
+    };
+    let Some(blob_id) = record.blob_id.filter(|_| is_watermarkable(&record)) else {
+        return;
+    };
+    if let Err(e) = make_watermarked(db, backend, &record, blob_id, spool.path(), watermark).await {
+        warn!("Failed to watermark {}: {}", record.uuid, e);
+    }
+}
 
-            if !tg_message_id.is_empty() {
-                debug!(
-                    "Try to delete telegram message: chat_id={}, message_id={}",
-                    chat_id, tg_message_id
-                );
-                match bot.delete_message(tg_message_id.clone()).await {
-                    Ok(_) => debug!("Telegram message deleted."),
-                    Err(e) => debug!("Telegram message delete failed: {}", e),
+#[derive(serde::Deserialize)]
+struct ThumbQuery {
+    size: Option<u32>,
+}
+
+#[route("/thumb/{year}/{month}/{day}/{uuid}", method = "GET", method = "HEAD")]
+async fn get_thumbnail(
+    req: actix_web::HttpRequest,
+    config: web::Data<Config>,
+    db: web::Data<Database>,
+    storage: web::Data<Storage>,
+    watermark: web::Data<Option<Watermark>>,
+    path: actix_web::web::Path<(u32, u32, u32, String)>,
+    query: web::Query<ThumbQuery>,
+) -> impl Responder {
+    let (year, month, day, uuid) = path.into_inner();
+    let sizes = &config.thumbnails.sizes;
+    // Only the configured sizes, so links can't ask for arbitrary renderings
+    let size = match query.size {
+        Some(size) if sizes.contains(&size) => size,
+        Some(size) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "message": format!("Unsupported thumbnail size {}", size),
+                "sizes": sizes,
+            }));
+        }
+        None => sizes.iter().copied().min().unwrap_or_default(),
+    };
+    let (record, object, identity) =
+        match find_download(&req, &config, &db, year, month, day, &uuid).await {
+            Ok(found) => found,
+            Err(response) => return response,
+        };
+    let Some(blob_id) = record.blob_id else {
+        return HttpResponse::NotFound().finish();
+    };
+    let thumbnail = match db.run(move |db| db.get_thumbnail(blob_id, size)).await {
+        Ok(Some(thumbnail)) => thumbnail,
+        Ok(None) => {
+            return HttpResponse::NotFound().json(serde_json::json!({
+                "detail": "No thumbnail for this file"
+            }));
+        }
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+    let Some(backend) = storage.backend(&record.backend) else {
+        error!("Storage backend not configured: {}", record.backend);
+        return HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": format!("Storage backend not configured: {}", record.backend)
+        }));
+    };
+
+    let Some(watermark) = watermark
+        .as_ref()
+        .as_ref()
+        .filter(|_| is_watermarkable(&record))
+    else {
+        return serve_thumbnail(&req, &config, backend, &thumbnail).await;
+    };
+    let original = skips_watermark(&config, identity.as_ref(), &record);
+    let mut response = if original {
+        serve_thumbnail(&req, &config, backend, &thumbnail).await
+    } else {
+        // Rendered from the watermarked copy, so no thumbnail size gets around the mark
+        let format = Format::for_source(&thumbnail.mime_type);
+        let quality = config.thumbnails.quality;
+        let transform = Transform::thumbnail(thumbnail.size, format, quality);
+        serve_watermarked(
+            &req,
+            &config,
+            &db,
+            backend,
+            &record,
+            &object,
+            watermark,
+            Some(transform),
+        )
+        .await
+    };
+    vary_by_identity(&mut response, original);
+    response
+}
+
+/// Send a stored thumbnail, answering conditional requests.
+async fn serve_thumbnail(
+    req: &actix_web::HttpRequest,
+    config: &Config,
+    backend: &Backend,
+    thumbnail: &Thumbnail,
+) -> HttpResponse {
+    let cache_control = derived_cache_control(config);
+    let validators = Validators::for_thumbnail(thumbnail);
+    let not_modified = validators.not_modified(req);
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.insert_header(cache_control);
+    if let Some(etag) = validators.etag {
+        response.insert_header(header::ETag(etag));
+    }
+    if not_modified {
+        return response.finish();
+    }
+    if let Some(last_modified) = validators.last_modified {
+        response.insert_header(header::LastModified(last_modified));
+    }
+    response
+        .content_type(thumbnail.mime_type.as_str())
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .no_chunking(thumbnail.byte_size);
+    match backend.get(&thumbnail.object_ref()).await {
+        Ok(content) => response.streaming(content),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": e.to_string()
+        })),
+    }
+}
+
+/// Seconds clients may use a thumbnail or rendering before checking it is still current.
+const DERIVED_MAX_AGE: u32 = 24 * 60 * 60;
+
+/// `Cache-Control` of images derived from a file. Shared caches may keep them only when anyone
+/// may download the file.
+fn derived_cache_control(config: &Config) -> header::CacheControl {
+    let visibility = if config.auth.public_downloads {
+        header::CacheDirective::Public
+    } else {
+        header::CacheDirective::Private
+    };
+    header::CacheControl(vec![
+        visibility,
+        header::CacheDirective::MaxAge(DERIVED_MAX_AGE),
+    ])
+}
+
+/// Serve `transform` of an image, rendering it into the transform cache first if needed.
+async fn serve_transformed(
+    req: &actix_web::HttpRequest,
+    config: &Config,
+    backend: &Backend,
+    record: &FileRecord,
+    object: &ObjectRef,
+    source: &str,
+    transform: Transform,
+) -> HttpResponse {
+    let mime_type = record
+        .mime_type
+        .as_deref()
+        .unwrap_or(content_type::DEFAULT_MIME_TYPE);
+    if !imaging::is_raster(mime_type) {
+        return HttpResponse::BadRequest().json(serde_json::json!({
+            "message": "Only PNG, JPEG, GIF, WebP and BMP images can be transformed"
+        }));
+    }
+    let settings = &config.transforms;
+    let format = transform
+        .format
+        .unwrap_or_else(|| Format::for_source(mime_type));
+    let quality = transform.q.unwrap_or(settings.quality);
+    let name = transform.cache_name(format, quality);
+    let cache = TransformCache::new(&settings.cache_dir);
+    let path = cache.path(source, &name);
+
+    let validators = Validators {
+        etag: Some(EntityTag::new_strong(format!("{}-{}", source, name))),
+        last_modified: Validators::for_record(record).last_modified,
+    };
+    let not_modified = validators.not_modified(req);
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    response.insert_header(derived_cache_control(config));
+    if let Some(etag) = validators.etag {
+        response.insert_header(header::ETag(etag));
+    }
+    if not_modified {
+        return response.finish();
+    }
+
+    if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        debug!("Rendering {} of {}", name, record.uuid);
+        let rendered = render_transform(backend, object, transform, format, quality).await;
+        if let Err(e) = match rendered {
+            Ok(data) => cache.store(&path, &data).await.map_err(Into::into),
+            Err(e) => Err(e),
+        } {
+            error!("Failed to render {} of {}: {}", name, record.uuid, e);
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "message": "Failed to transform image",
+                "error": e.to_string()
+            }));
+        }
+    }
+    let file = match tokio::fs::File::open(&path).await {
+        Ok(file) => file,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            }));
+        }
+    };
+    if let Ok(metadata) = file.metadata().await {
+        response.no_chunking(metadata.len());
+    }
+    if let Some(last_modified) = validators.last_modified {
+        response.insert_header(header::LastModified(last_modified));
+    }
+    let filename = std::path::Path::new(&record.filename)
+        .with_extension(format.extension())
+        .to_string_lossy()
+        .into_owned();
+    response
+        .content_type(format.mime_type())
+        .insert_header(content_type::content_disposition(&filename, true))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .streaming(tokio_util::io::ReaderStream::new(file))
+}
+
+/// Fetch the original from its backend and render `transform` of it.
+async fn render_transform(
+    backend: &Backend,
+    object: &ObjectRef,
+    transform: Transform,
+    format: Format,
+    quality: u8,
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let spool = Spool::write(backend.get(object).await?).await?;
+    let source = spool.path().to_path_buf();
+    let rendered = tokio::task::spawn_blocking(move || {
+        let image = imaging::open(&source)?;
+        transform.render(&image, format, quality)
+    })
+    .await??;
+    Ok(rendered)
+}
+
+#[delete("/del/{file_id}", wrap = "RequireScope(Scope::Delete)")]
+async fn delete_file(
+    config: web::Data<Config>,
+    db: web::Data<Database>,
+    storage: web::Data<Storage>,
+    identity: Identity,
+    path: actix_web::web::Path<i64>,
+) -> impl Responder {
+    let file_id = path.into_inner();
+
+    debug!("Try to delete file_id: {}", file_id);
+
+    let lookup = db
+        .run(move |db| match db.get_file_record_by_id(file_id)? {
+            // Someone else's file is treated as missing
+            Some(record) if identity.can_access(record.owner_id) => {
+                let object = db.get_object_ref(&record)?;
+                let mut derived: Vec<ObjectRef> = Vec::new();
+                // Renderings are cached by the content they were made from
+                let mut sources =
+                    vec![record.sha256.clone().unwrap_or_else(|| record.uuid.clone())];
+                if let Some(blob_id) = record.blob_id {
+                    derived.extend(
+                        db.list_thumbnails(blob_id)?
+                            .iter()
+                            .map(Thumbnail::object_ref),
+                    );
+                    derived.extend(
+                        db.list_variants(blob_id)?
+                            .iter()
+                            .filter_map(|v| v.stored.as_ref().map(StoredVariant::object_ref)),
+                    );
+                    for watermarked in db.list_watermarked(blob_id)? {
+                        derived.push(watermarked.stored.object_ref());
+                        sources.push(watermarked.stored.sha256);
+                    }
                 }
+                Ok(Some((record, object, derived, sources)))
             }
+            _ => Ok(None),
+        })
+        .await;
+    match lookup {
+        Ok(Some((record, object, derived, sources))) => {
+            debug!("DB record: {:?}", record);
 
-            match db.del_record_by_id(file_id) {
-                Ok(_) => {
-                    debug!("DB record deleted: {}", file_id);
-                    HttpResponse::Ok().json(serde_json::json!({
-                        "message": "Deleted (db+telegram)"
-                    }))
+            // The stored object goes once no other record shares it
+            let remaining = match db.run(move |db| db.del_record_by_id(file_id)).await {
+                Ok(Some(remaining)) => remaining,
+                Ok(None) => {
+                    return HttpResponse::NotFound().json(serde_json::json!({
+                        "detail": "File not found in database"
+                    }));
                 }
                 Err(e) => {
                     error!("Delete failed: {}", e);
-                    HttpResponse::InternalServerError().json(serde_json::json!({
+                    return HttpResponse::InternalServerError().json(serde_json::json!({
                         "error": e.to_string()
-                    }))
+                    }));
+                }
+            };
+            debug!("DB record deleted: {}", file_id);
+            if remaining > 0 {
+                return HttpResponse::Ok().json(serde_json::json!({
+                    "message": format!(
+                        "Deleted (db; {} other references keep the stored file)",
+                        remaining
+                    )
+                }));
+            }
+
+            // The record is gone, so objects that can't be removed now are only found through
+            // the log and the response
+            let mut orphaned = Vec::new();
+            match storage.backend(&record.backend) {
+                Some(backend) => {
+                    // The original, then thumbnails and converted copies
+                    for object in std::iter::once(&object).chain(&derived) {
+                        match backend.delete(object).await {
+                            Ok(_) => debug!("Deleted {} from {}", object.key, record.backend),
+                            Err(e) => {
+                                error!(
+                                    "Failed to delete {} from {}, which is now orphaned: {}",
+                                    object.key, record.backend, e
+                                );
+                                orphaned.push(object.key.clone());
+                            }
+                        }
+                    }
+                }
+                None => {
+                    orphaned.extend(
+                        std::iter::once(&object)
+                            .chain(&derived)
+                            .map(|o| o.key.clone()),
+                    );
+                    error!(
+                        "Storage backend not configured: {}; orphaned {}",
+                        record.backend,
+                        orphaned.join(", ")
+                    );
                 }
             }
+            let cache = TransformCache::new(&config.transforms.cache_dir);
+            for source in &sources {
+                cache.remove(source).await;
+            }
+            if !orphaned.is_empty() {
+                return HttpResponse::Ok().json(serde_json::json!({
+                    "message": format!(
+                        "Deleted (db; {} stored object(s) could not be removed from {})",
+                        orphaned.len(),
+                        record.backend
+                    ),
+                    "orphaned": orphaned,
+                }));
+            }
+            HttpResponse::Ok().json(serde_json::json!({
+                "message": format!("Deleted (db+{})", record.backend)
+            }))
         }
         Ok(None) => {
             error!("File not found in database: {}", file_id);
@@ -209,13 +1711,144 @@ async fn delete_file(path: actix_web::web::Path<i64>) -> impl Responder {
     }
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    unsafe {
-        std::env::set_var("RUST_LOG", "debug");
+#[derive(serde::Deserialize)]
+struct UsageQuery {
+    /// Whose usage to report; only admins may ask about other users
+    user: Option<String>,
+}
+
+/// Storage used by the requester, or by `?user=` for admins, against their limits.
+#[get("/usage")]
+async fn usage(
+    req: actix_web::HttpRequest,
+    config: web::Data<Config>,
+    db: web::Data<Database>,
+    query: web::Query<UsageQuery>,
+) -> impl Responder {
+    let identity = match auth::identify(&req).await {
+        Ok(Some(identity)) => identity,
+        Ok(None) => {
+            return HttpResponse::Unauthorized().json(serde_json::json!({
+                "message": "Log in or send an API key"
+            }));
+        }
+        Err(response) => return response,
+    };
+    let username = query.into_inner().user;
+    if username.is_some() && !identity.is_admin() {
+        return HttpResponse::Forbidden().json(serde_json::json!({
+            "message": "Only admins can see other users' usage"
+        }));
+    }
+    let defaults = config.limits;
+    let lookup = db
+        .run(move |db| {
+            let owner_id = match username {
+                Some(username) => match db.get_user_by_username(&username)? {
+                    Some(user) => Some(user.id),
+                    None => return Ok(None),
+                },
+                None => identity.user_id,
+            };
+            Ok(Some((owner_id, db.get_quota(owner_id, &defaults)?)))
+        })
+        .await;
+    match lookup {
+        Ok(Some((owner_id, quota))) => HttpResponse::Ok().json(serde_json::json!({
+            "user_id": owner_id,
+            "limits": quota.limits,
+            "usage": quota.usage,
+            "remaining_bytes": quota.remaining_bytes(),
+            "remaining_files_today": quota.remaining_files_today(),
+        })),
+        Ok(None) => HttpResponse::NotFound().json(serde_json::json!({
+            "detail": "No such user"
+        })),
+        Err(e) => HttpResponse::InternalServerError().json(serde_json::json!({
+            "error": e.to_string()
+        })),
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+#[post("/login")]
+async fn login(
+    req: actix_web::HttpRequest,
+    config: web::Data<Config>,
+    db: web::Data<Database>,
+    form: web::Json<LoginForm>,
+) -> impl Responder {
+    let LoginForm { username, password } = form.into_inner();
+    let ttl = config.auth.session_ttl;
+    // Password hashing is slow on purpose, so it runs on the blocking pool too
+    let result = db
+        .run(move |db| {
+            let login = db.get_login(&username)?;
+            let hash = login.as_ref().map(|(_, hash)| hash.as_str());
+            if !auth::verify_password(&password, hash) {
+                return Ok(None);
+            }
+            let (user, _) = login.expect("verified against its hash");
+            let token = auth::generate_token();
+            db.create_session(&auth::hash_key(&token), user.id, ttl)?;
+            Ok(Some((user, token)))
+        })
+        .await;
+    match result {
+        Ok(Some((user, token))) => {
+            info!("{} logged in", user.username);
+            HttpResponse::Ok()
+                .cookie(auth::session_cookie(&req, token, ttl))
+                .json(user)
+        }
+        Ok(None) => HttpResponse::Unauthorized().json(serde_json::json!({
+            "message": "Invalid username or password"
+        })),
+        Err(e) => {
+            error!("Login failed: {}", e);
+            HttpResponse::InternalServerError().json(serde_json::json!({
+                "error": e.to_string()
+            }))
+        }
+    }
+}
+
+#[post("/logout")]
+async fn logout(req: actix_web::HttpRequest, db: web::Data<Database>) -> impl Responder {
+    if let Some(cookie) = req.cookie(auth::SESSION_COOKIE) {
+        let hash = auth::hash_key(cookie.value());
+        if let Err(e) = db.run(move |db| db.delete_session(&hash)).await {
+            error!("Failed to end session: {}", e);
+        }
+    }
+    HttpResponse::Ok()
+        .cookie(auth::removal_cookie())
+        .json(serde_json::json!({ "message": "Logged out" }))
+}
+
+/// Who the request is authenticated as, so clients can tell whether to show a login form.
+#[get("/me")]
+async fn me(req: actix_web::HttpRequest) -> impl Responder {
+    match auth::identify(&req).await {
+        Ok(Some(identity)) => HttpResponse::Ok().json(serde_json::json!({
+            "name": identity.name,
+            "user_id": identity.user_id,
+            "admin": identity.is_admin(),
+        })),
+        Ok(None) => HttpResponse::Unauthorized().json(serde_json::json!({
+            "message": "Not logged in"
+        })),
+        Err(response) => response,
     }
-    env_logger::init();
+}
 
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
     // Parse command line arguments using clap
     let matches = Command::new("rusty-img-hosting")
         .version("1.0")
@@ -225,95 +1858,142 @@ async fn main() -> std::io::Result<()> {
                 .short('c')
                 .long("config")
                 .num_args(1)
-                .help("Sets a custom config file"),
+                .help("Sets a custom config file; built-in defaults are used without one"),
         )
+        .subcommand(cli::migrate_command())
+        .subcommand(cli::keys_command())
+        .subcommand(cli::users_command())
+        .subcommand(cli::backfill_command())
         .get_matches();
 
-    let config_path = matches.get_one::<String>("config").unwrap();
-    info!("Using config file: {}", config_path);
-
-    let config_content = std::fs::read_to_string(config_path).expect("Failed to read config file");
-    let config: toml::Value = toml::from_str(&config_content).expect("Failed to parse config file");
-    info!("Loaded config: {:?}", config);
-    if let Some(v) = config.get("TG_BOT_TOKEN") {
-        info!("TG_BOT_TOKEN is set in the config file");
-        unsafe {
-            env::set_var("TG_BOT_TOKEN", v.as_str().unwrap());
+    // `.env` may hold RIH_* overrides, like the real environment
+    dotenv::dotenv().ok();
+    let config_path = matches.get_one::<String>("config");
+    let config = match Config::load(config_path.map(String::as_str)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
         }
+    };
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(&config.logging.level),
+    )
+    .init();
+    match config_path {
+        Some(path) => info!("Using config file: {}", path),
+        None => info!("No config file given, using defaults"),
     }
-    if let Some(v) = config.get("TG_CHAT_ID") {
-        info!("TG_CHAT_ID is set in the config file");
-        unsafe {
-            env::set_var("TG_CHAT_ID", v.as_str().unwrap());
-        }
+    for warning in &config.warnings {
+        warn!("{}", warning);
     }
 
-    let custom_port = config
-        .get("PORT")
-        .and_then(|v| v.as_integer())
-        .unwrap_or(8000) as u16;
+    match matches.subcommand() {
+        Some(("migrate", matches)) => {
+            if let Err(e) = cli::migrate(matches, &config) {
+                error!("Migration failed: {}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(("keys", matches)) => {
+            if let Err(e) = cli::keys(matches, &config) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(("users", matches)) => {
+            if let Err(e) = cli::users(matches, &config) {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(("backfill", _)) => {
+            if let Err(e) = cli::backfill(&config).await {
+                error!("Backfill failed: {}", e);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
 
-    let custom_listen_address = config
-        .get("LISTEN_ADDRESS")
-        .and_then(|v| v.as_str())
-        .unwrap_or("0.0.0.0");
+    let storage = match Storage::from_config(&config.storage) {
+        Ok(storage) => web::Data::new(storage),
+        Err(e) => {
+            error!("Failed to set up storage backend: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let db = match Database::open(&config.database.path) {
+        Ok(db) => web::Data::new(db),
+        Err(e) => {
+            error!("Failed to open database {}: {}", config.database.path, e);
+            std::process::exit(1);
+        }
+    };
+    if !config.auth.enabled {
+        warn!("Authentication is disabled; anyone can upload, list and delete files");
+    } else if db.list_users().is_ok_and(|users| users.is_empty())
+        && db
+            .list_api_keys()
+            .is_ok_and(|keys| keys.iter().all(|key| key.revoked_at.is_some()))
+    {
+        warn!(
+            "No users or API keys exist yet; create an admin with `users create --username <name> --admin`"
+        );
+    }
+    let watermark = match config.watermark.load() {
+        Ok(watermark) => web::Data::new(watermark),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let conversions = web::Data::new(Conversions::default());
+    let bind_address = (config.server.listen_address.clone(), config.server.port);
+    let config = web::Data::new(config);
 
-    HttpServer::new(|| {
-        let cors = Cors::default()
-            .allow_any_origin()
-            .allowed_methods(vec!["GET", "POST", "DELETE"])
-            .allowed_headers(vec![actix_web::http::header::CONTENT_TYPE])
-            .supports_credentials();
+    HttpServer::new(move || {
+        // Keys travel in headers, not cookies, so no origin is sent credentials
+        let mut cors = Cors::default()
+            .allowed_methods(vec!["GET", "HEAD", "POST", "DELETE"])
+            .allowed_headers(vec![
+                header::CONTENT_TYPE,
+                header::AUTHORIZATION,
+                header::HeaderName::from_static(auth::API_KEY_HEADER),
+            ]);
+        if config.server.cors_origins.is_empty() {
+            cors = cors.allow_any_origin();
+        }
+        for origin in &config.server.cors_origins {
+            cors = cors.allowed_origin(origin);
+        }
 
         App::new()
             .wrap(cors)
+            .app_data(config.clone())
+            .app_data(db.clone())
+            .app_data(storage.clone())
+            .app_data(watermark.clone())
+            .app_data(conversions.clone())
             .service(get_updates)
             .service(upload_file)
             .service(get_files)
+            .service(get_similar_files)
             .service(get_file)
+            .service(get_thumbnail)
             .service(delete_file)
+            .service(login)
+            .service(logout)
+            .service(me)
+            .service(usage)
             // Serve static files (js, css, etc.) from src/public/ as the last fallback
             .service(actix_files::Files::new("/", "src/public").index_file("index.html"))
     })
-    .bind((custom_listen_address, custom_port))?
+    .bind(bind_address)?
     .run()
     .await
 }
-
-// mod telegram;
-// use telegram::api::TelegramBot;
-// mod db;
-// use db::db::Database;
-
-// use crate::telegram::Bot;
-// use dotenv::dotenv;
-// use env_logger;
-// #[allow(unused_imports)]
-// use log::{Level, debug, error, info, warn};
-// use std::{env, fs::File};
-
-// #[tokio::main]
-// async fn main() {
-//     unsafe {
-//         std::env::set_var("RUST_LOG", "debug");
-//     }
-//     dotenv().ok();
-//     env_logger::init();
-
-//     // let token = env::var("TG_BOT_TOKEN").unwrap_or_else(|e| {
-//     //     error!("TG_BOT_TOKEN must be set in the environment: {}", e);
-//     //     std::process::exit(1);
-//     // });
-//     // let chat_id = env::var("TG_CHAT_ID").unwrap_or_else(|e| {
-//     //     error!("TG_CHAT_ID must be set in the environment: {}", e);
-//     //     std::process::exit(1);
-//     // });
-//     // let bot = TelegramBot::new(&token, &chat_id);
-//     // let result = bot
-//     //     .send_document(File::open("Cargo.toml").unwrap(), "Cargo.toml")
-//     //     .await
-//     //     .unwrap();
-//     // println!("{:?}", result);
-
-//     let db = Database::new("db.db").init_db().unwrap();
-// }
@@ -0,0 +1,740 @@
+//! What an upload holds beyond its type: pixel size, running time, frames, colour and when it
+//! was taken. Everything is best effort; what can't be read is left out.
+
+use crate::imaging;
+use crate::imaging::metadata::iso_boxes;
+use crate::imaging::{placeholder, similarity};
+use image::{DynamicImage, GenericImageView as _, ImageDecoder as _, ImageReader};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::{Cursor, Read as _, Seek as _, SeekFrom};
+use std::path::Path;
+
+/// Details of an image, video or audio file, recorded at upload.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MediaInfo {
+    /// Pixel size as displayed, after any rotation the file asks for
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Running time of video and audio, in seconds
+    pub duration: Option<f64>,
+    /// Number of frames of animated images
+    pub frame_count: Option<u32>,
+    /// Most common colour of an image, as `#rrggbb`
+    pub dominant_color: Option<String>,
+    /// When a photo was taken according to its EXIF, as `YYYY-MM-DD HH:MM:SS` in the camera's
+    /// local time
+    pub captured_at: Option<String>,
+    /// BlurHash of an image, to draw while it loads
+    pub blurhash: Option<String>,
+    /// A copy of an image a few pixels across, as a `data:` URI
+    pub lqip: Option<String>,
+    /// Perceptual hash of an image, as 16 hex digits; close for near-identical images
+    pub dhash: Option<String>,
+}
+
+/// Bytes read from the start of video and audio files, which keep their headers there.
+const HEAD_LEN: u64 = 1 << 20;
+/// Largest MP4 `moov` box read; it indexes every sample, so long videos have big ones.
+const MAX_MOOV_LEN: u64 = 64 << 20;
+
+/// Read what can be told about the file at `path`, of detected type `mime_type`. Images are
+/// read whole, so those over `max_image_size` bytes are skipped.
+pub fn probe(path: &Path, mime_type: &str, max_image_size: u64) -> MediaInfo {
+    let info = match mime_type {
+        _ if imaging::is_raster(mime_type) => probe_image(path, mime_type, max_image_size),
+        "video/mp4" | "video/x-m4v" | "video/quicktime" | "audio/m4a" => probe_iso(path),
+        "video/webm" | "video/x-matroska" => read_head(path).map(|head| probe_matroska(&head)),
+        "audio/x-wav" => read_head(path).map(|head| probe_wav(&head)),
+        "audio/x-flac" => read_head(path).map(|head| probe_flac(&head)),
+        _ => Ok(MediaInfo::default()),
+    };
+    info.unwrap_or_else(|e| {
+        debug!("Could not probe {} as {}: {}", path.display(), mime_type, e);
+        MediaInfo::default()
+    })
+}
+
+fn read_head(path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut head = Vec::new();
+    File::open(path)?.take(HEAD_LEN).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+fn probe_image(path: &Path, mime_type: &str, max_size: u64) -> Result<MediaInfo, Box<dyn Error>> {
+    let size = std::fs::metadata(path)?.len();
+    if size > max_size {
+        warn!(
+            "Not probing {}: {} bytes is over metadata.max_size",
+            path.display(),
+            size
+        );
+        return Ok(MediaInfo::default());
+    }
+    let data = std::fs::read(path)?;
+    let mut decoder = ImageReader::new(Cursor::new(&data))
+        .with_guessed_format()?
+        .into_decoder()?;
+    let exif = decoder.exif_metadata().ok().flatten();
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(MediaInfo {
+        width: Some(image.width()),
+        height: Some(image.height()),
+        frame_count: frame_count(mime_type, &data).filter(|frames| *frames > 1),
+        dominant_color: dominant_color(&image),
+        captured_at: exif.as_deref().and_then(capture_date),
+        blurhash: Some(placeholder::blurhash(&image)),
+        lqip: placeholder::lqip(&image),
+        dhash: Some(similarity::to_hex(similarity::dhash(&image))),
+        ..Default::default()
+    })
+}
+
+/// When the photo at `path` was taken, read on its own from the EXIF alone so it can be kept
+/// for an upload whose metadata is about to be removed. Skipped like `probe` over `max_size`.
+pub fn captured_at(path: &Path, max_size: u64) -> Option<String> {
+    if std::fs::metadata(path).ok()?.len() > max_size {
+        return None;
+    }
+    let mut decoder = ImageReader::open(path)
+        .ok()?
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let exif = decoder.exif_metadata().ok().flatten()?;
+    capture_date(&exif)
+}
+
+/// The most common colour, counted in buckets of 16 shades per channel on a small copy and
+/// averaged within the winning bucket. Mostly transparent pixels don't count.
+pub fn dominant_color(image: &DynamicImage) -> Option<String> {
+    let small = image.thumbnail(64, 64);
+    let mut buckets = vec![(0u32, [0u32; 3]); 16 * 16 * 16];
+    for (_, _, pixel) in small.pixels() {
+        let [r, g, b, a] = pixel.0;
+        if a < 128 {
+            continue;
+        }
+        let bucket =
+            &mut buckets[usize::from(r >> 4) << 8 | usize::from(g >> 4) << 4 | usize::from(b >> 4)];
+        bucket.0 += 1;
+        for (sum, value) in bucket.1.iter_mut().zip([r, g, b]) {
+            *sum += u32::from(value);
+        }
+    }
+    // Ties go to the first bucket, so the answer doesn't depend on iteration order
+    let (count, sums) = buckets
+        .iter()
+        .rev()
+        .max_by_key(|(count, _)| *count)
+        .filter(|(count, _)| *count > 0)?;
+    let [r, g, b] = sums.map(|sum| sum / count);
+    Some(format!("#{:02x}{:02x}{:02x}", r, g, b))
+}
+
+/// Frames in a GIF, APNG or animated WebP; still images count one.
+fn frame_count(mime_type: &str, data: &[u8]) -> Option<u32> {
+    match mime_type {
+        "image/gif" => gif_frames(data),
+        "image/png" => Some(png_frames(data).unwrap_or(1)),
+        "image/webp" => Some(webp_frames(data).max(1)),
+        _ => None,
+    }
+}
+
+/// Count the image descriptors of a GIF, skipping everything else block by block.
+fn gif_frames(data: &[u8]) -> Option<u32> {
+    let color_table = |flags: u8| {
+        if flags & 0x80 != 0 {
+            3 << ((flags & 7) + 1)
+        } else {
+            0
+        }
+    };
+    let skip_sub_blocks = |mut pos: usize| {
+        loop {
+            let len = usize::from(*data.get(pos)?);
+            pos += 1 + len;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    };
+    let mut pos = 13 + color_table(*data.get(10)?);
+    let mut frames = 0;
+    loop {
+        match *data.get(pos)? {
+            0x2C => {
+                frames += 1;
+                pos += 10 + color_table(*data.get(pos + 9)?);
+                // LZW minimum code size, then the image data
+                pos = skip_sub_blocks(pos + 1)?;
+            }
+            0x21 => pos = skip_sub_blocks(pos + 2)?,
+            0x3B => return Some(frames),
+            _ => return None,
+        }
+    }
+}
+
+/// Frames an APNG declares in its `acTL` chunk; `None` for plain PNGs.
+fn png_frames(data: &[u8]) -> Option<u32> {
+    let mut pos = 8;
+    while let Some(header) = data.get(pos..pos + 8) {
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        match &header[4..] {
+            b"acTL" => {
+                let frames = data.get(pos + 8..pos + 12)?;
+                return Some(u32::from_be_bytes(frames.try_into().unwrap()));
+            }
+            b"IDAT" => return None,
+            _ => pos += 12 + len,
+        }
+    }
+    None
+}
+
+/// Number of `ANMF` chunks of a WebP, which is zero for still images.
+fn webp_frames(data: &[u8]) -> u32 {
+    let mut frames = 0;
+    let mut pos = 12;
+    while let Some(header) = data.get(pos..pos + 8) {
+        if &header[..4] == b"ANMF" {
+            frames += 1;
+        }
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        pos += (8 + len + 1) & !1;
+    }
+    frames
+}
+
+/// Big- or little-endian fields of a TIFF structure, the body of an EXIF block.
+struct Tiff<'a> {
+    data: &'a [u8],
+    big_endian: bool,
+}
+
+impl Tiff<'_> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let bytes = self.data.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let bytes = self.data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Count and position of the value (or of the offset to it) of `tag` in the IFD at `ifd`.
+    fn entry(&self, ifd: usize, tag: u16) -> Option<(usize, usize)> {
+        let entries = usize::from(self.u16(ifd)?);
+        (0..entries)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&at| self.u16(at) == Some(tag))
+            .and_then(|at| Some((self.u32(at + 4)? as usize, at + 8)))
+    }
+
+    fn ascii(&self, ifd: usize, tag: u16) -> Option<&str> {
+        let (count, at) = self.entry(ifd, tag)?;
+        let start = if count > 4 {
+            self.u32(at)? as usize
+        } else {
+            at
+        };
+        let text = self.data.get(start..start + count)?;
+        std::str::from_utf8(text)
+            .ok()
+            .map(|s| s.trim_end_matches('\0'))
+    }
+}
+
+const EXIF_IFD_TAG: u16 = 0x8769;
+const DATE_TIME_ORIGINAL_TAG: u16 = 0x9003;
+const DATE_TIME_TAG: u16 = 0x0132;
+
+/// When the photo was taken: EXIF's `DateTimeOriginal`, or failing that `DateTime`.
+pub fn capture_date(tiff: &[u8]) -> Option<String> {
+    let tiff = Tiff {
+        data: tiff,
+        big_endian: match tiff.get(..4)? {
+            b"MM\0\x2a" => true,
+            b"II\x2a\0" => false,
+            _ => return None,
+        },
+    };
+    let ifd0 = tiff.u32(4)? as usize;
+    let original = tiff
+        .entry(ifd0, EXIF_IFD_TAG)
+        .and_then(|(_, at)| tiff.u32(at))
+        .and_then(|exif_ifd| tiff.ascii(exif_ifd as usize, DATE_TIME_ORIGINAL_TAG));
+    let text = original.or_else(|| tiff.ascii(ifd0, DATE_TIME_TAG))?;
+    // Cameras without a clock write zeros or blanks
+    let time = chrono::NaiveDateTime::parse_from_str(text, "%Y:%m:%d %H:%M:%S").ok()?;
+    Some(time.format("%Y-%m-%d %H:%M:%S").to_string())
+}
+
+/// MP4 and QuickTime: the running time from `mvhd` and the picture size from the first video
+/// track's `tkhd`. `moov` can sit after the media data, so boxes are skipped rather than read.
+fn probe_iso(path: &Path) -> Result<MediaInfo, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut pos = 0;
+    while pos + 8 <= len {
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header[..8])?;
+        let kind: [u8; 4] = header[4..8].try_into().unwrap();
+        let (size, header_len) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            0 => (len - pos, 8),
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (u64::from_be_bytes(header[8..].try_into().unwrap()), 16)
+            }
+            size => (u64::from(size), 8),
+        };
+        if size < header_len {
+            return Err("malformed box".into());
+        }
+        if &kind == b"moov" {
+            if size > MAX_MOOV_LEN {
+                return Err("moov box too large".into());
+            }
+            let mut moov = vec![0; (size - header_len) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(parse_moov(&moov)?);
+        }
+        pos += size;
+    }
+    Err("no moov box".into())
+}
+
+fn parse_moov(moov: &[u8]) -> std::io::Result<MediaInfo> {
+    let u32_at = |at: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(moov.get(at..at + 4)?.try_into().ok()?))
+    };
+    let u64_at = |at: usize| -> Option<u64> {
+        Some(u64::from_be_bytes(moov.get(at..at + 8)?.try_into().ok()?))
+    };
+    let mut info = MediaInfo::default();
+    for child in iso_boxes(moov, 0, moov.len())? {
+        match &child.kind {
+            b"mvhd" => {
+                let (timescale, duration) = if moov.get(child.start) == Some(&1) {
+                    (u32_at(child.start + 20), u64_at(child.start + 24))
+                } else {
+                    (
+                        u32_at(child.start + 12),
+                        u32_at(child.start + 16).map(u64::from),
+                    )
+                };
+                // All ones means unknown
+                info.duration = match (timescale, duration) {
+                    (Some(timescale), Some(duration))
+                        if timescale > 0
+                            && duration != u64::MAX
+                            && duration != u64::from(u32::MAX) =>
+                    {
+                        Some(duration as f64 / f64::from(timescale))
+                    }
+                    _ => None,
+                };
+            }
+            b"trak" if info.width.is_none() => {
+                let Some(tkhd) = iso_boxes(moov, child.start, child.end)?
+                    .into_iter()
+                    .find(|b| &b.kind == b"tkhd")
+                else {
+                    continue;
+                };
+                if tkhd.end - tkhd.start < 84 {
+                    continue;
+                }
+                // Width and height end the box, as 16.16 fixed point after the 3x3 matrix
+                let (Some(width), Some(height)) = (u32_at(tkhd.end - 8), u32_at(tkhd.end - 4))
+                else {
+                    continue;
+                };
+                let (width, height) = (width >> 16, height >> 16);
+                if width == 0 || height == 0 {
+                    continue;
+                }
+                // Phones record portrait video as landscape turned a quarter by the matrix
+                let quarter_turn =
+                    u32_at(tkhd.end - 44) == Some(0) && u32_at(tkhd.end - 40) != Some(0);
+                (info.width, info.height) = if quarter_turn {
+                    (Some(height), Some(width))
+                } else {
+                    (Some(width), Some(height))
+                };
+            }
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+const SEGMENT_ID: u32 = 0x1853_8067;
+const INFO_ID: u32 = 0x1549_A966;
+const TIMECODE_SCALE_ID: u32 = 0x2A_D7B1;
+const DURATION_ID: u32 = 0x4489;
+const TRACKS_ID: u32 = 0x1654_AE6B;
+const TRACK_ENTRY_ID: u32 = 0xAE;
+const VIDEO_ID: u32 = 0xE0;
+const PIXEL_WIDTH_ID: u32 = 0xB0;
+const PIXEL_HEIGHT_ID: u32 = 0xBA;
+
+/// An EBML element: its ID, and where its body lies. Elements of unknown size run to the end of
+/// their parent, and bodies are cut short at the end of what was read.
+struct Element {
+    id: u32,
+    start: usize,
+    end: usize,
+}
+
+/// A variable-length EBML number at `pos`: its value with the length marker removed (`None`
+/// when all ones, meaning unknown), and its length.
+fn ebml_vint(data: &[u8], pos: usize, keep_marker: bool) -> Option<(Option<u64>, usize)> {
+    let first = *data.get(pos)?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+    let mut value = u64::from(if keep_marker {
+        first
+    } else {
+        first & (0xFF >> len)
+    });
+    for byte in data.get(pos + 1..pos + len)? {
+        value = value << 8 | u64::from(*byte);
+    }
+    let unknown = !keep_marker && value == (1 << (7 * len)) - 1;
+    Some(((!unknown).then_some(value), len))
+}
+
+fn ebml_children(data: &[u8], start: usize, end: usize) -> Vec<Element> {
+    let mut children = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        let Some((Some(id), id_len)) = ebml_vint(data, pos, true) else {
+            break;
+        };
+        let Some((size, size_len)) = ebml_vint(data, pos + id_len, false) else {
+            break;
+        };
+        let body = pos + id_len + size_len;
+        let body_end = size.map_or(end, |size| body.saturating_add(size as usize));
+        children.push(Element {
+            id: id as u32,
+            start: body.min(end),
+            end: body_end.min(end),
+        });
+        pos = body_end;
+    }
+    children
+}
+
+/// WebM and Matroska: the running time from the segment info and the picture size from the
+/// first video track.
+fn probe_matroska(head: &[u8]) -> MediaInfo {
+    let uint = |e: &Element| {
+        head[e.start..e.end]
+            .iter()
+            .fold(0u64, |v, b| v << 8 | u64::from(*b))
+    };
+    let mut info = MediaInfo::default();
+    let Some(segment) = ebml_children(head, 0, head.len())
+        .into_iter()
+        .find(|e| e.id == SEGMENT_ID)
+    else {
+        return info;
+    };
+    for child in ebml_children(head, segment.start, segment.end) {
+        match child.id {
+            INFO_ID => {
+                let fields = ebml_children(head, child.start, child.end);
+                let scale = fields
+                    .iter()
+                    .find(|e| e.id == TIMECODE_SCALE_ID)
+                    .map_or(1_000_000, uint);
+                info.duration = fields.iter().find(|e| e.id == DURATION_ID).and_then(|e| {
+                    let ticks = match &head[e.start..e.end] {
+                        bytes @ [_, _, _, _] => {
+                            f64::from(f32::from_be_bytes(bytes.try_into().unwrap()))
+                        }
+                        bytes @ [_, _, _, _, _, _, _, _] => {
+                            f64::from_be_bytes(bytes.try_into().unwrap())
+                        }
+                        _ => return None,
+                    };
+                    Some(ticks * scale as f64 / 1e9)
+                });
+            }
+            TRACKS_ID if info.width.is_none() => {
+                let video = ebml_children(head, child.start, child.end)
+                    .into_iter()
+                    .filter(|e| e.id == TRACK_ENTRY_ID)
+                    .find_map(|entry| {
+                        ebml_children(head, entry.start, entry.end)
+                            .into_iter()
+                            .find(|e| e.id == VIDEO_ID)
+                    });
+                if let Some(video) = video {
+                    let fields = ebml_children(head, video.start, video.end);
+                    let field = |id| fields.iter().find(|e| e.id == id).map(|e| uint(e) as u32);
+                    info.width = field(PIXEL_WIDTH_ID);
+                    info.height = field(PIXEL_HEIGHT_ID);
+                }
+            }
+            _ => {}
+        }
+    }
+    info
+}
+
+/// WAV: the size of the sample data over the bytes per second of the format.
+fn probe_wav(head: &[u8]) -> MediaInfo {
+    let mut byte_rate = None;
+    let mut pos = 12;
+    while let Some(header) = head.get(pos..pos + 8) {
+        let len = u32::from_le_bytes(header[4..].try_into().unwrap());
+        match &header[..4] {
+            b"fmt " => {
+                byte_rate = head
+                    .get(pos + 16..pos + 20)
+                    .map(|rate| u32::from_le_bytes(rate.try_into().unwrap()))
+            }
+            b"data" => {
+                return MediaInfo {
+                    duration: byte_rate
+                        .filter(|rate| *rate > 0)
+                        .map(|rate| f64::from(len) / f64::from(rate)),
+                    ..Default::default()
+                };
+            }
+            _ => {}
+        }
+        pos += (8 + len as usize + 1) & !1;
+    }
+    MediaInfo::default()
+}
+
+/// FLAC: total samples over the sample rate, from the `STREAMINFO` block that comes first.
+fn probe_flac(head: &[u8]) -> MediaInfo {
+    // "fLaC", the block header, then 10 bytes of block and frame sizes
+    let Some(info) = head.get(18..26) else {
+        return MediaInfo::default();
+    };
+    let rate = u32::from(info[0]) << 12 | u32::from(info[1]) << 4 | u32::from(info[2]) >> 4;
+    let samples = u64::from(info[3] & 0x0F) << 32
+        | u64::from(u32::from_be_bytes(info[4..8].try_into().unwrap()));
+    MediaInfo {
+        duration: (rate > 0 && samples > 0).then(|| samples as f64 / f64::from(rate)),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, Frame, Rgba, RgbaImage};
+
+    fn temp_file(data: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("rih-probe-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_probe_image() {
+        // Mostly red, with a blue stripe
+        let mut image = RgbaImage::from_pixel(128, 64, Rgba([200, 10, 10, 255]));
+        for x in 0..128 {
+            for y in 0..16 {
+                image.put_pixel(x, y, Rgba([10, 10, 200, 255]));
+            }
+        }
+        let image = DynamicImage::ImageRgba8(image);
+        let data = imaging::encode(&image, imaging::Format::Png, 80).unwrap();
+        let path = temp_file(&data);
+        let info = probe(&path, "image/png", 1 << 20);
+        let skipped = probe(&path, "image/png", data.len() as u64 - 1);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(skipped.width, None);
+        assert_eq!((info.width, info.height), (Some(128), Some(64)));
+        assert_eq!(info.dominant_color.as_deref(), Some("#c80a0a"));
+        assert!(info.blurhash.is_some());
+        assert!(info.lqip.is_some());
+        assert_eq!(info.dhash.as_deref().map(str::len), Some(16));
+        assert_eq!(info.frame_count, None);
+        assert_eq!(info.duration, None);
+
+        let clear = DynamicImage::ImageRgba8(RgbaImage::new(4, 4));
+        assert_eq!(dominant_color(&clear), None);
+    }
+
+    #[test]
+    fn test_gif_frames() {
+        let mut data = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut data);
+            let frames = (0..3).map(|i| {
+                let image = RgbaImage::from_pixel(4, 4, Rgba([i * 80, 0, 0, 255]));
+                Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(100, 1))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        assert_eq!(frame_count("image/gif", &data), Some(3));
+        assert_eq!(frame_count("image/gif", &data[..data.len() - 1]), None);
+        assert_eq!(frame_count("image/jpeg", &data), None);
+    }
+
+    /// IFD0 with DateTime and a pointer to the EXIF IFD holding DateTimeOriginal
+    fn dated_tiff() -> Vec<u8> {
+        let mut tiff = b"II\x2a\0\x08\0\0\0".to_vec();
+        tiff.extend_from_slice(&[2, 0]);
+        tiff.extend_from_slice(&[0x32, 0x01, 2, 0, 20, 0, 0, 0, 38, 0, 0, 0]);
+        tiff.extend_from_slice(&[0x69, 0x87, 4, 0, 1, 0, 0, 0, 58, 0, 0, 0]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(b"2024:05:06 07:08:09\0");
+        tiff.extend_from_slice(&[1, 0]);
+        tiff.extend_from_slice(&[0x03, 0x90, 2, 0, 20, 0, 0, 0, 76, 0, 0, 0]);
+        tiff.extend_from_slice(&[0, 0, 0, 0]);
+        tiff.extend_from_slice(b"2024:05:01 12:00:00\0");
+        tiff
+    }
+
+    #[test]
+    fn test_capture_date() {
+        let mut tiff = dated_tiff();
+        assert_eq!(capture_date(&tiff).as_deref(), Some("2024-05-01 12:00:00"));
+        // Only DateTime
+        tiff[22] = 0;
+        assert_eq!(capture_date(&tiff).as_deref(), Some("2024-05-06 07:08:09"));
+        assert_eq!(capture_date(b"not tiff"), None);
+    }
+
+    #[test]
+    fn test_captured_at() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 4, Rgba([1, 2, 3, 255])));
+        let plain = imaging::encode(&image, imaging::Format::Jpeg, 80).unwrap();
+        let body = [&b"Exif\0\0"[..], &dated_tiff()].concat();
+        let mut app1 = vec![0xFF, 0xE1];
+        app1.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+        app1.extend_from_slice(&body);
+        let tagged = [&plain[..2], &app1, &plain[2..]].concat();
+        let path = temp_file(&tagged);
+        let date = captured_at(&path, 1 << 20);
+        let skipped = captured_at(&path, tagged.len() as u64 - 1);
+        // What the upload is probed for once its metadata is gone
+        let stripped = imaging::metadata::strip("image/jpeg", &tagged).unwrap();
+        std::fs::write(&path, &stripped).unwrap();
+        let after = probe(&path, "image/jpeg", 1 << 20);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(date.as_deref(), Some("2024-05-01 12:00:00"));
+        assert_eq!(skipped, None);
+        assert_eq!(after.captured_at, None);
+        assert_eq!((after.width, after.height), (Some(8), Some(4)));
+    }
+
+    fn iso_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        [&((body.len() + 8) as u32).to_be_bytes()[..], kind, body].concat()
+    }
+
+    #[test]
+    fn test_probe_iso() {
+        let mut mvhd = vec![0; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&12_500u32.to_be_bytes());
+        // A portrait phone video: 1920x1080 turned a quarter
+        let mut tkhd = vec![0; 84];
+        tkhd[40..44].copy_from_slice(&0u32.to_be_bytes());
+        tkhd[44..48].copy_from_slice(&0x0001_0000u32.to_be_bytes());
+        tkhd[76..80].copy_from_slice(&(1920u32 << 16).to_be_bytes());
+        tkhd[80..84].copy_from_slice(&(1080u32 << 16).to_be_bytes());
+        let moov = iso_box(
+            b"moov",
+            &[
+                iso_box(b"mvhd", &mvhd),
+                iso_box(b"trak", &iso_box(b"tkhd", &tkhd)),
+            ]
+            .concat(),
+        );
+        // The media data comes first, as when a recording is written out
+        let file = [
+            iso_box(b"ftyp", b"isom\0\0\0\0"),
+            iso_box(b"mdat", &[7; 500]),
+            moov,
+        ]
+        .concat();
+        let path = temp_file(&file);
+        let info = probe(&path, "video/mp4", 0);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(info.duration, Some(12.5));
+        assert_eq!((info.width, info.height), (Some(1080), Some(1920)));
+    }
+
+    #[test]
+    fn test_probe_matroska() {
+        let element = |id: &[u8], body: &[u8]| [id, &[0x80 | body.len() as u8], body].concat();
+        let info = element(
+            &[0x15, 0x49, 0xA9, 0x66],
+            &[
+                element(&[0x2A, 0xD7, 0xB1], &[0x0F, 0x42, 0x40]),
+                element(&[0x44, 0x89], &3000f64.to_be_bytes()),
+            ]
+            .concat(),
+        );
+        let video = element(
+            &[0xE0],
+            &[
+                element(&[0xB0], &[0x02, 0x80]),
+                element(&[0xBA], &[0x01, 0xE0]),
+            ]
+            .concat(),
+        );
+        let tracks = element(&[0x16, 0x54, 0xAE, 0x6B], &element(&[0xAE], &video));
+        // A segment of unknown size, as live recordings write
+        let file = [
+            element(&[0x1A, 0x45, 0xDF, 0xA3], &[]),
+            vec![0x18, 0x53, 0x80, 0x67, 0xFF],
+            info,
+            tracks,
+        ]
+        .concat();
+        let info = probe_matroska(&file);
+        assert_eq!(info.duration, Some(3.0));
+        assert_eq!((info.width, info.height), (Some(640), Some(480)));
+    }
+
+    #[test]
+    fn test_probe_audio() {
+        let mut wav = b"RIFF\0\0\0\0WAVEfmt \x10\0\0\0".to_vec();
+        wav.extend_from_slice(&[
+            1, 0, 1, 0, 0x44, 0xAC, 0, 0, 0x88, 0x58, 0x01, 0, 2, 0, 16, 0,
+        ]);
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(88_200u32 * 2).to_le_bytes());
+        assert_eq!(probe_wav(&wav).duration, Some(2.0));
+
+        let mut flac = b"fLaC\x80\0\0\x22".to_vec();
+        flac.extend_from_slice(&[0; 10]);
+        // 44100 Hz, stereo, 16 bits, 441000 samples
+        flac.extend_from_slice(&[0x0A, 0xC4, 0x42, 0xF0, 0x00, 0x06, 0xBA, 0xA8]);
+        assert_eq!(probe_flac(&flac).duration, Some(10.0));
+        assert_eq!(probe_flac(b"fLaC").duration, None);
+    }
+}
@@ -3,6 +3,56 @@
 
 const api_base = window.location.origin;
 
+// Logged-in browsers carry a session cookie, which same-origin requests send automatically
+const loginForm = document.getElementById("loginForm");
+const userRow = document.getElementById("userRow");
+
+function showUser(me) {
+  loginForm.style.display = me ? "none" : "";
+  userRow.style.display = me ? "" : "none";
+  document.getElementById("userName").textContent = me
+    ? `Signed in as ${me.name}${me.admin ? " (admin)" : ""}`
+    : "";
+}
+
+async function checkLogin() {
+  const response = await fetch(`${api_base}/me`);
+  showUser(response.ok ? await response.json() : null);
+  fetchFiles();
+}
+
+loginForm.addEventListener("submit", async (e) => {
+  e.preventDefault();
+  const response = await fetch(`${api_base}/login`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({
+      username: document.getElementById("username").value,
+      password: document.getElementById("password").value
+    })
+  });
+  if (!response.ok) {
+    alert((await responseError(response, "Login failed")).message);
+    return;
+  }
+  document.getElementById("password").value = "";
+  checkLogin();
+});
+
+document.getElementById("logoutBtn").addEventListener("click", async () => {
+  await fetch(`${api_base}/logout`, { method: "POST" });
+  checkLogin();
+});
+
+// Explain auth failures instead of a generic error
+async function responseError(response, fallback) {
+  if (response.status === 401 || response.status === 403) {
+    const body = await response.json().catch(() => ({}));
+    return new Error(body.message || "Not authorized");
+  }
+  return new Error(fallback);
+}
+
 document.getElementById("uploadBtn").addEventListener("click", async function (e) {
   e.preventDefault(); // Prevent default button behavior
 
@@ -25,12 +75,14 @@ document.getElementById("uploadBtn").addEventListener("click", async function (e
     });
 
     if (!response.ok) {
-      throw new Error("Network request failed");
+      throw await responseError(response, "Network request failed");
     }
 
     const result = await response.json();
 
     if (result.url) {
+      // The server returns a server-relative path; show it as a full link
+      result.url = new URL(result.url, api_base).href;
       resultDiv.innerHTML = `<p>Upload successful! <a href="${result.url}" target="_blank">View Image</a></p>`;
       // Show original URL
 
@@ -53,24 +105,48 @@ document.getElementById("uploadBtn").addEventListener("click", async function (e
   }
 });
 
+const pageSize = 20;
+let pageOffset = 0;
+
 async function fetchFiles() {
   try {
-    const response = await fetch(`${api_base}/files`);
+    const params = new URLSearchParams({ limit: pageSize, offset: pageOffset });
+    const filename = document.getElementById("filenameFilter").value.trim();
+    if (filename) params.set("filename", filename);
+    const response = await fetch(`${api_base}/files?${params}`);
     if (!response.ok) {
-      throw new Error("Failed to fetch files");
+      throw await responseError(response, "Failed to fetch files");
     }
-    const files = await response.json();
+    const page = await response.json();
+    // Deleting the last file on a page leaves it empty; step back to the previous one
+    if (page.files.length === 0 && pageOffset > 0) {
+      pageOffset = Math.max(0, pageOffset - pageSize);
+      return fetchFiles();
+    }
+    const files = page.files;
+    const last = Math.min(page.offset + files.length, page.total);
+    document.getElementById("pageInfo").textContent =
+      page.total ? `${page.offset + 1}-${last} of ${page.total}` : "No files";
+    document.getElementById("prevPage").disabled = page.offset === 0;
+    document.getElementById("nextPage").disabled = last >= page.total;
     const tableBody = document.querySelector("#filesTable tbody");
     tableBody.innerHTML = ""; // Clear existing rows
 
     files.forEach(file => {
       // 动态生成 custom_url，始终用当前域名
       const custom_url = `${window.location.origin}/find/${file.year}/${file.month}/${file.day}/${file.uuid}`;
+      const thumb_url = `${window.location.origin}/thumb/${file.year}/${file.month}/${file.day}/${file.uuid}`;
+      // A blurred copy of the image, or its own colour, fills the cell until the thumbnail arrives
+      const background = [
+        file.lqip ? `url('${file.lqip}') center / cover no-repeat` : "",
+        file.dominant_color || "",
+      ].join(" ").trim();
+      const placeholder = background ? ` style="background:${background}"` : "";
       const row = document.createElement("tr");
       row.innerHTML = `
         <td>${file.id}</td>
         <td>${file.filename}</td>
-        <td><img src="${custom_url}" alt="preview" class="table-preview-img preview-clickable" data-full="${custom_url}"></td>
+        <td><img src="${thumb_url}" alt="preview" class="table-preview-img preview-clickable" data-full="${custom_url}"${placeholder}></td>
         <td>${file.year}</td>
         <td>${file.month}</td>
         <td>${file.day}</td>
@@ -81,6 +157,10 @@ async function fetchFiles() {
       `;
       tableBody.appendChild(row);
     });
+    // Files without a thumbnail (older uploads, non-raster images) preview the original
+    document.querySelectorAll('.table-preview-img').forEach(img => {
+      img.addEventListener('error', () => { img.src = img.dataset.full; }, { once: true });
+    });
     // Bind image click event to show full image in overlay
     document.querySelectorAll('.preview-clickable').forEach(img => {
       img.addEventListener('click', function() {
@@ -99,17 +179,33 @@ async function fetchFiles() {
         if (confirm('Are you sure you want to delete this file?')) {
           const res = await fetch(`${api_base}/del/${id}`, { method: 'DELETE' });
           if (res.ok) fetchFiles();
-          else alert('Delete failed');
+          else alert((await responseError(res, 'Delete failed')).message);
         }
       });
     });
   } catch (error) {
     console.error("Error fetching files:", error);
+    document.getElementById("pageInfo").textContent = error.message;
   }
 }
 
 // Fetch files on page load
-document.addEventListener("DOMContentLoaded", fetchFiles);
+document.addEventListener("DOMContentLoaded", checkLogin);
+
+document.getElementById("prevPage").addEventListener("click", () => {
+  pageOffset = Math.max(0, pageOffset - pageSize);
+  fetchFiles();
+});
+
+document.getElementById("nextPage").addEventListener("click", () => {
+  pageOffset += pageSize;
+  fetchFiles();
+});
+
+document.getElementById("filenameFilter").addEventListener("input", () => {
+  pageOffset = 0;
+  fetchFiles();
+});
 
 // File selection custom logic
 const fileInput = document.getElementById("fileInput");
@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Upload limits. Unset limits don't apply, except in a user's overrides, where they fall back
+/// to the limits in the config.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// Largest single upload in bytes
+    pub max_upload_size: Option<u64>,
+    /// Most bytes one owner may have stored in total
+    pub max_total_bytes: Option<u64>,
+    /// Most files one owner may upload per (UTC) day
+    pub max_files_per_day: Option<u32>,
+}
+
+impl Limits {
+    /// These limits, with unset ones taken from `defaults`.
+    pub fn or(self, defaults: &Limits) -> Limits {
+        Limits {
+            max_upload_size: self.max_upload_size.or(defaults.max_upload_size),
+            max_total_bytes: self.max_total_bytes.or(defaults.max_total_bytes),
+            max_files_per_day: self.max_files_per_day.or(defaults.max_files_per_day),
+        }
+    }
+}
+
+/// What one owner has stored so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Usage {
+    pub files: u64,
+    /// Sum of the recorded sizes; files from before sizes were recorded count as empty
+    pub total_bytes: u64,
+    /// Files uploaded since midnight UTC
+    pub files_today: u64,
+}
+
+/// An owner's effective limits and usage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Quota {
+    pub limits: Limits,
+    pub usage: Usage,
+}
+
+/// How much the next upload may be, and how to explain cutting it off.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Allowance {
+    pub max_bytes: u64,
+    pub message: String,
+}
+
+/// Why an upload can't start at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaError {
+    /// `max_files_per_day` files were already uploaded today
+    DailyFiles(u32),
+    /// `max_total_bytes` are already stored
+    StorageFull(u64),
+}
+
+impl fmt::Display for QuotaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaError::DailyFiles(max) => {
+                write!(f, "Daily limit of {} uploads reached", max)
+            }
+            QuotaError::StorageFull(max) => {
+                write!(f, "Storage quota of {} bytes used up", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for QuotaError {}
+
+impl Quota {
+    pub fn remaining_bytes(&self) -> Option<u64> {
+        self.limits
+            .max_total_bytes
+            .map(|max| max.saturating_sub(self.usage.total_bytes))
+    }
+
+    pub fn remaining_files_today(&self) -> Option<u64> {
+        self.limits
+            .max_files_per_day
+            .map(|max| u64::from(max).saturating_sub(self.usage.files_today))
+    }
+
+    /// Check whether another upload may start, and if so the most bytes it may have;
+    /// `None` when its size is unlimited.
+    pub fn allowance(&self) -> Result<Option<Allowance>, QuotaError> {
+        if let Some(max) = self.limits.max_files_per_day
+            && self.remaining_files_today() == Some(0)
+        {
+            return Err(QuotaError::DailyFiles(max));
+        }
+        let remaining = self.remaining_bytes();
+        if let Some(max) = self.limits.max_total_bytes
+            && remaining == Some(0)
+        {
+            return Err(QuotaError::StorageFull(max));
+        }
+        let by_size = self.limits.max_upload_size.map(|max| Allowance {
+            max_bytes: max,
+            message: format!("Upload exceeds the limit of {} bytes", max),
+        });
+        let by_quota = remaining.map(|remaining| Allowance {
+            max_bytes: remaining,
+            message: format!(
+                "Upload exceeds the {} bytes left of the storage quota",
+                remaining
+            ),
+        });
+        Ok(match (by_size, by_quota) {
+            (Some(size), Some(quota)) if quota.max_bytes < size.max_bytes => Some(quota),
+            (Some(size), _) => Some(size),
+            (None, quota) => quota,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(limits: Limits, total_bytes: u64, files_today: u64) -> Quota {
+        Quota {
+            limits,
+            usage: Usage {
+                files: files_today,
+                total_bytes,
+                files_today,
+            },
+        }
+    }
+
+    #[test]
+    fn test_limits_or() {
+        let defaults = Limits {
+            max_upload_size: Some(10),
+            max_total_bytes: Some(100),
+            max_files_per_day: None,
+        };
+        let overrides = Limits {
+            max_total_bytes: Some(1000),
+            ..Default::default()
+        };
+        assert_eq!(
+            overrides.or(&defaults),
+            Limits {
+                max_upload_size: Some(10),
+                max_total_bytes: Some(1000),
+                max_files_per_day: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_allowance() {
+        assert_eq!(quota(Limits::default(), 500, 50).allowance(), Ok(None));
+
+        let limits = Limits {
+            max_upload_size: Some(100),
+            max_total_bytes: Some(1000),
+            max_files_per_day: Some(3),
+        };
+        // The single-file limit binds while there is room left
+        let allowance = quota(limits, 500, 2).allowance().unwrap().unwrap();
+        assert_eq!(allowance.max_bytes, 100);
+        assert!(allowance.message.contains("limit of 100 bytes"));
+
+        // Then the quota does
+        let allowance = quota(limits, 950, 2).allowance().unwrap().unwrap();
+        assert_eq!(allowance.max_bytes, 50);
+        assert!(allowance.message.contains("50 bytes left"));
+
+        assert_eq!(
+            quota(limits, 1000, 0).allowance(),
+            Err(QuotaError::StorageFull(1000))
+        );
+        assert_eq!(
+            quota(limits, 0, 3).allowance(),
+            Err(QuotaError::DailyFiles(3))
+        );
+        assert_eq!(quota(limits, 1200, 5).remaining_bytes(), Some(0));
+        assert_eq!(quota(limits, 1200, 5).remaining_files_today(), Some(0));
+    }
+}
@@ -0,0 +1,155 @@
+use super::{ByteStream, ObjectRef, ObjectStat, StorageBackend};
+use futures_util::StreamExt as _;
+use log::debug;
+use std::path::{Component, Path, PathBuf};
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _};
+use tokio_util::io::ReaderStream;
+
+/// Stores objects as plain files under `root`, using the `{year}/{month}/{day}/{uuid}` layout.
+pub struct LocalStorage {
+    root: PathBuf,
+}
+
+impl LocalStorage {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        LocalStorage { root: root.into() }
+    }
+
+    /// Resolve `key` to a path under the storage root, rejecting anything that could escape it.
+    fn path_for(&self, key: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let relative = Path::new(key);
+        if key.is_empty()
+            || !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(format!("Invalid local storage key: {}", key).into());
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl StorageBackend for LocalStorage {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        _file_name: &str,
+        mut data: ByteStream,
+    ) -> Result<ObjectRef, Box<dyn std::error::Error>> {
+        let path = self.path_for(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write under a temporary name so a failed upload never leaves a truncated object.
+        let partial = path.with_extension("partial");
+        debug!("Writing {}", partial.display());
+        let written: std::io::Result<u64> = async {
+            let mut file = tokio::fs::File::create(&partial).await?;
+            let mut written = 0;
+            while let Some(chunk) = data.next().await {
+                let chunk = chunk?;
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+            }
+            file.flush().await?;
+            Ok(written)
+        }
+        .await;
+        match written {
+            Ok(written) => debug!("Wrote {} bytes to {}", written, path.display()),
+            Err(e) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(e.into());
+            }
+        }
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(ObjectRef {
+            key: key.to_string(),
+            handle: String::new(),
+            parts: Vec::new(),
+        })
+    }
+
+    async fn get(&self, object: &ObjectRef) -> Result<ByteStream, Box<dyn std::error::Error>> {
+        let path = self.path_for(&object.key)?;
+        debug!("Reading {}", path.display());
+        let file = tokio::fs::File::open(&path).await?;
+        Ok(Box::pin(ReaderStream::new(file)))
+    }
+
+    async fn get_range(
+        &self,
+        object: &ObjectRef,
+        start: u64,
+        len: u64,
+    ) -> Result<ByteStream, Box<dyn std::error::Error>> {
+        let path = self.path_for(&object.key)?;
+        debug!("Reading {} bytes at {} from {}", len, start, path.display());
+        let mut file = tokio::fs::File::open(&path).await?;
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        Ok(Box::pin(ReaderStream::new(file.take(len))))
+    }
+
+    async fn delete(&self, object: &ObjectRef) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.path_for(&object.key)?;
+        debug!("Removing {}", path.display());
+        tokio::fs::remove_file(&path).await?;
+        Ok(())
+    }
+
+    async fn stat(&self, object: &ObjectRef) -> Result<ObjectStat, Box<dyn std::error::Error>> {
+        let path = self.path_for(&object.key)?;
+        let metadata = tokio::fs::metadata(&path).await?;
+        Ok(ObjectStat {
+            size: Some(metadata.len()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{read_to_end, stream_once};
+
+    #[test]
+    fn test_path_for_rejects_traversal() {
+        let storage = LocalStorage::new("data");
+        assert!(storage.path_for("../etc/passwd").is_err());
+        assert!(storage.path_for("/etc/passwd").is_err());
+        assert!(storage.path_for("").is_err());
+        assert_eq!(
+            storage.path_for("2025/5/31/abc").unwrap(),
+            PathBuf::from("data/2025/5/31/abc")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_get_delete() {
+        let root = std::env::temp_dir().join(format!("rih-local-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(&root);
+
+        let object = storage
+            .put(
+                "2025/5/31/uuid-1234",
+                "hello.txt",
+                stream_once(b"hello".to_vec()),
+            )
+            .await
+            .unwrap();
+        assert_eq!(object.key, "2025/5/31/uuid-1234");
+        let content = read_to_end(storage.get(&object).await.unwrap())
+            .await
+            .unwrap();
+        assert_eq!(content, b"hello");
+        assert_eq!(storage.stat(&object).await.unwrap().size, Some(5));
+
+        storage.delete(&object).await.unwrap();
+        assert!(storage.get(&object).await.is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
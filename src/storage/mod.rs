@@ -0,0 +1,524 @@
+pub mod local;
+pub mod s3;
+pub mod telegram;
+
+use crate::config::StorageConfig;
+use crate::telegram::api::TelegramBot;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt as _};
+use local::LocalStorage;
+use log::{info, warn};
+use s3::S3Storage;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::rc::Rc;
+use tokio::io::AsyncWriteExt as _;
+use tokio_util::io::ReaderStream;
+
+/// Body of an object moving into or out of a backend, one network-sized chunk at a time.
+pub type ByteStream = Pin<Box<dyn Stream<Item = io::Result<Bytes>>>>;
+
+/// Backend-neutral reference to an object held by a `StorageBackend`.
+#[derive(Debug, Clone, Default)]
+pub struct ObjectRef {
+    /// Identifier of the object inside its backend (e.g. the Telegram `file_id`).
+    pub key: String,
+    /// Secondary handle some backends need to remove the object (e.g. the Telegram `message_id`).
+    pub handle: String,
+    /// Numbered pieces of an object the backend had to split; empty for whole objects.
+    pub parts: Vec<ObjectPart>,
+}
+
+/// One piece of an object stored in several parts, in `index` order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ObjectPart {
+    pub index: u32,
+    pub key: String,
+    pub handle: String,
+    pub size: u64,
+}
+
+/// Metadata a backend reports about a stored object.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Default)]
+pub struct ObjectStat {
+    pub size: Option<u64>,
+}
+
+/// A place the bytes behind a `FileRecord` can be stored.
+#[allow(async_fn_in_trait)]
+#[allow(dead_code)]
+pub trait StorageBackend {
+    /// Short name recorded alongside each object, e.g. `"telegram"`.
+    fn name(&self) -> &'static str;
+
+    /// Store everything `data` yields and return a reference to it.
+    ///
+    /// `key` is a suggested path-like key (`{year}/{month}/{day}/{uuid}`) and `file_name`
+    /// the name the client uploaded the file under. Backends hold at most one part's
+    /// worth of the body in memory at a time.
+    async fn put(
+        &self,
+        key: &str,
+        file_name: &str,
+        data: ByteStream,
+    ) -> Result<ObjectRef, Box<dyn std::error::Error>>;
+
+    /// Open the object for reading. Errors locating the object are reported here,
+    /// before any bytes are produced.
+    async fn get(&self, object: &ObjectRef) -> Result<ByteStream, Box<dyn std::error::Error>>;
+
+    /// Open `len` bytes of the object starting at byte `start`.
+    ///
+    /// The default reads from the beginning and discards what comes before `start`;
+    /// backends that can seek should override it.
+    async fn get_range(
+        &self,
+        object: &ObjectRef,
+        start: u64,
+        len: u64,
+    ) -> Result<ByteStream, Box<dyn std::error::Error>> {
+        Ok(slice_stream(self.get(object).await?, start, len))
+    }
+
+    async fn delete(&self, object: &ObjectRef) -> Result<(), Box<dyn std::error::Error>>;
+
+    async fn stat(&self, object: &ObjectRef) -> Result<ObjectStat, Box<dyn std::error::Error>>;
+
+    /// A URL clients can be redirected to instead of streaming through this server.
+    fn presigned_url(&self, _object: &ObjectRef) -> Option<String> {
+        None
+    }
+}
+
+/// A configured storage backend.
+pub enum Backend {
+    Telegram(TelegramBot),
+    Local(LocalStorage),
+    S3(S3Storage),
+}
+
+impl StorageBackend for Backend {
+    fn name(&self) -> &'static str {
+        match self {
+            Backend::Telegram(bot) => bot.name(),
+            Backend::Local(local) => local.name(),
+            Backend::S3(s3) => s3.name(),
+        }
+    }
+
+    async fn put(
+        &self,
+        key: &str,
+        file_name: &str,
+        data: ByteStream,
+    ) -> Result<ObjectRef, Box<dyn std::error::Error>> {
+        match self {
+            Backend::Telegram(bot) => bot.put(key, file_name, data).await,
+            Backend::Local(local) => local.put(key, file_name, data).await,
+            Backend::S3(s3) => s3.put(key, file_name, data).await,
+        }
+    }
+
+    async fn get(&self, object: &ObjectRef) -> Result<ByteStream, Box<dyn std::error::Error>> {
+        match self {
+            Backend::Telegram(bot) => bot.get(object).await,
+            Backend::Local(local) => local.get(object).await,
+            Backend::S3(s3) => s3.get(object).await,
+        }
+    }
+
+    async fn get_range(
+        &self,
+        object: &ObjectRef,
+        start: u64,
+        len: u64,
+    ) -> Result<ByteStream, Box<dyn std::error::Error>> {
+        match self {
+            Backend::Telegram(bot) => bot.get_range(object, start, len).await,
+            Backend::Local(local) => local.get_range(object, start, len).await,
+            Backend::S3(s3) => s3.get_range(object, start, len).await,
+        }
+    }
+
+    async fn delete(&self, object: &ObjectRef) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Backend::Telegram(bot) => bot.delete(object).await,
+            Backend::Local(local) => local.delete(object).await,
+            Backend::S3(s3) => s3.delete(object).await,
+        }
+    }
+
+    async fn stat(&self, object: &ObjectRef) -> Result<ObjectStat, Box<dyn std::error::Error>> {
+        match self {
+            Backend::Telegram(bot) => bot.stat(object).await,
+            Backend::Local(local) => local.stat(object).await,
+            Backend::S3(s3) => s3.stat(object).await,
+        }
+    }
+
+    fn presigned_url(&self, object: &ObjectRef) -> Option<String> {
+        match self {
+            Backend::S3(s3) => s3.presigned_url(object),
+            _ => None,
+        }
+    }
+}
+
+/// All configured backends, plus the one new uploads go to (`STORAGE_BACKEND`).
+///
+/// Existing records keep pointing at the backend that stored them, so every backend
+/// with usable settings is registered, not only the default one.
+pub struct Storage {
+    default: String,
+    backends: Vec<Backend>,
+}
+
+impl Storage {
+    /// Build the backends described by the `[storage]` config section.
+    ///
+    /// `storage.backend` selects the backend for new uploads.
+    pub fn from_config(config: &StorageConfig) -> Result<Self, String> {
+        let default = config.default_backend().to_string();
+        info!("Using storage backend: {}", default);
+
+        let mut backends = Vec::new();
+        match &config.telegram {
+            Some(telegram) => backends.push(Backend::Telegram(TelegramBot::new(
+                &telegram.bot_token,
+                &telegram.chat_id,
+            ))),
+            None if default == "telegram" => {
+                return Err("[storage.telegram] must be set for the telegram backend".into());
+            }
+            None => {}
+        }
+        if config.local.is_some() || default == "local" {
+            let local = config.local.clone().unwrap_or_default();
+            info!("Local storage root: {}", local.root);
+            backends.push(Backend::Local(LocalStorage::new(local.root)));
+        }
+        match &config.s3 {
+            Some(s3) => backends.push(Backend::S3(S3Storage::new(s3.clone())?)),
+            None if default == "s3" => {
+                return Err("[storage.s3] must be set for the s3 backend".into());
+            }
+            None => {}
+        }
+
+        let storage = Storage { default, backends };
+        if storage.backend(&storage.default).is_none() {
+            return Err(format!("Unknown storage backend: {}", storage.default));
+        }
+        Ok(storage)
+    }
+
+    /// The backend new uploads are written to.
+    pub fn default_backend(&self) -> &Backend {
+        self.backend(&self.default)
+            .expect("default backend is validated in from_config")
+    }
+
+    /// Look up a configured backend by the name stored on a `FileRecord`.
+    pub fn backend(&self, name: &str) -> Option<&Backend> {
+        self.backends.iter().find(|b| b.name() == name)
+    }
+
+    /// The configured Telegram bot, if any.
+    pub fn telegram(&self) -> Option<&TelegramBot> {
+        self.backends.iter().find_map(|b| match b {
+            Backend::Telegram(bot) => Some(bot),
+            _ => None,
+        })
+    }
+}
+
+/// Pull from `data` until `buffer` holds more than `limit` bytes or the stream ends.
+///
+/// Returns `true` once the stream is exhausted.
+pub async fn fill_buffer(
+    data: &mut ByteStream,
+    buffer: &mut Vec<u8>,
+    limit: usize,
+) -> io::Result<bool> {
+    while buffer.len() <= limit {
+        match data.next().await {
+            Some(chunk) => buffer.extend_from_slice(&chunk?),
+            None => return Ok(true),
+        }
+    }
+    Ok(false)
+}
+
+/// Fail with `ErrorKind::FileTooLarge` and `message` once `data` has yielded more than `max`
+/// bytes.
+pub fn limit_stream(data: ByteStream, max: u64, message: String) -> ByteStream {
+    let mut seen = 0u64;
+    Box::pin(data.map(move |chunk| {
+        let chunk = chunk?;
+        seen += chunk.len() as u64;
+        if seen > max {
+            return Err(io::Error::new(io::ErrorKind::FileTooLarge, message.clone()));
+        }
+        Ok(chunk)
+    }))
+}
+
+/// Whether `e` is `limit_stream` cutting off an upload.
+pub fn is_too_large(e: &(dyn std::error::Error + 'static)) -> bool {
+    e.downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::FileTooLarge)
+}
+
+/// The `len` bytes of `data` starting at byte `start`.
+pub fn slice_stream(data: ByteStream, start: u64, len: u64) -> ByteStream {
+    let end = start + len;
+    Box::pin(futures_util::stream::unfold(
+        (data, 0u64),
+        move |(mut data, mut pos)| async move {
+            while pos < end {
+                let chunk = match data.next().await? {
+                    Ok(chunk) => chunk,
+                    Err(e) => return Some((Err(e), (data, end))),
+                };
+                let chunk_start = pos;
+                pos += chunk.len() as u64;
+                if pos <= start {
+                    continue;
+                }
+                let from = start.saturating_sub(chunk_start) as usize;
+                let to = (end.min(pos) - chunk_start) as usize;
+                return Some((Ok(chunk.slice(from..to)), (data, pos)));
+            }
+            None
+        },
+    ))
+}
+
+/// Running SHA-256 and length of a stream, filled in as the stream is consumed.
+#[derive(Default, Clone)]
+pub struct StreamDigest {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl StreamDigest {
+    /// Wrap `data` so every chunk read from it is also fed to the returned digest.
+    pub fn wrap(data: ByteStream) -> (ByteStream, Rc<RefCell<StreamDigest>>) {
+        let digest = Rc::new(RefCell::new(StreamDigest::default()));
+        let observer = digest.clone();
+        let data = data.inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                let mut digest = observer.borrow_mut();
+                digest.hasher.update(chunk);
+                digest.size += chunk.len() as u64;
+            }
+        });
+        (Box::pin(data), digest)
+    }
+
+    /// Hex SHA-256 and byte count of everything seen so far.
+    pub fn finish(&self) -> (String, u64) {
+        (hex::encode(self.hasher.clone().finalize()), self.size)
+    }
+}
+
+/// An upload held in a temporary file until it is known whether it needs storing at all.
+/// The file is removed when the spool is dropped, off the async threads when in a runtime.
+pub struct Spool {
+    path: PathBuf,
+}
+
+impl Spool {
+    /// Write all of `data` to a new file in the system temporary directory.
+    pub async fn write(mut data: ByteStream) -> io::Result<Spool> {
+        let spool = Spool {
+            path: std::env::temp_dir().join(format!("rih-upload-{}", uuid::Uuid::new_v4())),
+        };
+        let mut file = tokio::fs::File::create(&spool.path).await?;
+        while let Some(chunk) = data.next().await {
+            file.write_all(&chunk?).await?;
+        }
+        file.flush().await?;
+        Ok(spool)
+    }
+
+    /// Read the spooled upload back.
+    pub async fn read(&self) -> io::Result<ByteStream> {
+        let file = tokio::fs::File::open(&self.path).await?;
+        Ok(Box::pin(ReaderStream::new(file)))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        let remove = move || {
+            if let Err(e) = std::fs::remove_file(&path) {
+                warn!("Failed to remove {}: {}", path.display(), e);
+            }
+        };
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(remove)),
+            Err(_) => remove(),
+        }
+    }
+}
+
+/// A stream yielding `data` in one piece.
+pub fn stream_once(data: Vec<u8>) -> ByteStream {
+    Box::pin(futures_util::stream::once(
+        async move { Ok(Bytes::from(data)) },
+    ))
+}
+
+/// Read a stream to the end. Only for bodies already known to be small.
+#[allow(dead_code)]
+pub async fn read_to_end(mut data: ByteStream) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    while let Some(chunk) = data.next().await {
+        buffer.extend_from_slice(&chunk?);
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_config_telegram() {
+        let config: StorageConfig =
+            toml::from_str("[telegram]\nbot_token = \"1:abc\"\nchat_id = \"42\"").unwrap();
+        let storage = Storage::from_config(&config).unwrap();
+        assert_eq!(storage.default_backend().name(), "telegram");
+        assert_eq!(storage.telegram().unwrap().chat_id(), "42");
+        assert!(storage.backend("local").is_none());
+    }
+
+    #[test]
+    fn test_from_config_local() {
+        let config: StorageConfig =
+            toml::from_str("backend = \"local\"\n[local]\nroot = \"uploads\"").unwrap();
+        let storage = Storage::from_config(&config).unwrap();
+        assert_eq!(storage.default_backend().name(), "local");
+        assert!(storage.backend("local").is_some());
+    }
+
+    #[test]
+    fn test_from_config_s3() {
+        let config: StorageConfig = toml::from_str(
+            r#"
+            backend = "s3"
+            [s3]
+            endpoint = "http://localhost:9000"
+            bucket = "images"
+            access_key = "minio"
+            secret_key = "minio123"
+            presign_expiry = 300
+            "#,
+        )
+        .unwrap();
+        let storage = Storage::from_config(&config).unwrap();
+        assert_eq!(storage.default_backend().name(), "s3");
+        let object = ObjectRef {
+            key: "2025/5/31/uuid".to_string(),
+            ..Default::default()
+        };
+        let url = storage.default_backend().presigned_url(&object).unwrap();
+        assert!(url.starts_with("http://localhost:9000/images/2025/5/31/uuid?"));
+    }
+
+    #[tokio::test]
+    async fn test_fill_buffer() {
+        let chunks = (0..4).map(|i| Ok(Bytes::from(vec![i as u8; 3])));
+        let mut data: ByteStream = Box::pin(futures_util::stream::iter(chunks));
+        let mut buffer = Vec::new();
+
+        assert!(!fill_buffer(&mut data, &mut buffer, 4).await.unwrap());
+        assert_eq!(buffer.len(), 6);
+        buffer.clear();
+        assert!(fill_buffer(&mut data, &mut buffer, 100).await.unwrap());
+        assert_eq!(buffer, vec![2, 2, 2, 3, 3, 3]);
+    }
+
+    fn chunked(data: &[u8], chunk_size: usize) -> ByteStream {
+        let chunks: Vec<io::Result<Bytes>> = data
+            .chunks(chunk_size)
+            .map(|c| Ok(Bytes::copy_from_slice(c)))
+            .collect();
+        Box::pin(futures_util::stream::iter(chunks))
+    }
+
+    #[tokio::test]
+    async fn test_slice_stream() {
+        let data: Vec<u8> = (0..100).collect();
+        for (start, len) in [(0, 100), (0, 1), (5, 10), (9, 2), (99, 1), (30, 70)] {
+            let sliced = read_to_end(slice_stream(chunked(&data, 7), start, len))
+                .await
+                .unwrap();
+            assert_eq!(sliced, &data[start as usize..(start + len) as usize]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limit_stream() {
+        let limit = |max| limit_stream(chunked(b"hello world", 3), max, "too big".to_string());
+        let data = read_to_end(limit(11)).await;
+        assert_eq!(data.unwrap(), b"hello world");
+
+        let err = read_to_end(limit(10)).await.unwrap_err();
+        assert_eq!(err.to_string(), "too big");
+        let err: Box<dyn std::error::Error> = err.into();
+        assert!(is_too_large(err.as_ref()));
+    }
+
+    #[tokio::test]
+    async fn test_spool() {
+        let spool = Spool::write(chunked(b"hello world", 3)).await.unwrap();
+        let path = spool.path.clone();
+        assert_eq!(
+            read_to_end(spool.read().await.unwrap()).await.unwrap(),
+            b"hello world"
+        );
+        // Readable more than once, e.g. to retry
+        assert_eq!(
+            read_to_end(spool.read().await.unwrap()).await.unwrap(),
+            b"hello world"
+        );
+        drop(spool);
+        // Removed in the background
+        for _ in 0..100 {
+            if !path.exists() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_stream_digest() {
+        let (data, digest) = StreamDigest::wrap(chunked(b"hello world", 3));
+        assert_eq!(read_to_end(data).await.unwrap(), b"hello world");
+        let (sha256, size) = digest.borrow().finish();
+        assert_eq!(size, 11);
+        assert_eq!(
+            sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+        );
+    }
+
+    #[test]
+    fn test_from_config_unknown_backend() {
+        let config: StorageConfig = toml::from_str("backend = \"floppy\"").unwrap();
+        assert!(Storage::from_config(&config).is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use super::{
+    ByteStream, ObjectPart, ObjectRef, ObjectStat, StorageBackend, fill_buffer, slice_stream,
+};
+use crate::telegram::api::TelegramBot;
+use futures_util::{StreamExt as _, TryStreamExt as _};
+use log::{debug, error, info, warn};
+use std::io;
+
+/// Largest piece sent as a single document.
+///
+/// The Bot API accepts uploads up to 50 MB but `getFile` only serves files up to 20 MB,
+/// so anything bigger is split into numbered parts that each stay below the download limit.
+/// `sendDocument` needs each part in one piece, so this is also the most an upload buffers.
+pub const CHUNK_SIZE: usize = 19 * 1024 * 1024;
+
+impl TelegramBot {
+    /// Start downloading `file_id`, re-resolving its path once if the cached one has gone stale.
+    async fn open_download(
+        &self,
+        file_id: &str,
+    ) -> Result<reqwest::Response, Box<dyn std::error::Error>> {
+        let mut retried = false;
+        loop {
+            let url = self.get_file_url(file_id).await?;
+            // Strip the URL from errors: it embeds the bot token.
+            let response = reqwest::get(&url)
+                .await
+                .map_err(reqwest::Error::without_url)?;
+            info!("Telegram download status: {}", response.status());
+            if response.status() == reqwest::StatusCode::NOT_FOUND && !retried {
+                warn!("File path for {} is no longer valid, refreshing", file_id);
+                self.forget_file_url(file_id);
+                retried = true;
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(format!(
+                    "Failed to fetch file {} from Telegram: {}",
+                    file_id,
+                    response.status()
+                )
+                .into());
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Send `buffer` and the rest of `data` as numbered parts, appending each sent part to `parts`.
+    async fn send_parts(
+        &self,
+        file_name: &str,
+        mut data: ByteStream,
+        mut buffer: Vec<u8>,
+        parts: &mut Vec<ObjectPart>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut eof = false;
+        loop {
+            let rest = if buffer.len() > CHUNK_SIZE {
+                buffer.split_off(CHUNK_SIZE)
+            } else {
+                Vec::new()
+            };
+            let chunk = std::mem::replace(&mut buffer, rest);
+            let index = parts.len() as u32;
+            let size = chunk.len() as u64;
+            let part_name = format!("{}.part{:03}", file_name, index + 1);
+            let res = self.send_document(chunk, &part_name).await?;
+            parts.push(ObjectPart {
+                index,
+                key: res.file_id,
+                handle: res.message_id,
+                size,
+            });
+
+            if !eof {
+                eof = fill_buffer(&mut data, &mut buffer, CHUNK_SIZE).await?;
+            }
+            if eof && buffer.is_empty() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn delete_parts(&self, parts: &[ObjectPart]) -> Result<(), Box<dyn std::error::Error>> {
+        let mut failed = Vec::new();
+        for part in parts {
+            debug!(
+                "Try to delete telegram message: chat_id={}, message_id={} (part {})",
+                self.chat_id(),
+                part.handle,
+                part.index
+            );
+            if let Err(e) = self.delete_message(part.handle.clone()).await {
+                error!("Failed to delete part {}: {}", part.index, e);
+                failed.push(part.index.to_string());
+            }
+        }
+        if !failed.is_empty() {
+            return Err(format!("Failed to delete parts: {}", failed.join(", ")).into());
+        }
+        Ok(())
+    }
+}
+
+fn body_stream(
+    response: reqwest::Response,
+) -> impl futures_util::Stream<Item = io::Result<bytes::Bytes>> {
+    response
+        .bytes_stream()
+        .map_err(|e| io::Error::other(e.without_url()))
+}
+
+impl StorageBackend for TelegramBot {
+    fn name(&self) -> &'static str {
+        "telegram"
+    }
+
+    async fn put(
+        &self,
+        _key: &str,
+        file_name: &str,
+        mut data: ByteStream,
+    ) -> Result<ObjectRef, Box<dyn std::error::Error>> {
+        let mut buffer = Vec::new();
+        let eof = fill_buffer(&mut data, &mut buffer, CHUNK_SIZE).await?;
+        if eof {
+            let res = self.send_document(buffer, file_name).await?;
+            // `file_id` is the durable key; download URLs expire and are resolved on demand.
+            return Ok(ObjectRef {
+                key: res.file_id,
+                handle: res.message_id,
+                parts: Vec::new(),
+            });
+        }
+
+        info!(
+            "{} exceeds {} bytes, sending it in parts",
+            file_name, CHUNK_SIZE
+        );
+        let mut parts = Vec::new();
+        if let Err(e) = self.send_parts(file_name, data, buffer, &mut parts).await {
+            // Don't leave orphaned parts behind in the chat.
+            let _ = self.delete_parts(&parts).await;
+            return Err(e);
+        }
+        info!("Sent {} in {} parts", file_name, parts.len());
+        Ok(ObjectRef {
+            parts,
+            ..Default::default()
+        })
+    }
+
+    async fn get(&self, object: &ObjectRef) -> Result<ByteStream, Box<dyn std::error::Error>> {
+        if object.parts.is_empty() {
+            let response = self.open_download(&object.key).await?;
+            return Ok(Box::pin(body_stream(response)));
+        }
+
+        // Open the first part now so a missing object fails before any bytes are sent;
+        // later parts are opened one at a time as the previous one finishes.
+        let first = self.open_download(&object.parts[0].key).await?;
+        let bot = self.clone();
+        let rest = futures_util::stream::iter(
+            object.parts[1..]
+                .iter()
+                .map(|p| p.key.clone())
+                .collect::<Vec<_>>(),
+        )
+        .then(move |key| {
+            let bot = bot.clone();
+            async move {
+                debug!("Fetching next part {}", key);
+                bot.open_download(&key)
+                    .await
+                    .map(body_stream)
+                    .map_err(|e| io::Error::other(e.to_string()))
+            }
+        })
+        .try_flatten();
+        Ok(Box::pin(body_stream(first).chain(rest)))
+    }
+
+    async fn get_range(
+        &self,
+        object: &ObjectRef,
+        start: u64,
+        len: u64,
+    ) -> Result<ByteStream, Box<dyn std::error::Error>> {
+        if object.parts.is_empty() {
+            // Telegram serves whole files only.
+            return Ok(slice_stream(self.get(object).await?, start, len));
+        }
+        // Only fetch the parts that overlap the range.
+        let mut offset = 0;
+        let mut skipped = 0;
+        let mut parts = Vec::new();
+        for part in &object.parts {
+            let end = offset + part.size;
+            if end <= start {
+                skipped = end;
+            } else if offset < start + len {
+                parts.push(part.clone());
+            }
+            offset = end;
+        }
+        let subset = ObjectRef {
+            parts,
+            ..object.clone()
+        };
+        if subset.parts.is_empty() {
+            return Err(format!("Range starts past the end of {}", object.key).into());
+        }
+        Ok(slice_stream(self.get(&subset).await?, start - skipped, len))
+    }
+
+    async fn delete(&self, object: &ObjectRef) -> Result<(), Box<dyn std::error::Error>> {
+        if !object.parts.is_empty() {
+            return self.delete_parts(&object.parts).await;
+        }
+        if object.handle.is_empty() {
+            debug!(
+                "No message_id recorded for {}, nothing to delete",
+                object.key
+            );
+            return Ok(());
+        }
+        debug!(
+            "Try to delete telegram message: chat_id={}, message_id={}",
+            self.chat_id(),
+            object.handle
+        );
+        self.delete_message(object.handle.clone()).await?;
+        Ok(())
+    }
+
+    async fn stat(&self, object: &ObjectRef) -> Result<ObjectStat, Box<dyn std::error::Error>> {
+        if !object.parts.is_empty() {
+            return Ok(ObjectStat {
+                size: Some(object.parts.iter().map(|p| p.size).sum()),
+            });
+        }
+        let file = self.get_file(&object.key).await?;
+        Ok(ObjectStat {
+            size: file.file_size,
+        })
+    }
+}
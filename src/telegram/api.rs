@@ -1,13 +1,32 @@
-use super::Bot;
 use log::{debug, error, info};
 use reqwest;
 use serde_json;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+/// How long a `getFile` path is reused before asking Telegram again.
+///
+/// Telegram only guarantees download links for about an hour, so stay safely below that.
+const FILE_PATH_TTL: Duration = Duration::from_secs(50 * 60);
+
+/// Most `getFile` paths kept at once; expired ones are dropped when the cache fills up.
+const MAX_CACHED_FILE_PATHS: usize = 10_000;
+
+#[derive(Clone)]
 pub struct TelegramBot {
     api_url: String,
     base_url: String,
     token: String,
     chat_id: String,
+    /// `file_id` -> (`file_path`, time it stops being trusted), shared between clones
+    file_paths: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+}
+
+#[derive(Debug)]
+pub struct ResGetFile {
+    pub file_path: String,
+    pub file_size: Option<u64>,
 }
 
 #[allow(dead_code)]
@@ -15,47 +34,52 @@ pub struct TelegramBot {
 pub struct ResSendDocument {
     pub file_id: String,
     pub file_name: String,
-    pub file_url: String,
     pub message_id: String,
 }
 
 impl TelegramBot {
     pub fn new(token: &str, chat_id: &str) -> Self {
-        let api_url = format!("https://api.telegram.org");
+        let api_url = "https://api.telegram.org".to_string();
         TelegramBot {
             api_url: api_url.clone(),
             token: token.to_string(),
             chat_id: chat_id.to_string(),
             base_url: format!("{}/bot{}", api_url, token),
+            file_paths: Arc::new(Mutex::new(HashMap::new())),
         }
     }
-}
 
-impl Bot for TelegramBot {
-    async fn get_updates(&self) -> Result<String, Box<dyn std::error::Error>> {
+    /// Chat ID the bot posts documents to.
+    pub fn chat_id(&self) -> &str {
+        &self.chat_id
+    }
+
+    pub async fn get_updates(&self) -> Result<String, Box<dyn std::error::Error>> {
         let url = format!("{}/getUpdates", self.base_url);
-        debug!("Requesting updates from: {}", url);
+        debug!("Requesting updates");
 
-        let response = reqwest::get(&url).await?;
+        let response = reqwest::get(&url)
+            .await
+            .map_err(reqwest::Error::without_url)?;
         if response.status().is_success() {
-            let text = response.text().await?;
+            let text = response.text().await.map_err(reqwest::Error::without_url)?;
             info!("Received updates: {}", text);
             Ok(text)
         } else {
             let status = response.status();
-            let error_text = response.text().await?;
+            let error_text = response.text().await.map_err(reqwest::Error::without_url)?;
             error!("Failed to get updates: {} - {}", status, error_text);
             Err(format!("Failed to get updates: {} - {}", status, error_text).into())
         }
     }
 
-    async fn send_document(
+    pub async fn send_document(
         &self,
         file: Vec<u8>,
         file_name: &str,
     ) -> Result<ResSendDocument, Box<dyn std::error::Error>> {
         let url = format!("{}/sendDocument", self.base_url);
-        debug!("Sending document (from file) to: {}", url);
+        debug!("Sending document {} to chat {}", file_name, self.chat_id);
 
         let part = reqwest::multipart::Part::bytes(file).file_name(file_name.to_string());
         let form = reqwest::multipart::Form::new()
@@ -67,92 +91,127 @@ impl Bot for TelegramBot {
             .multipart(form)
             .send()
             .await
-            .unwrap();
+            .map_err(reqwest::Error::without_url)?;
 
         if response.status().is_success() {
             debug!("Document sent successfully: {}", response.status());
-            let json: serde_json::Value = response
-                .json()
-                .await
-                .expect("Failed to parse JSON response");
-            let file_id: &serde_json::Value = json
-                .get("result")
-                .and_then(|r| r.get("document"))
-                .and_then(|d| d.get("file_id"))
-                .expect("Expected 'file_id' in document");
-            debug!("File ID: {}", file_id);
-            let message_id: &serde_json::Value = json
-                .get("result")
-                .and_then(|r| r.get("message_id"))
-                .expect("Expected 'message_id' in document");
-            debug!("Message ID: {}", message_id);
-            // Check if there is a sticker file_id, and return "sticker_[file_id].webp" if present
-            let sticker_file_id = json
-                .get("result")
-                .and_then(|r| r.get("sticker"))
-                .and_then(|s| s.get("file_id"))
-                .and_then(|v| v.as_str());
-
-            let file_name = if let Some(sticker_id) = sticker_file_id {
-                format!("sticker_{}.webp", sticker_id)
-            } else {
-                json.get("result")
-                    .and_then(|r| r.get("document"))
-                    .and_then(|d| d.get("file_name"))
-                    .and_then(|n| n.as_str())
-                    .unwrap_or("unknown")
-                    .to_string()
-            };
-            let file_url = self.get_file_url(file_id.as_str().unwrap()).await?;
-            Ok(ResSendDocument {
-                file_id: file_id.as_str().unwrap().to_string(),
-                file_name,
-                file_url,
-                message_id: message_id.as_u64().unwrap().to_string(),
-            })
+            let json: serde_json::Value =
+                response.json().await.map_err(reqwest::Error::without_url)?;
+            let sent = parse_sent_document(&json)?;
+            debug!("File ID: {}, message ID: {}", sent.file_id, sent.message_id);
+            Ok(sent)
         } else {
             let status = response.status();
-            let error_json: serde_json::Value = response.json().await?;
+            let error_json: serde_json::Value =
+                response.json().await.map_err(reqwest::Error::without_url)?;
             error!("Failed to send document: {} - {}", status, error_json);
             Err(format!("Failed to send document: {} - {}", status, error_json).into())
         }
     }
 
-    async fn get_file_url(&self, file_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+    pub async fn get_file(&self, file_id: &str) -> Result<ResGetFile, Box<dyn std::error::Error>> {
         let request_url = format!("{}/getFile?file_id={}", self.base_url, file_id);
-        debug!("Requesting file path from: {}", request_url);
+        debug!("Requesting file path for file_id: {}", file_id);
         let response = reqwest::get(&request_url)
             .await
-            .expect("Failed to get file path");
-        let json: serde_json::Value = response
-            .json()
-            .await
-            .expect("Failed to parse JSON response");
+            .map_err(reqwest::Error::without_url)?;
+        let json: serde_json::Value = response.json().await.map_err(reqwest::Error::without_url)?;
 
-        if json.get("ok").unwrap() == &serde_json::Value::Bool(false) {
+        if !json.get("ok").and_then(|v| v.as_bool()).unwrap_or(false) {
             error!("Failed to get file path: {:?}", json);
-            return Err(format!("Failed to get file path").into());
+            return Err("Failed to get file path".into());
         }
-        let file_path = json
-            .get("result")
+        let result = json.get("result");
+        let file_path = result
             .and_then(|r| r.get("file_path"))
             .and_then(|p| p.as_str())
-            .expect("Expected 'file_path' in response");
+            .ok_or("Expected 'file_path' in response")?;
+        let file_size = result
+            .and_then(|r| r.get("file_size"))
+            .and_then(|s| s.as_u64());
+
+        Ok(ResGetFile {
+            file_path: file_path.to_string(),
+            file_size,
+        })
+    }
 
+    /// Download URL for `file_id`, resolved through `getFile` when no fresh path is cached.
+    ///
+    /// The URL embeds the bot token and expires, so it must never be stored or shown to clients.
+    pub async fn get_file_url(&self, file_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let file_path = match self.cached_file_path(file_id) {
+            Some(file_path) => file_path,
+            None => {
+                let file = self.get_file(file_id).await?;
+                self.remember_file_path(file_id, &file.file_path);
+                file.file_path
+            }
+        };
         Ok(format!(
             "{}/file/bot{}/{}",
             self.api_url, self.token, file_path
         ))
     }
-    async fn delete_message(&self, message_id: String) -> Result<bool, Box<dyn std::error::Error>> {
+
+    fn remember_file_path(&self, file_id: &str, file_path: &str) {
+        let mut file_paths = self.file_paths.lock().unwrap();
+        let now = Instant::now();
+        if file_paths.len() >= MAX_CACHED_FILE_PATHS {
+            file_paths.retain(|_, (_, expires_at)| *expires_at > now);
+        }
+        // Still full of fresh paths: make room by forgetting the one that expires first
+        if file_paths.len() >= MAX_CACHED_FILE_PATHS
+            && let Some(oldest) = file_paths
+                .iter()
+                .min_by_key(|(_, (_, expires_at))| *expires_at)
+                .map(|(file_id, _)| file_id.clone())
+        {
+            file_paths.remove(&oldest);
+        }
+        file_paths.insert(
+            file_id.to_string(),
+            (file_path.to_string(), now + FILE_PATH_TTL),
+        );
+    }
+
+    /// Drop the cached path for `file_id`, e.g. after Telegram answered 404 for it.
+    pub fn forget_file_url(&self, file_id: &str) {
+        self.file_paths.lock().unwrap().remove(file_id);
+    }
+
+    fn cached_file_path(&self, file_id: &str) -> Option<String> {
+        let mut file_paths = self.file_paths.lock().unwrap();
+        match file_paths.get(file_id) {
+            Some((file_path, expires_at)) if *expires_at > Instant::now() => {
+                Some(file_path.clone())
+            }
+            Some(_) => {
+                debug!("Cached file path for {} expired", file_id);
+                file_paths.remove(file_id);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub async fn delete_message(
+        &self,
+        message_id: String,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
         let url = format!("{}/deleteMessage", self.base_url);
         let params = [
             ("chat_id", self.chat_id.as_str()),
             ("message_id", message_id.as_str()),
         ];
         let client = reqwest::Client::new();
-        let response = client.post(&url).form(&params).send().await?;
-        let json: serde_json::Value = response.json().await?;
+        let response = client
+            .post(&url)
+            .form(&params)
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        let json: serde_json::Value = response.json().await.map_err(reqwest::Error::without_url)?;
         if !json.get("ok").and_then(|v| v.as_bool()).unwrap_or(false) {
             return Err(format!("Delete message failed: {:?}", json).into());
         }
@@ -160,6 +219,46 @@ impl Bot for TelegramBot {
     }
 }
 
+/// Read the reply to `sendDocument`. Telegram may turn a `.webp` document into a sticker, in
+/// which case the message has a `sticker` instead of a `document`.
+fn parse_sent_document(
+    json: &serde_json::Value,
+) -> Result<ResSendDocument, Box<dyn std::error::Error>> {
+    let result = json
+        .get("result")
+        .ok_or_else(|| format!("Expected 'result' in response: {}", json))?;
+    let message_id = result
+        .get("message_id")
+        .and_then(|v| v.as_u64())
+        .ok_or("Expected a numeric 'message_id' in response")?;
+    let (file_id, file_name) = match (result.get("document"), result.get("sticker")) {
+        (Some(document), _) => {
+            let file_id = document
+                .get("file_id")
+                .and_then(|v| v.as_str())
+                .ok_or("Expected 'file_id' in document")?;
+            let file_name = document
+                .get("file_name")
+                .and_then(|n| n.as_str())
+                .unwrap_or("unknown");
+            (file_id, file_name.to_string())
+        }
+        (None, Some(sticker)) => {
+            let file_id = sticker
+                .get("file_id")
+                .and_then(|v| v.as_str())
+                .ok_or("Expected 'file_id' in sticker")?;
+            (file_id, format!("sticker_{}.webp", file_id))
+        }
+        (None, None) => return Err("Expected a document or sticker in response".into()),
+    };
+    Ok(ResSendDocument {
+        file_id: file_id.to_string(),
+        file_name,
+        message_id: message_id.to_string(),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use std::{fs::File, io::Read};
@@ -172,12 +271,98 @@ mod tests {
             "7280383975:AAFAGg3HkTVoiK0ttxwDbQ1FJPQNIWMQA60",
             "test_chat_id",
         );
-        assert_eq!(bot.base_url, "https://api.telegram.org");
-        assert_eq!(bot.token, "test_token");
+        assert_eq!(bot.api_url, "https://api.telegram.org");
+        assert_eq!(
+            bot.base_url,
+            "https://api.telegram.org/bot7280383975:AAFAGg3HkTVoiK0ttxwDbQ1FJPQNIWMQA60"
+        );
+        assert_eq!(bot.token, "7280383975:AAFAGg3HkTVoiK0ttxwDbQ1FJPQNIWMQA60");
         assert_eq!(bot.chat_id, "test_chat_id");
     }
 
     #[tokio::test]
+    async fn test_cached_file_url() {
+        let bot = TelegramBot::new("1:abc", "42");
+        bot.file_paths.lock().unwrap().insert(
+            "fresh".to_string(),
+            (
+                "documents/file_1.png".to_string(),
+                Instant::now() + FILE_PATH_TTL,
+            ),
+        );
+        bot.file_paths.lock().unwrap().insert(
+            "stale".to_string(),
+            ("documents/file_2.png".to_string(), Instant::now()),
+        );
+
+        assert_eq!(
+            bot.get_file_url("fresh").await.unwrap(),
+            "https://api.telegram.org/file/bot1:abc/documents/file_1.png"
+        );
+        assert!(bot.cached_file_path("stale").is_none());
+        assert!(!bot.file_paths.lock().unwrap().contains_key("stale"));
+
+        bot.forget_file_url("fresh");
+        assert!(bot.cached_file_path("fresh").is_none());
+    }
+
+    #[test]
+    fn test_parse_sent_document() {
+        let sent = parse_sent_document(&serde_json::json!({
+            "ok": true,
+            "result": {"message_id": 7, "document": {"file_id": "doc", "file_name": "a.png"}}
+        }))
+        .unwrap();
+        assert_eq!(
+            (sent.file_id.as_str(), sent.file_name.as_str()),
+            ("doc", "a.png")
+        );
+        assert_eq!(sent.message_id, "7");
+
+        let sent = parse_sent_document(&serde_json::json!({
+            "ok": true,
+            "result": {"message_id": 8, "sticker": {"file_id": "stk"}}
+        }))
+        .unwrap();
+        assert_eq!(sent.file_id, "stk");
+        assert_eq!(sent.file_name, "sticker_stk.webp");
+
+        for reply in [
+            serde_json::json!({"ok": true}),
+            serde_json::json!({"result": {"message_id": "8", "document": {"file_id": "d"}}}),
+            serde_json::json!({"result": {"message_id": 8, "document": {"file_id": 1}}}),
+            serde_json::json!({"result": {"message_id": 8}}),
+        ] {
+            assert!(parse_sent_document(&reply).is_err(), "{}", reply);
+        }
+    }
+
+    #[test]
+    fn test_file_path_cache_is_bounded() {
+        let bot = TelegramBot::new("1:abc", "42");
+        {
+            let mut file_paths = bot.file_paths.lock().unwrap();
+            for i in 0..MAX_CACHED_FILE_PATHS - 1 {
+                let expires_at = Instant::now() + Duration::from_secs(60 + i as u64);
+                file_paths.insert(format!("fresh{}", i), (String::new(), expires_at));
+            }
+            file_paths.insert("stale".to_string(), (String::new(), Instant::now()));
+        }
+
+        // Expired paths go first...
+        bot.remember_file_path("new1", "documents/file_1.png");
+        assert_eq!(bot.file_paths.lock().unwrap().len(), MAX_CACHED_FILE_PATHS);
+        assert!(bot.cached_file_path("stale").is_none());
+        // ...then the one that expires soonest
+        bot.remember_file_path("new2", "documents/file_2.png");
+        let file_paths = bot.file_paths.lock().unwrap();
+        assert_eq!(file_paths.len(), MAX_CACHED_FILE_PATHS);
+        assert!(!file_paths.contains_key("fresh0"));
+        assert!(file_paths.contains_key("new1") && file_paths.contains_key("new2"));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a live Telegram bot"]
     async fn test_telegram_bot_send_document() {
         let bot = TelegramBot::new(
             "7280383975:AAFAGg3HkTVoiK0ttxwDbQ1FJPQNIWMQA60",
@@ -194,6 +379,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "requires a live Telegram bot"]
     async fn test_telegram_bot_get_updates() {
         let bot = TelegramBot::new(
             "7280383975:AAFAGg3HkTVoiK0ttxwDbQ1FJPQNIWMQA60",
@@ -204,6 +390,7 @@ mod tests {
         println!("Updates: {}", result);
     }
     #[tokio::test]
+    #[ignore = "requires a live Telegram bot"]
     async fn test_telegram_bot_get_file_url() {
         let bot = TelegramBot::new(
             "7280383975:AAFAGg3HkTVoiK0ttxwDbQ1FJPQNIWMQA60",
@@ -217,6 +404,7 @@ mod tests {
     }
 
     #[tokio::test]
+    #[ignore = "requires a live Telegram bot"]
     async fn test_telegram_bot_delete_message() {
         let bot = TelegramBot::new(
             "7280383975:AAFAGg3HkTVoiK0ttxwDbQ1FJPQNIWMQA60",